@@ -0,0 +1,469 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::diagnostic::{codes, Diagnostic};
+use crate::frontend::ast::Type;
+use crate::middle::ir::{BlockId, Function, Inst, Module, Overflow, ValueId};
+use crate::middle::opt::{widen, wrap};
+
+// Deeper recursion than this is reported instead of exhausting memory; the
+// JIT would overflow the native stack somewhere around here too.
+const MAX_CALL_DEPTH: usize = 100_000;
+
+// A runtime value. Integers use the representation of `Inst::Const`, so
+// `widen` gives their mathematical value.
+#[derive(Debug, Clone)]
+enum Value {
+    Int(i64),
+    Bool(bool),
+    // an f32 is kept rounded to f32
+    Float(f64),
+    // `None` is the null array inside a zeroed element, which reads as empty
+    Array(Option<Rc<Array>>),
+    Struct(Vec<Value>),
+    // an `ElemAddr`: the array and the flat index, checked when used
+    Ptr(Option<Rc<Array>>, i64),
+}
+
+// Copying an array value shares this, like the pointer the backend uses.
+#[derive(Debug)]
+struct Array {
+    extents: Vec<i64>,
+    elems: RefCell<Vec<Value>>,
+}
+
+// Runs the module's `main` by walking its IR, without LLVM, and returns what
+// it returns. It follows the backend's semantics (overflow policy, bounds
+// checks, array sharing), so its result is what `llvm::jit_run_main` should
+// give; where the backend aborts the process, this returns an error instead.
+// The module is expected to pass `ir::verify`.
+pub fn run_main(module: &Module) -> Result<i64, Box<Diagnostic>> {
+    let Some(main) = module.functions.iter().find(|f| f.name == "main") else {
+        return Err(Box::new(Diagnostic::error("no main function found").with_code(codes::INVALID_MAIN)));
+    };
+    if !main.params.is_empty() || !(main.ret.is_int() || main.ret == Type::Bool) {
+        return Err(Box::new(Diagnostic::error("`main` must take no arguments and return an integer")
+            .with_code(codes::INVALID_MAIN)
+            .with_note(format!("`main` takes {} argument(s) and returns {}", main.params.len(), main.ret))));
+    }
+    let mut machine = Machine {
+        module,
+        functions: module.functions.iter().map(|f| (f.name.as_str(), f)).collect(),
+        frames: vec![Frame::new(main, Vec::new())],
+    };
+    match machine.run()? {
+        Value::Int(v) => Ok(v),
+        Value::Bool(b) => Ok(b as i64),
+        v => unreachable!("`main` returns an integer, not {v:?}"),
+    }
+}
+
+struct Machine<'a> {
+    module: &'a Module,
+    functions: HashMap<&'a str, &'a Function>,
+    // the active calls, innermost last, kept here rather than on the native
+    // stack so deep recursion cannot overflow it
+    frames: Vec<Frame<'a>>,
+}
+
+struct Frame<'a> {
+    func: &'a Function,
+    // indexed by ValueId; `None` until defined
+    values: Vec<Option<Value>>,
+    // the named variable slots `Load` and `Store` use
+    slots: HashMap<&'a str, Value>,
+    block: BlockId,
+    // the next instruction to run in `block`; while a call is active, the
+    // one after the `Call`
+    next: usize,
+}
+
+impl<'a> Frame<'a> {
+    fn new(func: &'a Function, args: Vec<Value>) -> Self {
+        let mut frame = Frame { func, values: vec![None; func.value_count()], slots: HashMap::new(), block: func.entry(), next: 0 };
+        for (&param, arg) in func.block(func.entry()).params.iter().zip(args) {
+            frame.set(param, arg);
+        }
+        frame
+    }
+
+    fn get(&self, id: ValueId) -> &Value {
+        self.values[id.get_usize()].as_ref().expect("the verifier checks every value is defined before it is used")
+    }
+
+    fn set(&mut self, id: ValueId, value: Value) {
+        self.values[id.get_usize()] = Some(value);
+    }
+
+    // an integer or bool operand, as its mathematical value
+    fn int(&self, id: ValueId) -> i128 {
+        match self.get(id) {
+            Value::Int(v) => widen(&self.func.value_type(id), *v),
+            Value::Bool(b) => *b as i128,
+            v => unreachable!("an integer operand, not {v:?}"),
+        }
+    }
+
+    fn float(&self, id: ValueId) -> f64 {
+        match self.get(id) {
+            Value::Float(v) => *v,
+            v => unreachable!("a float operand, not {v:?}"),
+        }
+    }
+
+    fn bool(&self, id: ValueId) -> bool {
+        match self.get(id) {
+            Value::Bool(b) => *b,
+            v => unreachable!("a bool operand, not {v:?}"),
+        }
+    }
+
+    // `value` as an integer of `dst`'s type, wrapped around
+    fn set_int(&mut self, dst: ValueId, value: i128) {
+        let v = wrap(&self.func.value_type(dst), value);
+        self.set(dst, Value::Int(v));
+    }
+
+    // `value` as a float of `dst`'s type, rounded to f32 for an f32
+    fn set_float(&mut self, dst: ValueId, value: f64) {
+        let v = match self.func.value_type(dst) {
+            Type::F32 => value as f32 as f64,
+            _ => value,
+        };
+        self.set(dst, Value::Float(v));
+    }
+
+    // `value` as an integer of `dst`'s type if it fits, otherwise wrapped
+    // around or trapping by the overflow policy
+    fn set_checked(&mut self, dst: ValueId, value: i128, overflow: Overflow) -> Result<(), Box<Diagnostic>> {
+        let (min, max) = self.func.value_type(dst).int_range().expect("integer type");
+        if overflow == Overflow::Trap && !(min..=max).contains(&value) {
+            return Err(Box::new(trap(self.func, dst, "integer overflow")
+                .with_note(format!("the result does not fit in {}", self.func.value_type(dst)))));
+        }
+        self.set_int(dst, value);
+        Ok(())
+    }
+}
+
+impl<'a> Machine<'a> {
+    // Runs until the outermost call returns, giving its value.
+    fn run(&mut self) -> Result<Value, Box<Diagnostic>> {
+        loop {
+            let frame = self.frames.last_mut().expect("a call is active");
+            let func = frame.func;
+            let inst = &func.block(frame.block).insts[frame.next];
+            frame.next += 1;
+            match inst {
+                Inst::Br { target, args } => branch(frame, *target, args),
+                Inst::CondBr { cond, then_block, then_args, else_block, else_args } => {
+                    if frame.bool(*cond) {
+                        branch(frame, *then_block, then_args);
+                    } else {
+                        branch(frame, *else_block, else_args);
+                    }
+                }
+                Inst::Return { src } => {
+                    let value = frame.get(*src).clone();
+                    self.frames.pop();
+                    let Some(caller) = self.frames.last_mut() else {
+                        return Ok(value);
+                    };
+                    let call = &caller.func.block(caller.block).insts[caller.next - 1];
+                    let dst = call.dst().expect("only a `Call` is left waiting for a result");
+                    caller.set(dst, value);
+                }
+                Inst::Unreachable => {
+                    return Err(Box::new(Diagnostic::error(format!("reached an `unreachable` instruction in `{}`", func.name))
+                        .with_code(codes::RUNTIME_TRAP)
+                        .with_note("control is never supposed to get here; this is a bug in the IR")));
+                }
+                Inst::Call { dst, callee, args } => {
+                    let callee_func = *self.functions.get(callee.as_str()).ok_or_else(|| {
+                        trap(func, *dst, format!("call to undefined function '{callee}'")).with_code(codes::UNKNOWN_FUNCTION)
+                    })?;
+                    if callee_func.params.len() != args.len() {
+                        return Err(Box::new(trap(func, *dst, format!("wrong number of arguments to '{callee}'"))
+                            .with_code(codes::ARGUMENT_COUNT)
+                            .with_note(format!("'{callee}' takes {} argument(s) but {} were supplied", callee_func.params.len(), args.len()))));
+                    }
+                    let args = args.iter().map(|a| frame.get(*a).clone()).collect();
+                    if self.frames.len() >= MAX_CALL_DEPTH {
+                        return Err(Box::new(trap(func, *dst, "call stack overflow")
+                            .with_note(format!("more than {MAX_CALL_DEPTH} calls were active at once"))));
+                    }
+                    self.frames.push(Frame::new(callee_func, args));
+                }
+                _ => self.step(inst)?,
+            }
+        }
+    }
+
+    // Runs one instruction that neither branches nor calls, in the
+    // innermost frame.
+    fn step(&mut self, inst: &'a Inst) -> Result<(), Box<Diagnostic>> {
+        let (module, overflow) = (self.module, self.module.overflow);
+        let frame = self.frames.last_mut().expect("a call is active");
+        let func = frame.func;
+        match inst {
+            // a constant out of range for its type (lowering emits `const
+            // 128` for an i8) is truncated, as LLVM does
+            Inst::Const { dst, value } => frame.set_int(*dst, widen(&func.value_type(*dst), *value)),
+            Inst::Boolean { dst, value } => frame.set(*dst, Value::Bool(*value)),
+            Inst::FConst { dst, value } => frame.set_float(*dst, *value),
+            Inst::Cast { dst, src } => {
+                let value = if func.value_type(*src).is_float() {
+                    // rounds toward zero and saturates at the target's range,
+                    // NaN giving 0, like the backend's `llvm.fpto*i.sat`
+                    let (min, max) = func.value_type(*dst).int_range().expect("casts give ints");
+                    (frame.float(*src) as i128).clamp(min, max)
+                } else {
+                    frame.int(*src)
+                };
+                frame.set_int(*dst, value);
+            }
+            Inst::Add { dst, lhs, rhs } | Inst::Sub { dst, lhs, rhs } | Inst::Mul { dst, lhs, rhs } => {
+                let (l, r) = (frame.int(*lhs), frame.int(*rhs));
+                // only a u64 product can overflow an i128; wrapping keeps the
+                // low bits right and leaves a negative value that does not fit
+                let value = match inst {
+                    Inst::Add { .. } => l + r,
+                    Inst::Sub { .. } => l - r,
+                    _ => l.wrapping_mul(r),
+                };
+                frame.set_checked(*dst, value, overflow)?;
+            }
+            Inst::Div { dst, lhs, rhs } | Inst::Rem { dst, lhs, rhs } => {
+                let (l, r) = (frame.int(*lhs), frame.int(*rhs));
+                if r == 0 {
+                    return Err(Box::new(trap(func, *dst, "division by zero")));
+                }
+                // Rust's `/` and `%` round toward zero like LLVM's `sdiv` and
+                // `srem`; on the widened values only `MIN / -1` leaves the type
+                match inst {
+                    Inst::Div { .. } => frame.set_checked(*dst, l / r, overflow)?,
+                    _ => frame.set_int(*dst, l % r),
+                }
+            }
+            Inst::Greater { dst, lhs, rhs } | Inst::Less { dst, lhs, rhs } | Inst::Equal { dst, lhs, rhs }
+            | Inst::GreaterEq { dst, lhs, rhs } | Inst::LessEq { dst, lhs, rhs } | Inst::NotEqual { dst, lhs, rhs } => {
+                // the widened values compare signed or unsigned by their type
+                let (l, r) = (frame.int(*lhs), frame.int(*rhs));
+                let value = match inst {
+                    Inst::Greater { .. } => l > r,
+                    Inst::Less { .. } => l < r,
+                    Inst::Equal { .. } => l == r,
+                    Inst::GreaterEq { .. } => l >= r,
+                    Inst::LessEq { .. } => l <= r,
+                    _ => l != r,
+                };
+                frame.set(*dst, Value::Bool(value));
+            }
+            Inst::BitAnd { dst, lhs, rhs } | Inst::BitOr { dst, lhs, rhs } | Inst::BitXor { dst, lhs, rhs } => {
+                let (l, r) = (frame.int(*lhs), frame.int(*rhs));
+                let value = match inst {
+                    Inst::BitAnd { .. } => l & r,
+                    Inst::BitOr { .. } => l | r,
+                    _ => l ^ r,
+                };
+                frame.set_int(*dst, value);
+            }
+            Inst::BitNot { dst, src } => {
+                let value = !frame.int(*src);
+                frame.set_int(*dst, value);
+            }
+            Inst::Shl { dst, lhs, rhs } | Inst::Shr { dst, lhs, rhs } => {
+                let ty = func.value_type(*dst);
+                let bits = ty.int_bits().expect("integer type") as i128;
+                let (l, amount) = (frame.int(*lhs), frame.int(*rhs));
+                if overflow == Overflow::Trap && !(0..bits).contains(&amount) {
+                    return Err(Box::new(trap(func, *dst, "shift amount out of range")
+                        .with_note(format!("shifting {ty} by {amount} bits; the amount must be in 0..{bits}"))));
+                }
+                // `>>` of the widened value is arithmetic for signed types and
+                // logical for unsigned ones
+                let amount = amount.rem_euclid(bits) as u32;
+                let value = match inst {
+                    Inst::Shl { .. } => l << amount,
+                    _ => l >> amount,
+                };
+                frame.set_int(*dst, value);
+            }
+            Inst::Not { dst, src } => {
+                let value = !frame.bool(*src);
+                frame.set(*dst, Value::Bool(value));
+            }
+            Inst::FAdd { dst, lhs, rhs } | Inst::FSub { dst, lhs, rhs } | Inst::FMul { dst, lhs, rhs }
+            | Inst::FDiv { dst, lhs, rhs } | Inst::FRem { dst, lhs, rhs } => {
+                let (l, r) = (frame.float(*lhs), frame.float(*rhs));
+                // done in f64 and rounded once for an f32, which gives the
+                // same result as f32 arithmetic; Rust's `%` is `fmod`
+                let value = match inst {
+                    Inst::FAdd { .. } => l + r,
+                    Inst::FSub { .. } => l - r,
+                    Inst::FMul { .. } => l * r,
+                    Inst::FDiv { .. } => l / r,
+                    _ => l % r,
+                };
+                frame.set_float(*dst, value);
+            }
+            Inst::FNeg { dst, src } => {
+                let value = -frame.float(*src);
+                frame.set_float(*dst, value);
+            }
+            Inst::FGreater { dst, lhs, rhs } | Inst::FLess { dst, lhs, rhs } | Inst::FEqual { dst, lhs, rhs }
+            | Inst::FGreaterEq { dst, lhs, rhs } | Inst::FLessEq { dst, lhs, rhs } | Inst::FNotEqual { dst, lhs, rhs } => {
+                // anything involving NaN is false, except `!=`, as in the backend
+                let (l, r) = (frame.float(*lhs), frame.float(*rhs));
+                let value = match inst {
+                    Inst::FGreater { .. } => l > r,
+                    Inst::FLess { .. } => l < r,
+                    Inst::FEqual { .. } => l == r,
+                    Inst::FGreaterEq { .. } => l >= r,
+                    Inst::FLessEq { .. } => l <= r,
+                    _ => l != r,
+                };
+                frame.set(*dst, Value::Bool(value));
+            }
+            Inst::ArrayNew { dst, dims } => {
+                let mut extents = Vec::new();
+                let mut count: i64 = 1;
+                for d in dims {
+                    let extent = frame.int(*d) as i64;
+                    if extent < 0 {
+                        return Err(Box::new(trap(func, *dst, "negative array extent").with_note(format!("the extent is {extent}"))));
+                    }
+                    count = count.checked_mul(extent).ok_or_else(|| trap(func, *dst, "array too large"))?;
+                    extents.push(extent);
+                }
+                let Type::Array { elem, .. } = func.value_type(*dst) else {
+                    unreachable!("`array_new` makes an array");
+                };
+                let mut elems = Vec::new();
+                elems.try_reserve_exact(count as usize).map_err(|_| {
+                    trap(func, *dst, "array too large").with_note(format!("{count} elements do not fit in memory"))
+                })?;
+                elems.resize(count as usize, zero(module, &elem));
+                frame.set(*dst, Value::Array(Some(Rc::new(Array { extents, elems: RefCell::new(elems) }))));
+            }
+            Inst::ArrayLen { dst, array, dim } => {
+                let len = match frame.get(*array) {
+                    Value::Array(Some(a)) => a.extents[*dim as usize],
+                    _ => 0,
+                };
+                frame.set(*dst, Value::Int(len));
+            }
+            Inst::CheckIndex { index, len } => {
+                let (i, n) = (frame.int(*index), frame.int(*len));
+                // a negative index is out of bounds too
+                if module.bounds_checks && !(0..n).contains(&i) {
+                    return Err(Box::new(trap(func, *len, "index out of bounds")
+                        .with_note(format!("the index is {i} but the length is {n}"))));
+                }
+            }
+            Inst::ElemAddr { dst, array, index } => {
+                let Value::Array(a) = frame.get(*array) else {
+                    unreachable!("`elem_addr` takes an array");
+                };
+                let addr = Value::Ptr(a.clone(), frame.int(*index) as i64);
+                frame.set(*dst, addr);
+            }
+            Inst::LoadElem { dst, addr } => {
+                let value = elem(func, frame.get(*addr), *addr, |elems, i| elems[i].clone())?;
+                frame.set(*dst, value);
+            }
+            Inst::StoreElem { addr, src } => {
+                let value = frame.get(*src).clone();
+                elem(func, frame.get(*addr), *addr, |elems, i| elems[i] = value)?;
+            }
+            Inst::StructNew { dst, fields } => {
+                let value = Value::Struct(fields.iter().map(|f| frame.get(*f).clone()).collect());
+                frame.set(*dst, value);
+            }
+            Inst::ExtractField { dst, src, field } => {
+                let Value::Struct(fields) = frame.get(*src) else {
+                    unreachable!("`extract_field` takes a struct");
+                };
+                let value = fields[*field as usize].clone();
+                frame.set(*dst, value);
+            }
+            Inst::InsertField { dst, src, field, value } => {
+                let Value::Struct(fields) = frame.get(*src) else {
+                    unreachable!("`insert_field` takes a struct");
+                };
+                let mut fields = fields.clone();
+                fields[*field as usize] = frame.get(*value).clone();
+                frame.set(*dst, Value::Struct(fields));
+            }
+            Inst::Load { dst, name } => {
+                let value = frame.slots.get(name.as_str()).cloned().ok_or_else(|| {
+                    trap(func, *dst, format!("use of undefined variable '{name}'"))
+                        .with_code(codes::UNDEFINED_VARIABLE)
+                        .with_note(format!("'{name}' must be assigned before it is read"))
+                })?;
+                frame.set(*dst, value);
+            }
+            Inst::Store { name, src } => {
+                let value = frame.get(*src).clone();
+                frame.slots.insert(name, value);
+            }
+            Inst::Br { .. } | Inst::CondBr { .. } | Inst::Return { .. } | Inst::Unreachable | Inst::Call { .. } => {
+                unreachable!("handled by `run`")
+            }
+        }
+        Ok(())
+    }
+}
+
+// Passes `args` to the parameters of `target` and continues there. All the
+// arguments are read first, since a loop may pass its own parameters around.
+fn branch(frame: &mut Frame, target: BlockId, args: &[ValueId]) {
+    let args: Vec<Value> = args.iter().map(|a| frame.get(*a).clone()).collect();
+    for (&param, arg) in frame.func.block(target).params.iter().zip(args) {
+        frame.set(param, arg);
+    }
+    frame.block = target;
+    frame.next = 0;
+}
+
+// Reads or writes the element an `ElemAddr` points at. An address outside
+// the array only gets here with bounds checks off, where the backend's
+// access would be undefined; it is reported rather than guessed at.
+fn elem<T>(func: &Function, addr: &Value, id: ValueId, access: impl FnOnce(&mut Vec<Value>, usize) -> T) -> Result<T, Box<Diagnostic>> {
+    let Value::Ptr(array, index) = addr else {
+        unreachable!("elements are accessed through a ptr");
+    };
+    let len = array.as_ref().map_or(0, |a| a.elems.borrow().len());
+    match array {
+        Some(a) if (0..len as i64).contains(index) => Ok(access(&mut a.elems.borrow_mut(), *index as usize)),
+        _ => Err(Box::new(trap(func, id, "index out of bounds")
+            .with_note(format!("the element index is {index} but the array has {len} elements"))
+            .with_note("bounds checks are off, so compiled code would access memory outside the array"))),
+    }
+}
+
+// The value a zeroed element of type `ty` holds, as `calloc` gives it.
+fn zero(module: &Module, ty: &Type) -> Value {
+    match ty {
+        ty if ty.is_int() => Value::Int(0),
+        Type::Bool => Value::Bool(false),
+        Type::F32 | Type::F64 => Value::Float(0.0),
+        Type::Array { .. } => Value::Array(None),
+        Type::Struct(name) => {
+            let def = module.structs.iter().find(|s| s.name == *name).expect("the verifier checks struct types");
+            Value::Struct(def.fields.iter().map(|(_, ty)| zero(module, ty)).collect())
+        }
+        _ => unreachable!("no array holds {ty}"),
+    }
+}
+
+// A runtime error at the instruction that defines or uses `id`, with its
+// source span if lowering recorded one.
+fn trap(func: &Function, id: ValueId, message: impl Into<String>) -> Diagnostic {
+    let diag = Diagnostic::error(message).with_code(codes::RUNTIME_TRAP);
+    match func.span_of(id) {
+        Some(span) => diag.with_primary(span, "while running this expression"),
+        None => diag,
+    }
+}
@@ -1,17 +1,24 @@
 use std::collections::HashMap;
+use inkwell::intrinsics::Intrinsic;
 use inkwell::targets::{InitializationConfig, Target};
 
 
 use inkwell::{
+    basic_block::BasicBlock,
     builder::Builder,
     context::Context,
     module::Module as LlvmModule,
-    types::IntType,
-    values::{FunctionValue, IntValue, PointerValue},
+    module::Linkage,
+    types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum, IntType, StructType},
+    values::{BasicMetadataValueEnum, BasicValueEnum, FloatValue, FunctionValue, IntValue, PhiValue, PointerValue},
+    AddressSpace,
     OptimizationLevel
 };
 
-use crate::middle::ir::{Module as IrModule, Function as IrFunction, Inst, ValueId};
+use crate::diagnostic::{codes, Diagnostic};
+use crate::frontend::ast::Type;
+use crate::middle::cfg::Cfg;
+use crate::middle::ir::{BlockId, Module as IrModule, Function as IrFunction, Inst, Overflow, ValueId};
 pub fn init_llvm() {
     match Target::initialize_native(&InitializationConfig::default()) {
         Ok(()) => {}
@@ -21,13 +28,11 @@ pub fn init_llvm() {
         }
     }
 }
-pub fn jit_run_main(ir: &IrModule) -> Result<i64, String> {
-    //find IR main
-    let main_ir = ir
-        .functions
-        .iter()
-        .find(|f| f.name == "main")
-        .ok_or("No main function found".to_string())?;
+pub fn jit_run_main(ir: &IrModule) -> Result<i64, Box<Diagnostic>> {
+    //make sure there is an IR main to run
+    if !ir.functions.iter().any(|f| f.name == "main") {
+        return Err(Box::new(Diagnostic::error("no main function found").with_code(codes::JIT_FAILURE)));
+    }
 
     //setup LLVM
     let context = Context::create();
@@ -35,11 +40,38 @@ pub fn jit_run_main(ir: &IrModule) -> Result<i64, String> {
     let builder = context.create_builder();
     let i64_type = context.i64_type();
 
-    //declare LLVM main function
-    let llvm_main = declare_main_func(&context, &llvm_module, i64_type);
+    //name every struct type before giving any a body, so fields can refer
+    //to structs declared later in the file
+    for s in &ir.structs {
+        context.opaque_struct_type(&s.name);
+    }
+    for s in &ir.structs {
+        let fields: Vec<BasicTypeEnum> = s.fields.iter().map(|(_, ty)| llvm_type(&context, ty)).collect();
+        let struct_type = context.get_struct_type(&s.name).expect("named above");
+        struct_type.set_body(&fields, false);
+    }
 
-    //codegen main body
-    codegen_function(&context, &builder, i64_type, llvm_main, main_ir)?;
+    //declare every function before generating any body, so calls can refer
+    //to functions defined later in the file (and to themselves)
+    let declared: Vec<FunctionValue> = ir
+        .functions
+        .iter()
+        .map(|f| declare_function(&context, &llvm_module, f))
+        .collect();
+
+    //codegen bodies
+    for (ir_func, llvm_func) in ir.functions.iter().zip(declared) {
+        codegen_function(FunctionCodegen {
+            context: &context,
+            module: &llvm_module,
+            builder: &builder,
+            i64_type,
+            llvm_func,
+            ir_func,
+            overflow: ir.overflow,
+            bounds_checks: ir.bounds_checks,
+        })?;
+    }
 
     // Print the LLVM IR for debugging
     println!("LLVM IR:\n{}", llvm_module.print_to_string().to_string());
@@ -53,167 +85,552 @@ pub fn jit_run_main(ir: &IrModule) -> Result<i64, String> {
                         let func: extern "C" fn() -> i64 = std::mem::transmute(addr);
                         Ok(func())
                     }
-                    Err(e) => Err(format!("Failed to get 'main' symbol: {:?}", e)),
+                    Err(e) => Err(Box::new(Diagnostic::error(format!("failed to get 'main' symbol: {:?}", e))
+                        .with_code(codes::JIT_FAILURE))),
                 }
             }
         }
-        Err(e) => Err(format!("Failed to create JIT engine: {:?}", e)),
+        Err(e) => Err(Box::new(Diagnostic::error(format!("failed to create JIT engine: {:?}", e))
+            .with_code(codes::JIT_FAILURE)
+            .with_note("set LLVM_SYS_<ver>_PREFIX to a compatible LLVM installation"))),
     }
 }
 
 
 //helpers
-fn declare_main_func<'ctx>(
-    _context: &'ctx Context,
+// the LLVM signature follows the parameter and return types sema settled on
+fn declare_function<'ctx>(
+    context: &'ctx Context,
     module: &LlvmModule<'ctx>,
-    i64_type: IntType<'ctx>,
+    ir_func: &IrFunction,
 ) -> FunctionValue<'ctx> {
-    let fn_type = i64_type.fn_type(&[], false);
-    module.add_function("main", fn_type, None)
+    let param_types: Vec<BasicMetadataTypeEnum> =
+        ir_func.params.iter().map(|(_, ty)| llvm_type(context, ty).into()).collect();
+    let fn_type = llvm_type(context, &ir_func.ret).fn_type(&param_types, false);
+    module.add_function(&ir_func.name, fn_type, None)
+}
+
+// LLVM type of a Sprout value; unit values are never materialized
+fn llvm_type<'ctx>(context: &'ctx Context, ty: &Type) -> BasicTypeEnum<'ctx> {
+    match ty {
+        Type::I8 | Type::I16 | Type::I32 | Type::I64 | Type::U8 | Type::U16 | Type::U32 | Type::U64 => {
+            let bits = ty.int_bits().expect("integer type");
+            context.custom_width_int_type(bits).into()
+        }
+        Type::Bool => context.bool_type().into(),
+        Type::F32 => context.f32_type().into(),
+        Type::F64 => context.f64_type().into(),
+        // an array is a pointer to its heap block, see `array_layout`
+        Type::Array { .. } | Type::Ptr => context.ptr_type(AddressSpace::default()).into(),
+        // named in `jit_run_main` from the module's struct definitions
+        Type::Struct(name) => context.get_struct_type(name).expect("struct types are declared up front").into(),
+        Type::Unit => unreachable!("unit values have no LLVM representation"),
+    }
+}
+
+// An array's heap block: the extent of each dimension followed by the
+// elements in row-major order, `{ [rank x i64], [0 x T] }`.
+fn array_layout<'ctx>(context: &'ctx Context, array: &Type) -> StructType<'ctx> {
+    let Type::Array { elem, rank } = array else {
+        unreachable!("only arrays have a layout");
+    };
+    let dims = context.i64_type().array_type(*rank);
+    let elems = llvm_type(context, elem).array_type(0);
+    context.struct_type(&[dims.into(), elems.into()], false)
+}
+
+// Address of the extent of dimension `dim` in an array's header.
+fn build_dim_addr<'ctx>(
+    context: &'ctx Context,
+    builder: &Builder<'ctx>,
+    layout: StructType<'ctx>,
+    array: PointerValue<'ctx>,
+    dim: u32,
+) -> PointerValue<'ctx> {
+    let i32_type = context.i32_type();
+    let indices = [i32_type.const_zero(), i32_type.const_zero(), i32_type.const_int(dim as u64, false)];
+    // SAFETY: `dim` is below the rank, which is the length of the extents field
+    unsafe {
+        builder
+            .build_in_bounds_gep(layout, array, &indices, "dimaddr")
+            .expect("build_in_bounds_gep failed")
+    }
 }
 
 fn get_val<'ctx>(
-    values: &Vec<Option<IntValue<'ctx>>>,
+    values: &Vec<Option<BasicValueEnum<'ctx>>>,
     id: ValueId,
-) -> Result<IntValue<'ctx>, String> {
+    ir_func: &IrFunction,
+) -> Result<BasicValueEnum<'ctx>, Box<Diagnostic>> {
     let idx = id.get_usize();
     println!("Getting value for ValueId v{}", idx);
     values
         .get(idx)
         .and_then(|v| *v)
-        .ok_or_else(|| format!("ValueId v{} not found", idx))
+        .ok_or_else(|| {
+            Box::new(codegen_error(format!("ValueId v{} not found", idx), codes::UNDEFINED_VALUE, ir_func, id)
+                .with_note("this is a compiler bug: the value was used before codegen defined it"))
+        })
+}
+
+// the type checker decides which values are ints (including bools) and which are floats
+fn get_int<'ctx>(
+    values: &Vec<Option<BasicValueEnum<'ctx>>>,
+    id: ValueId,
+    ir_func: &IrFunction,
+) -> Result<IntValue<'ctx>, Box<Diagnostic>> {
+    Ok(get_val(values, id, ir_func)?.into_int_value())
+}
+
+fn get_float<'ctx>(
+    values: &Vec<Option<BasicValueEnum<'ctx>>>,
+    id: ValueId,
+    ir_func: &IrFunction,
+) -> Result<FloatValue<'ctx>, Box<Diagnostic>> {
+    Ok(get_val(values, id, ir_func)?.into_float_value())
+}
+
+// attach the source span of `id` (if lowering recorded one) to a codegen error
+fn codegen_error(message: String, code: &'static str, ir_func: &IrFunction, id: ValueId) -> Diagnostic {
+    let diag = Diagnostic::error(message).with_code(code);
+    match ir_func.span_of(id) {
+        Some(span) => diag.with_primary(span, "while generating code for this expression"),
+        None => diag,
+    }
 }
 
 fn set_val<'ctx>(
-    values: &mut Vec<Option<IntValue<'ctx>>>,
+    values: &mut Vec<Option<BasicValueEnum<'ctx>>>,
     id: ValueId,
-    v: IntValue<'ctx>,
+    v: impl Into<BasicValueEnum<'ctx>>,
 ) {
     let idx = id.get_usize();
     if values.len() <= idx {
         values.resize(idx + 1, None);
     }
-    values[idx] = Some(v);
+    values[idx] = Some(v.into());
 }
 
 
-fn codegen_function<'ctx>(
+// What generating the code of one function needs, passed around together.
+#[derive(Clone, Copy)]
+struct FunctionCodegen<'a, 'ctx> {
     context: &'ctx Context,
-    builder: &Builder<'ctx>,
+    module: &'a LlvmModule<'ctx>,
+    builder: &'a Builder<'ctx>,
     i64_type: IntType<'ctx>,
     llvm_func: FunctionValue<'ctx>,
-    ir_func: &IrFunction,
-) -> Result<(), String> {
-    // entry
-    let entry_bb = context.append_basic_block(llvm_func, "entry");
-    builder.position_at_end(entry_bb);
+    ir_func: &'a IrFunction,
+    overflow: Overflow,
+    bounds_checks: bool,
+}
+
+fn codegen_function<'ctx>(cg: FunctionCodegen<'_, 'ctx>) -> Result<(), Box<Diagnostic>> {
+    let FunctionCodegen { context, builder, llvm_func, ir_func, .. } = cg;
+    // one LLVM block per IR block, in reverse postorder so a value is
+    // generated before any use of it; the IR entry block is LLVM's entry
+    let order = Cfg::new(ir_func).reverse_postorder();
+    let mut blocks: Vec<Option<BasicBlock<'ctx>>> = vec![None; ir_func.blocks.len()];
+    for &b in &order {
+        let name = if b == ir_func.entry() { "entry".to_string() } else { format!("b{}", b.get_usize()) };
+        blocks[b.get_usize()] = Some(context.append_basic_block(llvm_func, &name));
+    }
 
     // map ValueId to LLVM Values
-    let mut values: Vec<Option<IntValue<'ctx>>> = Vec::new();
+    let mut values: Vec<Option<BasicValueEnum<'ctx>>> = Vec::new();
+
+    // block parameters become phis at the top of their block; the branches
+    // to the block add the incoming values. The entry block, first in the
+    // order, is never branched to
+    let mut phis: Vec<Vec<PhiValue<'ctx>>> = vec![Vec::new(); ir_func.blocks.len()];
+    for &b in &order[1..] {
+        builder.position_at_end(blocks[b.get_usize()].expect("created above"));
+        for &param in &ir_func.block(b).params {
+            let ty = llvm_type(context, &ir_func.value_type(param));
+            let phi = builder.build_phi(ty, &format!("v{}", param.get_usize())).expect("build_phi failed");
+            set_val(&mut values, param, phi.as_basic_value());
+            phis[b.get_usize()].push(phi);
+        }
+    }
+
+    // the entry block's parameters are the function's arguments
+    for (i, &param) in ir_func.block(ir_func.entry()).params.iter().enumerate() {
+        let arg = llvm_func
+            .get_nth_param(i as u32)
+            .expect("declared with one LLVM parameter per IR parameter");
+        set_val(&mut values, param, arg);
+    }
 
     // map var names to allocation pointers
     let mut vars: HashMap<String, PointerValue<'ctx>> = HashMap::new();
 
-    // track if we've seen a return instruction
-    let mut has_return = false;
-
-    // helper to codegen a single instruction (used recursively for nested blocks)
+    // helper to codegen a single instruction at the builder's position
     fn codegen_inst<'ctx>(
-        context: &'ctx Context,
-        builder: &Builder<'ctx>,
-        i64_type: IntType<'ctx>,
-        llvm_func: FunctionValue<'ctx>,
+        cg: FunctionCodegen<'_, 'ctx>,
+        blocks: &[Option<BasicBlock<'ctx>>],
+        phis: &[Vec<PhiValue<'ctx>>],
         inst: &Inst,
-        values: &mut Vec<Option<IntValue<'ctx>>>,
+        values: &mut Vec<Option<BasicValueEnum<'ctx>>>,
         vars: &mut HashMap<String, PointerValue<'ctx>>,
-    ) -> Result<(), String> {
+    ) -> Result<(), Box<Diagnostic>> {
+        let FunctionCodegen { context, module, builder, i64_type, llvm_func, ir_func, overflow, bounds_checks } = cg;
         match inst {
             Inst::Const { dst, value } => {
-                let v = i64_type.const_int(*value as u64, true);
+                let ty = llvm_type(context, &ir_func.value_type(*dst)).into_int_type();
+                let v = ty.const_int(*value as u64, true);
                 set_val(values, *dst, v);
                 Ok(())
             }
             Inst::Boolean { dst, value } => {
-                let v = i64_type.const_int(if *value { 1 } else { 0 }, false);
+                let v = context.bool_type().const_int(if *value { 1 } else { 0 }, false);
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::FConst { dst, value } => {
+                let ty = llvm_type(context, &ir_func.value_type(*dst)).into_float_type();
+                set_val(values, *dst, ty.const_float(*value));
+                Ok(())
+            }
+            Inst::Cast { dst, src } => {
+                let to = llvm_type(context, &ir_func.value_type(*dst)).into_int_type();
+                let v = if ir_func.value_type(*src).is_float() {
+                    // float -> int: rounds toward zero and saturates at the
+                    // target's range, NaN giving 0, where plain `fptosi` would
+                    // give poison; the interpreter does the same
+                    let x = get_float(values, *src, ir_func)?;
+                    let name = if ir_func.value_type(*dst).is_signed() { "llvm.fptosi.sat" } else { "llvm.fptoui.sat" };
+                    let intrinsic = Intrinsic::find(name)
+                        .and_then(|i| i.get_declaration(module, &[to.into(), x.get_type().into()]))
+                        .expect("LLVM provides the saturating conversion intrinsics");
+                    builder
+                        .build_call(intrinsic, &[x.into()], "cast")
+                        .expect("build_call failed")
+                        .try_as_basic_value()
+                        .basic()
+                        .expect("the conversion intrinsics return an int")
+                        .into_int_value()
+                } else {
+                    // bool -> int: false is 0, true is 1; an int is sign- or
+                    // zero-extended according to its own signedness
+                    let signed = ir_func.value_type(*src).is_signed();
+                    builder
+                        .build_int_cast_sign_flag(get_int(values, *src, ir_func)?, to, signed, "cast")
+                        .expect("build_int_cast_sign_flag failed")
+                };
                 set_val(values, *dst, v);
                 Ok(())
             }
             Inst::Less { dst, lhs, rhs } => {
-                let l = get_val(values, *lhs)?;
-                let r = get_val(values, *rhs)?;
+                let l = get_int(values, *lhs, ir_func)?;
+                let r = get_int(values, *rhs, ir_func)?;
+                let predicate = if ir_func.value_type(*lhs).is_signed() {
+                    inkwell::IntPredicate::SLT
+                } else {
+                    inkwell::IntPredicate::ULT
+                };
+                // comparisons produce an i1 bool
                 let cmp = builder
-                    .build_int_compare(inkwell::IntPredicate::SLT, l, r, "cmplt")
+                    .build_int_compare(predicate, l, r, "cmplt")
                     .expect("build_int_compare failed");
-                let v = builder
-                    .build_int_z_extend(cmp, i64_type, "zext")
-                    .expect("build_int_z_extend failed");
-                set_val(values, *dst, v);
+                set_val(values, *dst, cmp);
                 Ok(())
             }
             Inst::Greater { dst, lhs, rhs } => {
-                let l = get_val(values, *lhs)?;
-                let r = get_val(values, *rhs)?;
+                let l = get_int(values, *lhs, ir_func)?;
+                let r = get_int(values, *rhs, ir_func)?;
+                let predicate = if ir_func.value_type(*lhs).is_signed() {
+                    inkwell::IntPredicate::SGT
+                } else {
+                    inkwell::IntPredicate::UGT
+                };
+                // comparisons produce an i1 bool
                 let cmp = builder
-                    .build_int_compare(inkwell::IntPredicate::SGT, l, r, "cmpgt")
+                    .build_int_compare(predicate, l, r, "cmpgt")
                     .expect("build_int_compare failed");
-                let v = builder
-                    .build_int_z_extend(cmp, i64_type, "zext")
-                    .expect("build_int_z_extend failed");
-                set_val(values, *dst, v);
+                set_val(values, *dst, cmp);
                 Ok(())
             }
             Inst::Equal { dst, lhs, rhs } => {
-                let l = get_val(values, *lhs)?;
-                let r = get_val(values, *rhs)?;
+                let l = get_int(values, *lhs, ir_func)?;
+                let r = get_int(values, *rhs, ir_func)?;
+                // comparisons produce an i1 bool
                 let cmp = builder
                     .build_int_compare(inkwell::IntPredicate::EQ, l, r, "cmpeq")
                     .expect("build_int_compare failed");
-                let v = builder
-                    .build_int_z_extend(cmp, i64_type, "zext")
-                    .expect("build_int_z_extend failed");
+                set_val(values, *dst, cmp);
+                Ok(())
+            }
+            Inst::GreaterEq { dst, lhs, rhs } | Inst::LessEq { dst, lhs, rhs } | Inst::NotEqual { dst, lhs, rhs } => {
+                let l = get_int(values, *lhs, ir_func)?;
+                let r = get_int(values, *rhs, ir_func)?;
+                let signed = ir_func.value_type(*lhs).is_signed();
+                let (predicate, name) = match inst {
+                    Inst::GreaterEq { .. } if signed => (inkwell::IntPredicate::SGE, "cmpge"),
+                    Inst::GreaterEq { .. } => (inkwell::IntPredicate::UGE, "cmpge"),
+                    Inst::LessEq { .. } if signed => (inkwell::IntPredicate::SLE, "cmple"),
+                    Inst::LessEq { .. } => (inkwell::IntPredicate::ULE, "cmple"),
+                    _ => (inkwell::IntPredicate::NE, "cmpne"),
+                };
+                // comparisons produce an i1 bool
+                let cmp = builder
+                    .build_int_compare(predicate, l, r, name)
+                    .expect("build_int_compare failed");
+                set_val(values, *dst, cmp);
+                Ok(())
+            }
+            Inst::BitAnd { dst, lhs, rhs } | Inst::BitOr { dst, lhs, rhs } | Inst::BitXor { dst, lhs, rhs } => {
+                let l = get_int(values, *lhs, ir_func)?;
+                let r = get_int(values, *rhs, ir_func)?;
+                let v = match inst {
+                    Inst::BitAnd { .. } => builder.build_and(l, r, "andtmp").expect("build_and failed"),
+                    Inst::BitOr { .. } => builder.build_or(l, r, "ortmp").expect("build_or failed"),
+                    _ => builder.build_xor(l, r, "xortmp").expect("build_xor failed"),
+                };
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::BitNot { dst, src } => {
+                let v = get_int(values, *src, ir_func)?;
+                let v = builder.build_not(v, "bitnottmp").expect("build_not failed");
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::Shl { dst, lhs, rhs } | Inst::Shr { dst, lhs, rhs } => {
+                let l = get_int(values, *lhs, ir_func)?;
+                let r = get_int(values, *rhs, ir_func)?;
+                let int_type = r.get_type();
+                let width = int_type.get_bit_width() as u64;
+                let bits = int_type.const_int(width, false);
+                // LLVM gives poison for an amount of at least the bit width
+                let r = match overflow {
+                    // the widths are powers of two, so masking is the remainder
+                    Overflow::Wrap => builder
+                        .build_and(r, int_type.const_int(width - 1, false), "amount")
+                        .expect("build_and failed"),
+                    Overflow::Trap => {
+                        // unsigned, so a negative amount is out of range too
+                        let too_far = builder
+                            .build_int_compare(inkwell::IntPredicate::UGE, r, bits, "shiftrange")
+                            .expect("build_int_compare failed");
+                        build_trap_if(context, module, builder, llvm_func, too_far, "shift.overflow");
+                        r
+                    }
+                };
+                let v = match inst {
+                    Inst::Shl { .. } => builder.build_left_shift(l, r, "shltmp").expect("build_left_shift failed"),
+                    _ => {
+                        let signed = ir_func.value_type(*dst).is_signed();
+                        builder.build_right_shift(l, r, signed, "shrtmp").expect("build_right_shift failed")
+                    }
+                };
                 set_val(values, *dst, v);
                 Ok(())
             }
             Inst::Add { dst, lhs, rhs } => {
-                let l = get_val(values, *lhs)?;
-                let r = get_val(values, *rhs)?;
-                let v = builder
-                    .build_int_add(l, r, "addtmp")
-                    .expect("build_int_add failed");
+                let l = get_int(values, *lhs, ir_func)?;
+                let r = get_int(values, *rhs, ir_func)?;
+                let v = match overflow {
+                    Overflow::Wrap => builder.build_int_add(l, r, "addtmp").expect("build_int_add failed"),
+                    Overflow::Trap => {
+                        let signed = ir_func.value_type(*dst).is_signed();
+                        build_checked_arith(cg, "add", signed, l, r)
+                    }
+                };
                 set_val(values, *dst, v);
                 Ok(())
             }
             Inst::Sub { dst, lhs, rhs } => {
-                let l = get_val(values, *lhs)?;
-                let r = get_val(values, *rhs)?;
-                let v = builder
-                    .build_int_sub(l, r, "subtmp")
-                    .expect("build_int_sub failed");
+                let l = get_int(values, *lhs, ir_func)?;
+                let r = get_int(values, *rhs, ir_func)?;
+                let v = match overflow {
+                    Overflow::Wrap => builder.build_int_sub(l, r, "subtmp").expect("build_int_sub failed"),
+                    Overflow::Trap => {
+                        let signed = ir_func.value_type(*dst).is_signed();
+                        build_checked_arith(cg, "sub", signed, l, r)
+                    }
+                };
                 set_val(values, *dst, v);
                 Ok(())
             }
             Inst::Div { dst, lhs, rhs } => {
-                let l = get_val(values, *lhs)?;
-                let r = get_val(values, *rhs)?;
-                let v = builder
-                    .build_int_signed_div(l, r, "divtmp")
-                    .expect("build_int_signed_div failed");
+                let l = get_int(values, *lhs, ir_func)?;
+                let r = get_int(values, *rhs, ir_func)?;
+                let int_type = r.get_type();
+                // both of these are undefined behaviour in LLVM, so they are
+                // checked before dividing
+                let is_zero = builder
+                    .build_int_compare(inkwell::IntPredicate::EQ, r, int_type.const_zero(), "divzero")
+                    .expect("build_int_compare failed");
+                build_trap_if(context, module, builder, llvm_func, is_zero, "div.zero");
+                let v = if ir_func.value_type(*dst).is_signed() {
+                    // `MIN / -1` is the one signed division that overflows
+                    let min = int_type.const_int(1 << (int_type.get_bit_width() - 1), false);
+                    let l_min = builder
+                        .build_int_compare(inkwell::IntPredicate::EQ, l, min, "divmin")
+                        .expect("build_int_compare failed");
+                    let r_minus_one = builder
+                        .build_int_compare(inkwell::IntPredicate::EQ, r, int_type.const_all_ones(), "divm1")
+                        .expect("build_int_compare failed");
+                    let overflows = builder
+                        .build_and(l_min, r_minus_one, "divoverflow")
+                        .expect("build_and failed");
+                    let r = match overflow {
+                        // dividing by 1 instead gives MIN, the wrapped result
+                        Overflow::Wrap => builder
+                            .build_select(overflows, int_type.const_int(1, false), r, "divisor")
+                            .expect("build_select failed")
+                            .into_int_value(),
+                        Overflow::Trap => {
+                            build_trap_if(context, module, builder, llvm_func, overflows, "div.overflow");
+                            r
+                        }
+                    };
+                    builder
+                        .build_int_signed_div(l, r, "divtmp")
+                        .expect("build_int_signed_div failed")
+                } else {
+                    builder
+                        .build_int_unsigned_div(l, r, "divtmp")
+                        .expect("build_int_unsigned_div failed")
+                };
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::Rem { dst, lhs, rhs } => {
+                let l = get_int(values, *lhs, ir_func)?;
+                let r = get_int(values, *rhs, ir_func)?;
+                let int_type = r.get_type();
+                // the same undefined cases as `Div`
+                let is_zero = builder
+                    .build_int_compare(inkwell::IntPredicate::EQ, r, int_type.const_zero(), "remzero")
+                    .expect("build_int_compare failed");
+                build_trap_if(context, module, builder, llvm_func, is_zero, "rem.zero");
+                let v = if ir_func.value_type(*dst).is_signed() {
+                    // `MIN % -1` is 0, which fits, so it never traps; dividing
+                    // by 1 instead gives that 0 without LLVM's undefined behaviour
+                    let min = int_type.const_int(1 << (int_type.get_bit_width() - 1), false);
+                    let l_min = builder
+                        .build_int_compare(inkwell::IntPredicate::EQ, l, min, "remmin")
+                        .expect("build_int_compare failed");
+                    let r_minus_one = builder
+                        .build_int_compare(inkwell::IntPredicate::EQ, r, int_type.const_all_ones(), "remm1")
+                        .expect("build_int_compare failed");
+                    let overflows = builder
+                        .build_and(l_min, r_minus_one, "removerflow")
+                        .expect("build_and failed");
+                    let r = builder
+                        .build_select(overflows, int_type.const_int(1, false), r, "divisor")
+                        .expect("build_select failed")
+                        .into_int_value();
+                    builder
+                        .build_int_signed_rem(l, r, "remtmp")
+                        .expect("build_int_signed_rem failed")
+                } else {
+                    builder
+                        .build_int_unsigned_rem(l, r, "remtmp")
+                        .expect("build_int_unsigned_rem failed")
+                };
                 set_val(values, *dst, v);
                 Ok(())
             }
             Inst::Mul { dst, lhs, rhs } => {
-                let l = get_val(values, *lhs)?;
-                let r = get_val(values, *rhs)?;
+                let l = get_int(values, *lhs, ir_func)?;
+                let r = get_int(values, *rhs, ir_func)?;
+                let v = match overflow {
+                    Overflow::Wrap => builder.build_int_mul(l, r, "multmp").expect("build_int_mul failed"),
+                    Overflow::Trap => {
+                        let signed = ir_func.value_type(*dst).is_signed();
+                        build_checked_arith(cg, "mul", signed, l, r)
+                    }
+                };
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::FAdd { dst, lhs, rhs } => {
+                let l = get_float(values, *lhs, ir_func)?;
+                let r = get_float(values, *rhs, ir_func)?;
                 let v = builder
-                    .build_int_mul(l, r, "multmp")
-                    .expect("build_int_mul failed");
+                    .build_float_add(l, r, "faddtmp")
+                    .expect("build_float_add failed");
                 set_val(values, *dst, v);
                 Ok(())
             }
+            Inst::FSub { dst, lhs, rhs } => {
+                let l = get_float(values, *lhs, ir_func)?;
+                let r = get_float(values, *rhs, ir_func)?;
+                let v = builder
+                    .build_float_sub(l, r, "fsubtmp")
+                    .expect("build_float_sub failed");
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::FMul { dst, lhs, rhs } => {
+                let l = get_float(values, *lhs, ir_func)?;
+                let r = get_float(values, *rhs, ir_func)?;
+                let v = builder
+                    .build_float_mul(l, r, "fmultmp")
+                    .expect("build_float_mul failed");
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::FDiv { dst, lhs, rhs } => {
+                let l = get_float(values, *lhs, ir_func)?;
+                let r = get_float(values, *rhs, ir_func)?;
+                let v = builder
+                    .build_float_div(l, r, "fdivtmp")
+                    .expect("build_float_div failed");
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::FRem { dst, lhs, rhs } => {
+                let l = get_float(values, *lhs, ir_func)?;
+                let r = get_float(values, *rhs, ir_func)?;
+                let v = builder
+                    .build_float_rem(l, r, "fremtmp")
+                    .expect("build_float_rem failed");
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::Not { dst, src } => {
+                let v = get_int(values, *src, ir_func)?;
+                let v = builder.build_not(v, "nottmp").expect("build_not failed");
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::FNeg { dst, src } => {
+                let v = get_float(values, *src, ir_func)?;
+                let v = builder
+                    .build_float_neg(v, "fnegtmp")
+                    .expect("build_float_neg failed");
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::FGreater { dst, lhs, rhs } | Inst::FLess { dst, lhs, rhs } | Inst::FEqual { dst, lhs, rhs }
+            | Inst::FGreaterEq { dst, lhs, rhs } | Inst::FLessEq { dst, lhs, rhs } | Inst::FNotEqual { dst, lhs, rhs } => {
+                let l = get_float(values, *lhs, ir_func)?;
+                let r = get_float(values, *rhs, ir_func)?;
+                // ordered predicates: a comparison with NaN is false, as in the
+                // folder; `!=` is unordered, so it is true
+                let (predicate, name) = match inst {
+                    Inst::FGreater { .. } => (inkwell::FloatPredicate::OGT, "fcmpgt"),
+                    Inst::FLess { .. } => (inkwell::FloatPredicate::OLT, "fcmplt"),
+                    Inst::FEqual { .. } => (inkwell::FloatPredicate::OEQ, "fcmpeq"),
+                    Inst::FGreaterEq { .. } => (inkwell::FloatPredicate::OGE, "fcmpge"),
+                    Inst::FLessEq { .. } => (inkwell::FloatPredicate::OLE, "fcmple"),
+                    _ => (inkwell::FloatPredicate::UNE, "fcmpne"),
+                };
+                let cmp = builder
+                    .build_float_compare(predicate, l, r, name)
+                    .expect("build_float_compare failed");
+                set_val(values, *dst, cmp);
+                Ok(())
+            }
             Inst::Store { name, src } => {
-                let val = get_val(values, *src)?;
+                let val = get_val(values, *src, ir_func)?;
+                // the slot takes the type of the first value stored; the type
+                // checker guarantees every later store has the same type
                 let ptr = vars.entry(name.clone()).or_insert_with(|| {
-                    build_entry_alloca(context, builder, llvm_func, i64_type, name)
+                    build_entry_alloca(context, builder, llvm_func, val.get_type(), name)
                 });
                 builder
                     .build_store(*ptr, val)
@@ -223,122 +640,311 @@ fn codegen_function<'ctx>(
             Inst::Load { dst, name } => {
                 let ptr = vars
                     .get(name)
-                    .ok_or_else(|| format!("load of undefined variable '{name}'"))?;
+                    .ok_or_else(|| {
+                        codegen_error(format!("use of undefined variable '{name}'"), codes::UNDEFINED_VARIABLE, ir_func, *dst)
+                            .with_note(format!("'{name}' must be assigned before it is read"))
+                    })?;
+                let ty = llvm_type(context, &ir_func.value_type(*dst));
                 let loaded = builder
-                    .build_load(i64_type, *ptr, &format!("load_{name}"))
-                    .expect("build_load failed")
-                    .into_int_value();
+                    .build_load(ty, *ptr, &format!("load_{name}"))
+                    .expect("build_load failed");
                 set_val(values, *dst, loaded);
                 Ok(())
             }
             Inst::Return { src } => {
-                let v = get_val(values, *src)?;
+                let v = get_val(values, *src, ir_func)?;
                 let _ = builder.build_return(Some(&v));
                 // indicate stop by returning early to caller
                 Ok(())
             }
-            Inst::Call { .. } => {
-                Err("Call lowering not implemented yet".into())
-            }
-            Inst::Conditional { cond, body, else_insts, dst } => {
-                // save current builder position (in case we're nested)
-                let current_block = builder.get_insert_block();
-
-                // compute condition value
-                let cond_val = get_val(values, *cond)?;
-                let zero = i64_type.const_int(0, false);
-                let cond_bool = builder
-                    .build_int_compare(inkwell::IntPredicate::NE, cond_val, zero, "ifcond")
-                    .expect("build_int_compare failed");
-
-                // create blocks
-                let then_bb = context.append_basic_block(llvm_func, "if.then");
-                let else_bb = context.append_basic_block(llvm_func, "if.else");
-                let merge_bb = context.append_basic_block(llvm_func, "if.merge");
-
-                // pre-create entry alloca for temp so both branches store to same slot
-                let temp_name = format!("__if_tmp_{}", dst.get_usize());
-                let temp_ptr = build_entry_alloca(context, builder, llvm_func, i64_type, &temp_name);
-                // insert into vars if not already present
-                vars.entry(temp_name.clone()).or_insert(temp_ptr);
-
-                // branch from current position
-                builder
-                    .build_conditional_branch(cond_bool, then_bb, else_bb);
-
-                // THEN
-                builder.position_at_end(then_bb);
-                let mut then_terminated = false;
-                for i in body.iter() {
-                    codegen_inst(context, builder, i64_type, llvm_func, i, values, vars)?;
-                    // check if this instruction is a Return (terminates the block)
-                    if matches!(i, Inst::Return { .. }) {
-                        then_terminated = true;
-                        break;
-                    }
+            Inst::Call { dst, callee, args } => {
+                let function = module.get_function(callee).ok_or_else(|| {
+                    codegen_error(format!("call to undefined function '{callee}'"), codes::UNKNOWN_FUNCTION, ir_func, *dst)
+                })?;
+                let expected = function.count_params() as usize;
+                if expected != args.len() {
+                    return Err(Box::new(codegen_error(format!("wrong number of arguments to '{callee}'"), codes::ARGUMENT_COUNT, ir_func, *dst)
+                        .with_note(format!("'{callee}' takes {expected} argument(s) but {} were supplied", args.len()))));
                 }
-                if !then_terminated {
-                    let _ = builder.build_unconditional_branch(merge_bb);
+                let mut arg_vals: Vec<BasicMetadataValueEnum> = Vec::new();
+                for a in args {
+                    arg_vals.push(get_val(values, *a, ir_func)?.into());
                 }
-
-                // ELSE
-                builder.position_at_end(else_bb);
-                let mut else_terminated = false;
-                for i in else_insts.iter() {
-                    codegen_inst(context, builder, i64_type, llvm_func, i, values, vars)?;
-                    // check if this instruction terminates the block
-                    if matches!(i, Inst::Return { .. }) {
-                        else_terminated = true;
-                        break;
-                    }
-                    // if it's a Conditional, the builder is now at its merge block
-                    // continue adding instructions there
+                let v = builder
+                    .build_call(function, &arg_vals, "calltmp")
+                    .expect("build_call failed")
+                    .try_as_basic_value()
+                    .basic()
+                    .expect("Sprout functions always return a value");
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::ArrayNew { dst, dims } => {
+                let layout = array_layout(context, &ir_func.value_type(*dst));
+                // count the elements, trapping on a negative extent or a size
+                // that does not fit in an i64
+                let mut count = i64_type.const_int(1, false);
+                let mut dim_vals = Vec::new();
+                for d in dims {
+                    let dim = get_int(values, *d, ir_func)?;
+                    let is_negative = builder
+                        .build_int_compare(inkwell::IntPredicate::SLT, dim, i64_type.const_zero(), "dimneg")
+                        .expect("build_int_compare failed");
+                    build_trap_if(context, module, builder, llvm_func, is_negative, "array.dim");
+                    count = build_checked_arith(cg, "mul", true, count, dim);
+                    dim_vals.push(dim);
                 }
-                if !else_terminated {
-                    // branch from current position (may be else_bb or a nested conditional's merge)
-                    let _ = builder.build_unconditional_branch(merge_bb);
+                let elem_size = layout
+                    .get_field_type_at_index(1)
+                    .expect("arrays have an element field")
+                    .into_array_type()
+                    .get_element_type()
+                    .size_of()
+                    .expect("element types are sized");
+                let elems_size = build_checked_arith(cg, "mul", true, count, elem_size);
+                let header_size = layout
+                    .get_field_type_at_index(0)
+                    .expect("arrays have an extents field")
+                    .size_of()
+                    .expect("the extents are sized");
+                let size = build_checked_arith(cg, "add", true, elems_size, header_size);
+
+                // calloc zeroes the elements; the block is never freed
+                let calloc = module.get_function("calloc").unwrap_or_else(|| {
+                    let ptr_type = context.ptr_type(AddressSpace::default());
+                    let fn_type = ptr_type.fn_type(&[i64_type.into(), i64_type.into()], false);
+                    module.add_function("calloc", fn_type, Some(Linkage::External))
+                });
+                let ptr = builder
+                    .build_call(calloc, &[i64_type.const_int(1, false).into(), size.into()], "array")
+                    .expect("build_call failed")
+                    .try_as_basic_value()
+                    .basic()
+                    .expect("calloc returns a pointer")
+                    .into_pointer_value();
+                for (d, dim) in dim_vals.into_iter().enumerate() {
+                    let dim_ptr = build_dim_addr(context, builder, layout, ptr, d as u32);
+                    builder.build_store(dim_ptr, dim).expect("build_store failed");
                 }
-
-                // MERGE: load temp into dst
-                builder.position_at_end(merge_bb);
-                let ptr = vars.get(&temp_name).expect("temp ptr missing");
+                set_val(values, *dst, ptr);
+                Ok(())
+            }
+            Inst::ArrayLen { dst, array, dim } => {
+                let ptr = get_val(values, *array, ir_func)?.into_pointer_value();
+                let layout = array_layout(context, &ir_func.value_type(*array));
+                // an array inside a zeroed array element (an `array(n)` of
+                // arrays or of structs holding arrays) is a null pointer,
+                // which reads as empty so indexing it fails the bounds check
+                let is_null = builder.build_is_null(ptr, "isnull").expect("build_is_null failed");
+                let entry_bb = builder.get_insert_block().expect("builder is positioned");
+                let load_bb = context.append_basic_block(llvm_func, "len.load");
+                let done_bb = context.append_basic_block(llvm_func, "len.done");
+                builder
+                    .build_conditional_branch(is_null, done_bb, load_bb)
+                    .expect("build_conditional_branch failed");
+                builder.position_at_end(load_bb);
+                let dim_ptr = build_dim_addr(context, builder, layout, ptr, *dim);
                 let loaded = builder
-                    .build_load(i64_type, *ptr, &format!("load_if_{}", dst.get_usize()))
-                    .expect("build_load failed")
-                    .into_int_value();
+                    .build_load(i64_type, dim_ptr, "len")
+                    .expect("build_load failed");
+                builder.build_unconditional_branch(done_bb).expect("build_unconditional_branch failed");
+                builder.position_at_end(done_bb);
+                let len = builder.build_phi(i64_type, "len").expect("build_phi failed");
+                len.add_incoming(&[(&i64_type.const_zero(), entry_bb), (&loaded, load_bb)]);
+                set_val(values, *dst, len.as_basic_value());
+                Ok(())
+            }
+            Inst::CheckIndex { index, len } => {
+                if bounds_checks {
+                    let index = get_int(values, *index, ir_func)?;
+                    let len = get_int(values, *len, ir_func)?;
+                    // unsigned, so a negative index is out of bounds too
+                    let out_of_bounds = builder
+                        .build_int_compare(inkwell::IntPredicate::UGE, index, len, "oob")
+                        .expect("build_int_compare failed");
+                    build_trap_if(context, module, builder, llvm_func, out_of_bounds, "bounds");
+                }
+                Ok(())
+            }
+            Inst::ElemAddr { dst, array, index } => {
+                let ptr = get_val(values, *array, ir_func)?.into_pointer_value();
+                let index = get_int(values, *index, ir_func)?;
+                let layout = array_layout(context, &ir_func.value_type(*array));
+                let zero = context.i32_type().const_zero();
+                let elems = context.i32_type().const_int(1, false);
+                // SAFETY: lowering checked the index unless bounds checks are off
+                let addr = unsafe {
+                    builder
+                        .build_in_bounds_gep(layout, ptr, &[zero, elems, index], "elemaddr")
+                        .expect("build_in_bounds_gep failed")
+                };
+                set_val(values, *dst, addr);
+                Ok(())
+            }
+            Inst::LoadElem { dst, addr } => {
+                let ptr = get_val(values, *addr, ir_func)?.into_pointer_value();
+                let ty = llvm_type(context, &ir_func.value_type(*dst));
+                let loaded = builder.build_load(ty, ptr, "elem").expect("build_load failed");
                 set_val(values, *dst, loaded);
-                
-                // if we were called from within a parent block (nested conditional),
-                // restore builder position to the merge block so parent can continue
-                // (merge is now the "current" block for any code after this conditional)
+                Ok(())
+            }
+            Inst::StoreElem { addr, src } => {
+                let ptr = get_val(values, *addr, ir_func)?.into_pointer_value();
+                let val = get_val(values, *src, ir_func)?;
+                builder.build_store(ptr, val).expect("build_store failed");
+                Ok(())
+            }
+            Inst::StructNew { dst, fields } => {
+                let struct_type = llvm_type(context, &ir_func.value_type(*dst)).into_struct_type();
+                let mut agg = struct_type.const_zero().into();
+                for (i, f) in fields.iter().enumerate() {
+                    let v = get_val(values, *f, ir_func)?;
+                    agg = builder
+                        .build_insert_value(agg, v, i as u32, "field")
+                        .expect("build_insert_value failed");
+                }
+                set_val(values, *dst, agg.into_struct_value());
+                Ok(())
+            }
+            Inst::ExtractField { dst, src, field } => {
+                let agg = get_val(values, *src, ir_func)?.into_struct_value();
+                let v = builder
+                    .build_extract_value(agg, *field, "field")
+                    .expect("build_extract_value failed");
+                set_val(values, *dst, v);
+                Ok(())
+            }
+            Inst::InsertField { dst, src, field, value } => {
+                let agg = get_val(values, *src, ir_func)?.into_struct_value();
+                let v = get_val(values, *value, ir_func)?;
+                let updated = builder
+                    .build_insert_value(agg, v, *field, "withfield")
+                    .expect("build_insert_value failed");
+                set_val(values, *dst, updated.into_struct_value());
+                Ok(())
+            }
+            Inst::Br { target, args } => {
+                add_incoming(builder, phis, values, ir_func, *target, args)?;
+                builder
+                    .build_unconditional_branch(blocks[target.get_usize()].expect("branch targets are reachable"))
+                    .expect("build_unconditional_branch failed");
+                Ok(())
+            }
+            Inst::CondBr { cond, then_block, then_args, else_block, else_args } => {
+                // the condition is already an i1
+                let cond_bool = get_int(values, *cond, ir_func)?;
+                add_incoming(builder, phis, values, ir_func, *then_block, then_args)?;
+                add_incoming(builder, phis, values, ir_func, *else_block, else_args)?;
+                builder
+                    .build_conditional_branch(
+                        cond_bool,
+                        blocks[then_block.get_usize()].expect("branch targets are reachable"),
+                        blocks[else_block.get_usize()].expect("branch targets are reachable"),
+                    )
+                    .expect("build_conditional_branch failed");
+                Ok(())
+            }
+            Inst::Unreachable => {
+                builder.build_unreachable().expect("build_unreachable failed");
                 Ok(())
             }
         }
     }
 
-    // iterate top-level body and codegen each instruction via helper
-    for inst in &ir_func.body {
-        codegen_inst(context, builder, i64_type, llvm_func, inst, &mut values, &mut vars)?;
+    // every block ends in its terminator, so none needs a default return
+    for &b in &order {
+        builder.position_at_end(blocks[b.get_usize()].expect("created above"));
+        for inst in &ir_func.block(b).insts {
+            codegen_inst(cg, &blocks, &phis, inst, &mut values, &mut vars)?;
+        }
     }
+    Ok(())
+}
 
-    // only emit default return if the current block doesn't already have a terminator
-    // (a terminator is a return or branch instruction that ends a basic block)
-    if let Some(current_block) = builder.get_insert_block() {
-        if current_block.get_terminator().is_none() {
-            // no terminator, so emit default return 0
-            let zero = i64_type.const_int(0, false);
-            let _ = builder.build_return(Some(&zero));
-        }
+// Pass the arguments of a branch out of the current block to the phis that
+// stand for the target block's parameters.
+fn add_incoming<'ctx>(
+    builder: &Builder<'ctx>,
+    phis: &[Vec<PhiValue<'ctx>>],
+    values: &Vec<Option<BasicValueEnum<'ctx>>>,
+    ir_func: &IrFunction,
+    target: BlockId,
+    args: &[ValueId],
+) -> Result<(), Box<Diagnostic>> {
+    // code for an instruction like `ArrayLen` may have moved on to a block
+    // of its own, so the branch comes from wherever the builder is now
+    let from = builder.get_insert_block().expect("positioned in a block");
+    for (phi, arg) in phis[target.get_usize()].iter().zip(args) {
+        phi.add_incoming(&[(&get_val(values, *arg, ir_func)?, from)]);
     }
     Ok(())
 }
 
 
+// Branch to a block that aborts the program when `failed` is set; code
+// generation continues on the path where it is not.
+fn build_trap_if<'ctx>(
+    context: &'ctx Context,
+    module: &LlvmModule<'ctx>,
+    builder: &Builder<'ctx>,
+    func: FunctionValue<'ctx>,
+    failed: IntValue<'ctx>,
+    what: &str,
+) {
+    let trap_bb = context.append_basic_block(func, &format!("{what}.trap"));
+    let ok_bb = context.append_basic_block(func, &format!("{what}.ok"));
+    builder
+        .build_conditional_branch(failed, trap_bb, ok_bb)
+        .expect("build_conditional_branch failed");
+
+    builder.position_at_end(trap_bb);
+    let trap = Intrinsic::find("llvm.trap")
+        .and_then(|i| i.get_declaration(module, &[]))
+        .expect("LLVM provides llvm.trap");
+    builder.build_call(trap, &[], "").expect("build_call failed");
+    builder.build_unreachable().expect("build_unreachable failed");
+
+    builder.position_at_end(ok_bb);
+}
+
+// `l op r` through LLVM's `*.with.overflow` intrinsics, trapping if the
+// result does not fit. `op` is "add", "sub" or "mul".
+fn build_checked_arith<'ctx>(
+    cg: FunctionCodegen<'_, 'ctx>,
+    op: &str,
+    signed: bool,
+    l: IntValue<'ctx>,
+    r: IntValue<'ctx>,
+) -> IntValue<'ctx> {
+    let FunctionCodegen { context, module, builder, llvm_func: func, .. } = cg;
+    let name = format!("llvm.{}{op}.with.overflow", if signed { "s" } else { "u" });
+    let intrinsic = Intrinsic::find(&name)
+        .and_then(|i| i.get_declaration(module, &[l.get_type().into()]))
+        .expect("LLVM provides the overflow intrinsics");
+    let pair = builder
+        .build_call(intrinsic, &[l.into(), r.into()], &format!("{op}.checked"))
+        .expect("build_call failed")
+        .try_as_basic_value()
+        .basic()
+        .expect("overflow intrinsics return a {result, overflowed} pair")
+        .into_struct_value();
+    let value = builder
+        .build_extract_value(pair, 0, &format!("{op}tmp"))
+        .expect("build_extract_value failed")
+        .into_int_value();
+    let overflowed = builder
+        .build_extract_value(pair, 1, "overflowed")
+        .expect("build_extract_value failed")
+        .into_int_value();
+    build_trap_if(context, module, builder, func, overflowed, "overflow");
+    value
+}
+
 fn build_entry_alloca<'ctx>(
     _context: &'ctx Context,
     builder: &Builder<'ctx>,
     func: FunctionValue<'ctx>,
-    i64_type: IntType<'ctx>,
+    ty: impl BasicType<'ctx>,
     name: &str,
 ) -> PointerValue<'ctx> {
     let entry = func.get_first_basic_block().unwrap();
@@ -352,7 +958,7 @@ fn build_entry_alloca<'ctx>(
         builder.position_at_end(entry);
     }
 
-    let alloca = builder.build_alloca(i64_type, name).expect("Alloca Failed");
+    let alloca = builder.build_alloca(ty, name).expect("Alloca Failed");
 
     // restore insertion point
     builder.position_at_end(current_block);
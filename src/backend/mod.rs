@@ -1 +1,4 @@
+pub mod interp;
+// needs an LLVM installation; build with `--no-default-features` to do without
+#[cfg(feature = "llvm")]
 pub mod llvm;
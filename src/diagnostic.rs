@@ -0,0 +1,218 @@
+use std::fmt;
+use crate::frontend::span::{SourceFile, Span};
+
+/// Error codes, one per kind of problem, so they can be searched for and documented.
+pub mod codes {
+    // lexing and syntax
+    pub const UNEXPECTED_TOKEN: &str = "E0001";
+    pub const EXPECTED_TOKEN: &str = "E0002";
+    pub const UNKNOWN_CHARACTER: &str = "E0003";
+    pub const INTEGER_OVERFLOW: &str = "E0004";
+    pub const INCONSISTENT_INDENT: &str = "E0005";
+    pub const CHAINED_COMPARISON: &str = "E0006";
+    pub const FLOAT_OVERFLOW: &str = "E0007";
+    pub const MIXED_INDENTATION: &str = "E0008";
+
+    // lowering and name resolution
+    pub const INVALID_ASSIGN_TARGET: &str = "E0101";
+    pub const UNSUPPORTED_CALLEE: &str = "E0102";
+    pub const NESTED_FUNCTION: &str = "E0103";
+    pub const DUPLICATE_FUNCTION: &str = "E0104";
+    pub const NESTED_STRUCT: &str = "E0105";
+
+    // codegen / JIT
+    pub const UNDEFINED_VARIABLE: &str = "E0201";
+    // only the LLVM backend reports these
+    #[cfg(feature = "llvm")]
+    pub const UNDEFINED_VALUE: &str = "E0202";
+    #[cfg(feature = "llvm")]
+    pub const JIT_FAILURE: &str = "E0204";
+    pub const UNKNOWN_FUNCTION: &str = "E0205";
+    pub const ARGUMENT_COUNT: &str = "E0206";
+
+    // semantic analysis
+    pub const USED_BEFORE_ASSIGNED: &str = "E0301";
+    pub const DUPLICATE_PARAMETER: &str = "E0302";
+    pub const TYPE_MISMATCH: &str = "E0303";
+    pub const UNKNOWN_TYPE: &str = "E0304";
+    pub const UNKNOWN_FIELD: &str = "E0305";
+    pub const MISSING_FIELD: &str = "E0306";
+    pub const DUPLICATE_FIELD: &str = "E0307";
+    pub const DUPLICATE_STRUCT: &str = "E0308";
+    pub const RECURSIVE_STRUCT: &str = "E0309";
+
+    // IR verification
+    pub const INVALID_IR: &str = "E0401";
+
+    // running programs in the interpreter
+    pub const RUNTIME_TRAP: &str = "E0501";
+    pub const INVALID_MAIN: &str = "E0502";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// A compiler message: what went wrong, where, and anything that helps fix it.
+/// The primary label is the offending code; secondary labels point at related
+/// code (e.g. the `if` that is missing its `:`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Option<&'static str>,
+    pub message: String,
+    pub primary: Option<Label>,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity,
+            code: None,
+            message: message.into(),
+            primary: None,
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Diagnostic::new(Severity::Error, message)
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_primary(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.primary = Some(Label { span, message: message.into() });
+        self
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label { span, message: message.into() });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Render in the familiar rustc layout:
+    ///
+    /// ```text
+    /// error[E0002]: expected ':' after if condition
+    ///  --> tests/conditional.sp:2:10
+    ///   |
+    /// 2 | if x > 5 x
+    ///   | -- this `if` needs a ':' before its body
+    ///   |          ^ expected ':'
+    /// ```
+    ///
+    /// Each label gets its own underline row beneath its line; the primary
+    /// label is drawn with `^`, secondary labels with `-`.
+    pub fn render(&self, file: &SourceFile) -> String {
+        let mut out = String::new();
+        match self.code {
+            Some(code) => out.push_str(&format!("{}[{}]: {}\n", self.severity, code, self.message)),
+            None => out.push_str(&format!("{}: {}\n", self.severity, self.message)),
+        }
+
+        // (span, message, is_primary), sorted by position so lines print top to bottom
+        let mut labels: Vec<(Span, &str, bool)> = Vec::new();
+        if let Some(p) = &self.primary {
+            labels.push((p.span, &p.message, true));
+        }
+        for l in &self.labels {
+            labels.push((l.span, &l.message, false));
+        }
+        labels.sort_by_key(|(span, _, _)| (span.start, span.end));
+
+        let location_span = self.primary.as_ref().map(|p| p.span).or(labels.first().map(|l| l.0));
+        let max_line = labels
+            .iter()
+            .map(|(span, _, _)| file.line_col(span.start).0)
+            .max()
+            .unwrap_or(1);
+        let gutter = " ".repeat(max_line.to_string().len());
+
+        match location_span {
+            Some(span) => {
+                let (line, col) = file.line_col(span.start);
+                out.push_str(&format!("{gutter}--> {}:{}:{}\n", file.name, line, col));
+            }
+            None => out.push_str(&format!("{gutter}--> {}\n", file.name)),
+        }
+
+        if !labels.is_empty() {
+            out.push_str(&format!("{gutter} |\n"));
+            let mut i = 0;
+            while i < labels.len() {
+                let line = file.line_col(labels[i].0.start).0;
+                let text = file.line_text(line);
+                out.push_str(&format!("{:>width$} | {}\n", line, text, width = gutter.len()));
+
+                // every label that starts on this line gets an underline row
+                while i < labels.len() && file.line_col(labels[i].0.start).0 == line {
+                    let (span, message, primary) = labels[i];
+                    let (_, col) = file.line_col(span.start);
+                    // multi-line spans are underlined to the end of their first line
+                    let end_col = if file.line_col(span.end).0 == line {
+                        file.line_col(span.end).1
+                    } else {
+                        text.chars().count() + 1
+                    };
+                    let width = end_col.saturating_sub(col).max(1);
+                    let mark = if primary { "^" } else { "-" };
+                    out.push_str(&format!(
+                        "{gutter} | {}{} {}\n",
+                        " ".repeat(col - 1),
+                        mark.repeat(width),
+                        message
+                    ));
+                    i += 1;
+                }
+            }
+        }
+
+        if !self.notes.is_empty() {
+            out.push_str(&format!("{gutter} |\n"));
+            for note in &self.notes {
+                out.push_str(&format!("{gutter} = note: {note}\n"));
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{}[{}]: {}", self.severity, code, self.message)?,
+            None => write!(f, "{}: {}", self.severity, self.message)?,
+        }
+        if let Some(p) = &self.primary {
+            write!(f, " (at {})", p.span)?;
+        }
+        Ok(())
+    }
+}
@@ -1,30 +1,263 @@
 use std::fmt;
+use crate::frontend::span::Span;
 
+/// An expression together with the source range it was parsed from.
+/// `ty` is filled in by `sema::typeck`.
 #[derive(Debug, Clone, PartialEq)]
-pub enum Expr {
-    Number(i64),
-    Ident(String),
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+    pub ty: Option<Type>,
+}
+
+/// The type of a value. `Unit` is the type of code that produces no value,
+/// such as loops and an `if` without `else`. Integer arithmetic wraps on
+/// overflow unless the program is compiled with checked overflow.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Type {
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    Bool,
+    F32,
+    F64,
+    Unit,
+    // a reference to a heap array of `rank` dimensions, stored row-major with
+    // its extents; copying the value shares the elements
+    Array { elem: Box<Type>, rank: u32 },
+    // a value of a declared struct, copied as a whole like a number
+    Struct(String),
+    // only in the IR: the address of an array element
+    Ptr,
+}
+
+impl Type {
+    pub fn is_int(&self) -> bool {
+        self.int_bits().is_some()
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(self, Type::I8 | Type::I16 | Type::I32 | Type::I64)
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, Type::F32 | Type::F64)
+    }
+
+    /// Width of an integer type; `None` for everything else, including bool.
+    pub fn int_bits(&self) -> Option<u32> {
+        match self {
+            Type::I8 | Type::U8 => Some(8),
+            Type::I16 | Type::U16 => Some(16),
+            Type::I32 | Type::U32 => Some(32),
+            Type::I64 | Type::U64 => Some(64),
+            _ => None,
+        }
+    }
+
+    /// Smallest and largest value of an integer type.
+    pub fn int_range(&self) -> Option<(i128, i128)> {
+        let bits = self.int_bits()?;
+        if self.is_signed() {
+            Some((-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1))
+        } else {
+            Some((0, (1i128 << bits) - 1))
+        }
+    }
+}
+
+/// Index into the resolver's symbol table. Names start out unresolved (`None`)
+/// and are filled in by `sema::resolve`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SymbolId(pub u32);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Number(u64),
+    Float(f64),
+    Bool(bool),
+    Ident { name: String, sym: Option<SymbolId> },
     Unary { op: UnaryOp, expr: Box<Expr> },
     Binary { left: Box<Expr>, op: BinaryOp, right: Box<Expr> },
     Call { callee: Box<Expr>, args: Vec<Expr> },
-    If {cond: Box<Expr>, body: Box<Expr>, else_branch: Option<Box<Expr>>}
+    // has a value only when there is an `else`
+    If {cond: Box<Expr>, body: Box<Stmt>, else_branch: Option<Box<Stmt>>},
+    // re-evaluates `cond` before every iteration; has no value
+    While { cond: Box<Expr>, body: Box<Stmt> },
+    // `for var in start..end`: counts from `start` up to, not including, `end`.
+    // Both bounds are evaluated once, before the first iteration. Has no value.
+    For { var: Box<Expr>, start: Box<Expr>, end: Box<Expr>, body: Box<Stmt> },
+    // `[a, b, c]`
+    Array(Vec<Expr>),
+    // `array(n)`, `array(n, m)`, ...: a new zeroed array with one dimension
+    // per extent
+    NewArray { dims: Vec<Expr> },
+    // `len(a)` or `len(a, d)`: the extent of dimension `d` (0 if omitted),
+    // which must be an integer literal
+    Len { array: Box<Expr>, dim: Option<Box<Expr>> },
+    // `a[i]`, `m[i, j]`, ... with one index per dimension; also a place that
+    // can be assigned to
+    Index { array: Box<Expr>, indices: Vec<Expr> },
+    // `Particle { x: 1.0, v: 0.0 }`: every field exactly once, in any order
+    StructLit { name: String, fields: Vec<FieldInit> },
+    // `p.x`; also a place that can be assigned to. `index` is the field's
+    // position in its struct, filled in by `sema::typeck`
+    Field { base: Box<Expr>, name: String, index: Option<u32> },
+    // placeholder for code that failed to parse; never reaches lowering
+    Error,
 }
 
-//add increment operation later
+// `Not` is both `not e` and `!e`; `BitNot` is `~e`
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub enum UnaryOp { Neg }
+pub enum UnaryOp { Neg, Not, BitNot }
 
+// `And` and `Or` short-circuit: the right operand only runs when the left
+// one does not already decide the result
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub enum BinaryOp { Add, Sub, Mul, Div, Assign, Greater, Less, Equal }
+pub enum BinaryOp {
+    Add, Sub, Mul, Div, Rem,
+    Greater, Less, Equal, GreaterEq, LessEq, NotEqual,
+    And, Or,
+    BitAnd, BitOr, BitXor, Shl, Shr,
+}
 
-impl fmt::Display for Expr {
+/// A statement. Statements also produce a value so that `if` bodies and the
+/// program itself can evaluate to their last statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+/// A type written in the source, like the `int` in `x: int = 1`. Names are
+/// looked up by `sema::typeck`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeExpr {
+    pub kind: TypeExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeExprKind {
+    Named(String),
+    // `[T]`, or `[T, N]` for `N` dimensions; `rank` is checked by the type checker
+    Array { elem: Box<TypeExpr>, rank: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    // evaluates to the expression
+    Expr(Expr),
+    // `x = v`, `x: T = v` or `a[i] = v`; evaluates to the assigned value
+    Assign { target: Expr, ann: Option<TypeExpr>, value: Expr },
+    // `x += v`, `x -= v`, `x *= v` or `x /= v` on any assignable target, with
+    // `op` the arithmetic it does. `x++` and `x--` have no `value` and add or
+    // subtract one. The target is evaluated once; evaluates to the new value
+    CompoundAssign { target: Expr, op: BinaryOp, value: Option<Expr> },
+    // evaluates to its last statement; an empty block has no value
+    Block(Vec<Stmt>),
+    // `struct Name { a: T, b: T }`; only allowed at the top level of a
+    // program. Has no value.
+    Struct { name: String, fields: Vec<FieldDecl> },
+    // `fn name(a, b: T) -> T: body`; only allowed at the top level of a program.
+    // The function returns its body's value unless it hits a `return` first.
+    // `ret_ty` is the return type, written or inferred, filled in by `sema::typeck`.
+    Fn { name: String, params: Vec<Param>, ret: Option<TypeExpr>, ret_ty: Option<Type>, body: Box<Stmt> },
+    // leaves the enclosing function (or the program) with the value
+    Return(Expr),
+}
+
+/// A function parameter, with its optional annotation. `ty` is the
+/// parameter's type, written or inferred, filled in by `sema::typeck`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: String,
+    pub span: Span,
+    pub sym: Option<SymbolId>,
+    pub ann: Option<TypeExpr>,
+    pub ty: Option<Type>,
+}
+
+/// A field of a struct declaration. `ty` is the annotation's meaning,
+/// filled in by `sema::typeck`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDecl {
+    pub name: String,
+    pub span: Span,
+    pub ann: TypeExpr,
+    pub ty: Option<Type>,
+}
+
+/// One `name: value` of a struct literal. `index` is the field's position in
+/// its struct, filled in by `sema::typeck`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldInit {
+    pub name: String,
+    pub span: Span,
+    pub value: Expr,
+    pub index: Option<u32>,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span, ty: None }
+    }
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Stmt { kind, span }
+    }
+}
+
+impl fmt::Display for Type {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Expr::Number(n) => write!(f, "{}", n),
-            Expr::Ident(s) => write!(f, "{}", s),
-            Expr::Unary { op, expr } => write!(f, "({:?} {})", op, expr),
-            Expr::Binary { left, op, right } => write!(f, "({} {:?} {})", left, op, right),
-            Expr::Call { callee, args } => {
+            Type::I8 => write!(f, "i8"),
+            Type::I16 => write!(f, "i16"),
+            Type::I32 => write!(f, "i32"),
+            Type::I64 => write!(f, "i64"),
+            Type::U8 => write!(f, "u8"),
+            Type::U16 => write!(f, "u16"),
+            Type::U32 => write!(f, "u32"),
+            Type::U64 => write!(f, "u64"),
+            Type::Bool => write!(f, "bool"),
+            Type::F32 => write!(f, "f32"),
+            Type::F64 => write!(f, "f64"),
+            Type::Unit => write!(f, "unit"),
+            Type::Array { elem, rank: 1 } => write!(f, "[{}]", elem),
+            Type::Array { elem, rank } => write!(f, "[{}, {}]", elem, rank),
+            Type::Struct(name) => write!(f, "{}", name),
+            Type::Ptr => write!(f, "ptr"),
+        }
+    }
+}
+
+impl fmt::Display for TypeExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            TypeExprKind::Named(name) => write!(f, "{}", name),
+            TypeExprKind::Array { elem, rank: 1 } => write!(f, "[{}]", elem),
+            TypeExprKind::Array { elem, rank } => write!(f, "[{}, {}]", elem, rank),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Number(n) => write!(f, "{}", n),
+            ExprKind::Float(x) => write!(f, "{:?}", x),
+            ExprKind::Bool(b) => write!(f, "{}", b),
+            ExprKind::Ident { name, .. } => write!(f, "{}", name),
+            ExprKind::Unary { op, expr } => write!(f, "({:?} {})", op, expr),
+            ExprKind::Binary { left, op, right } => write!(f, "({} {:?} {})", left, op, right),
+            ExprKind::Call { callee, args } => {
                 write!(f, "{}(", callee)?;
                 for (i, a) in args.iter().enumerate() {
                     if i > 0 { write!(f, ", ")?; }
@@ -32,13 +265,75 @@ impl fmt::Display for Expr {
                 }
                 write!(f, ")")
             }
-            Expr::If { cond, body, else_branch } => {
+            ExprKind::If { cond, body, else_branch } => {
                 if let Some(else_e) = else_branch {
                     write!(f, "if {} {} else {}", cond, body, else_e)
                 } else {
                     write!(f, "if {} {}", cond, body)
                 }
             }
+            ExprKind::While { cond, body } => write!(f, "while {} {}", cond, body),
+            ExprKind::For { var, start, end, body } => write!(f, "for {} in {}..{} {}", var, start, end, body),
+            ExprKind::Array(elems) => {
+                let elems: Vec<String> = elems.iter().map(|e| e.to_string()).collect();
+                write!(f, "[{}]", elems.join(", "))
+            }
+            ExprKind::NewArray { dims } => {
+                let dims: Vec<String> = dims.iter().map(|e| e.to_string()).collect();
+                write!(f, "array({})", dims.join(", "))
+            }
+            ExprKind::Len { array, dim: Some(dim) } => write!(f, "len({}, {})", array, dim),
+            ExprKind::Len { array, dim: None } => write!(f, "len({})", array),
+            ExprKind::Index { array, indices } => {
+                let indices: Vec<String> = indices.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}[{}]", array, indices.join(", "))
+            }
+            ExprKind::StructLit { name, fields } => {
+                let fields: Vec<String> = fields.iter().map(|i| format!("{}: {}", i.name, i.value)).collect();
+                write!(f, "{} {{ {} }}", name, fields.join(", "))
+            }
+            ExprKind::Field { base, name, .. } => write!(f, "{}.{}", base, name),
+            ExprKind::Error => write!(f, "<error>"),
+        }
+    }
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            StmtKind::Expr(e) => write!(f, "{}", e),
+            StmtKind::Assign { target, ann: Some(ann), value } => write!(f, "({}: {} = {})", target, ann, value),
+            StmtKind::Assign { target, ann: None, value } => write!(f, "({} = {})", target, value),
+            StmtKind::CompoundAssign { target, op, value: Some(value) } => write!(f, "({} {:?}= {})", target, op, value),
+            StmtKind::CompoundAssign { target, op: BinaryOp::Add, value: None } => write!(f, "({}++)", target),
+            StmtKind::CompoundAssign { target, value: None, .. } => write!(f, "({}--)", target),
+            StmtKind::Block(stmts) => {
+                write!(f, "{{")?;
+                for (i, s) in stmts.iter().enumerate() {
+                    if i > 0 { write!(f, "; ")?; }
+                    write!(f, "{}", s)?;
+                }
+                write!(f, "}}")
+            }
+            StmtKind::Struct { name, fields } => {
+                let fields: Vec<String> = fields.iter().map(|d| format!("{}: {}", d.name, d.ann)).collect();
+                write!(f, "struct {} {{ {} }}", name, fields.join(", "))
+            }
+            StmtKind::Fn { name, params, ret, body, .. } => {
+                let params: Vec<String> = params
+                    .iter()
+                    .map(|p| match &p.ann {
+                        Some(ann) => format!("{}: {}", p.name, ann),
+                        None => p.name.clone(),
+                    })
+                    .collect();
+                write!(f, "fn {}({})", name, params.join(", "))?;
+                if let Some(ret) = ret {
+                    write!(f, " -> {}", ret)?;
+                }
+                write!(f, " {}", body)
+            }
+            StmtKind::Return(e) => write!(f, "return {}", e),
         }
     }
 }
@@ -1,91 +1,384 @@
-#[derive(Clone, Debug, PartialEq)]
+use std::fmt;
+use logos::Logos;
+use crate::frontend::span::{Span, Spanned};
+use crate::diagnostic::{codes, Diagnostic};
+
+/// Why logos rejected a piece of input. The default covers any character
+/// that does not start a valid token.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum LexError {
+    #[default]
+    UnknownChar,
+    IntegerOverflow,
+    FloatOverflow,
+}
+
+#[derive(Logos, Clone, Debug, PartialEq)]
+#[logos(error = LexError)]
+#[logos(skip r"[ \t\r]+")]
+#[logos(skip r"#[^\n]*")]
 pub enum Token {
+    // keywords are lexed as identifiers first and then looked up in KEYWORDS
     If,
     Else,
+    While,
+    For,
+    In,
+    Fn,
+    Return,
+    Struct,
+    And,
+    Or,
+    Not,
+    True,
+    False,
+    #[token("+")]
     Plus,
+    #[token("-")]
     Minus,
+    #[token("*")]
     Star,
+    #[token("/")]
     Slash,
+    #[token("%")]
+    Percent,
+    #[token("&")]
+    Amp,
+    #[token("|")]
+    Pipe,
+    #[token("^")]
+    Caret,
+    #[token("~")]
+    Tilde,
+    #[token("!")]
+    Bang,
+    #[token("<<")]
+    Shl,
+    #[token(">>")]
+    Shr,
+    #[token("+=")]
+    PlusEq,
+    #[token("-=")]
+    MinusEq,
+    #[token("*=")]
+    StarEq,
+    #[token("/=")]
+    SlashEq,
+    #[token("++")]
+    PlusPlus,
+    #[token("--")]
+    MinusMinus,
+    #[token("(")]
     LParen,
+    #[token(")")]
     RParen,
+    #[token("[")]
+    LBracket,
+    #[token("]")]
+    RBracket,
+    #[token("{")]
+    LBrace,
+    #[token("}")]
+    RBrace,
+    #[token(",")]
     Comma,
+    #[token("=")]
     Equals,
+    #[token(">")]
     Gt,
+    #[token("<")]
     Lt,
+    #[token("==")]
     EqComp,
+    #[token("!=")]
+    NotEq,
+    #[token(">=")]
+    GtEq,
+    #[token("<=")]
+    LtEq,
+    #[token(":")]
     Colon,
+    #[token("..")]
+    DotDot,
+    #[token(".")]
+    Dot,
+    #[token("->")]
+    Arrow,
+    #[token(";")]
     Semicolon,
-    Number(i64),
+    // statement separator, like ';'; only emitted outside parentheses
+    #[token("\n")]
+    Newline,
+    // produced by `layout`, never by logos: a line indented deeper than the
+    // previous one opens a block, and returning to an outer level closes it
+    Indent,
+    Dedent,
+    // unsigned and as wide as the widest type, `u64`; whether it fits the
+    // literal's type is up to the type checker
+    #[regex("[0-9]+", |lex| lex.slice().parse::<u64>().map_err(|_| LexError::IntegerOverflow))]
+    Number(u64),
+    // `1.5`, `2e10`, `1.5e-3`; a digit is required after the dot so `0..10` stays a range
+    // a literal too large for an f64 would round to infinity, so it is an error
+    #[regex(r"[0-9]+\.[0-9]+([eE][+-]?[0-9]+)?|[0-9]+[eE][+-]?[0-9]+", |lex| lex.slice().parse::<f64>().ok().filter(|x| x.is_finite()).ok_or(LexError::FloatOverflow))]
+    Float(f64),
+    #[regex("[A-Za-z_][A-Za-z0-9_]*", |lex| lex.slice().to_string())]
     Ident(String),
     Eof,
 }
 
-fn is_ident_start(c: char) -> bool { c.is_ascii_alphabetic() || c == '_' }
-fn is_ident_continue(c: char) -> bool { c.is_ascii_alphanumeric() || c == '_' }
+/// Reserved words. Adding a keyword is one entry here plus its `Token` variant.
+const KEYWORDS: &[(&str, Token)] = &[
+    ("if", Token::If),
+    ("else", Token::Else),
+    ("while", Token::While),
+    ("for", Token::For),
+    ("in", Token::In),
+    ("fn", Token::Fn),
+    ("return", Token::Return),
+    ("struct", Token::Struct),
+    ("and", Token::And),
+    ("or", Token::Or),
+    ("not", Token::Not),
+    ("true", Token::True),
+    ("false", Token::False),
+];
 
-pub fn lex(input: &str) -> Vec<Token> {
-    let mut tokens = Vec::new();
-    let mut chars = input.chars().peekable();
+fn keyword(s: &str) -> Option<Token> {
+    KEYWORDS.iter().find(|(kw, _)| *kw == s).map(|(_, tok)| tok.clone())
+}
 
-    while let Some(&c) = chars.peek() {
-        match c {
-            ' ' | '\t' | '\n' | '\r' => { chars.next(); }
-            '#' => {
-                while let Some(&ch) = chars.peek(){
-                    if ch == '\n' {
-                        break;
-                    } else {
-                        chars.next();
-                    }
-                    }
-                }
-            ';' => {chars.next(); tokens.push(Token::Semicolon);}
-            '=' => {
-                chars.next(); 
-                if let Some(&'=') = chars.peek() {
-                    chars.next();
-                    tokens.push(Token::EqComp);
-                } else {
-                    tokens.push(Token::Equals);
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::If => write!(f, "if"),
+            Token::Else => write!(f, "else"),
+            Token::While => write!(f, "while"),
+            Token::For => write!(f, "for"),
+            Token::In => write!(f, "in"),
+            Token::Fn => write!(f, "fn"),
+            Token::Return => write!(f, "return"),
+            Token::Struct => write!(f, "struct"),
+            Token::And => write!(f, "and"),
+            Token::Or => write!(f, "or"),
+            Token::Not => write!(f, "not"),
+            Token::True => write!(f, "true"),
+            Token::False => write!(f, "false"),
+            Token::Plus => write!(f, "+"),
+            Token::Minus => write!(f, "-"),
+            Token::Star => write!(f, "*"),
+            Token::Slash => write!(f, "/"),
+            Token::Percent => write!(f, "%"),
+            Token::Amp => write!(f, "&"),
+            Token::Pipe => write!(f, "|"),
+            Token::Caret => write!(f, "^"),
+            Token::Tilde => write!(f, "~"),
+            Token::Bang => write!(f, "!"),
+            Token::Shl => write!(f, "<<"),
+            Token::Shr => write!(f, ">>"),
+            Token::PlusEq => write!(f, "+="),
+            Token::MinusEq => write!(f, "-="),
+            Token::StarEq => write!(f, "*="),
+            Token::SlashEq => write!(f, "/="),
+            Token::PlusPlus => write!(f, "++"),
+            Token::MinusMinus => write!(f, "--"),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::LBracket => write!(f, "["),
+            Token::RBracket => write!(f, "]"),
+            Token::LBrace => write!(f, "{{"),
+            Token::RBrace => write!(f, "}}"),
+            Token::Comma => write!(f, ","),
+            Token::Equals => write!(f, "="),
+            Token::Gt => write!(f, ">"),
+            Token::Lt => write!(f, "<"),
+            Token::EqComp => write!(f, "=="),
+            Token::NotEq => write!(f, "!="),
+            Token::GtEq => write!(f, ">="),
+            Token::LtEq => write!(f, "<="),
+            Token::Colon => write!(f, ":"),
+            Token::DotDot => write!(f, ".."),
+            Token::Dot => write!(f, "."),
+            Token::Arrow => write!(f, "->"),
+            Token::Semicolon => write!(f, ";"),
+            Token::Newline => write!(f, "newline"),
+            Token::Indent => write!(f, "indent"),
+            Token::Dedent => write!(f, "dedent"),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Float(x) => write!(f, "{:?}", x),
+            Token::Ident(s) => write!(f, "{}", s),
+            Token::Eof => write!(f, "end of file"),
+        }
+    }
+}
+
+/// Lex the whole input. Lexing never stops early: every bad character or
+/// literal becomes a diagnostic and the remaining input is still tokenized,
+/// so later stages can report their own errors in the same run.
+///
+/// Newlines separate statements, so they are kept as tokens, except inside
+/// parentheses, brackets and braces (so call arguments, array literals and
+/// struct fields can span lines) and where they would be redundant (at the
+/// start of the file or right after another newline). A line starting with
+/// `fn` or `struct` cannot continue an expression, so it closes any brackets
+/// left open before it and the parser can pick up again from there.
+/// Leading whitespace is then turned into `Indent`/`Dedent` by `layout`, a
+/// tab counting as one column; one line's indentation may not mix the two.
+pub fn lex(input: &str) -> (Vec<Spanned<Token>>, Vec<Diagnostic>) {
+    let mut tokens: Vec<Spanned<Token>> = Vec::new();
+    let mut errors = Vec::new();
+    let mut paren_depth = 0usize;
+    // a newline dropped inside brackets, while no token has followed it yet
+    let mut dropped_newline = None;
+
+    for (result, range) in Token::lexer(input).spanned() {
+        let span = Span::new(range.start, range.end);
+        let after_newline = std::mem::take(&mut dropped_newline);
+        match result {
+            Ok(Token::Ident(s)) => {
+                let tok = keyword(&s).unwrap_or(Token::Ident(s));
+                if let (Some(newline), Token::Fn | Token::Struct) = (after_newline, &tok) {
+                    paren_depth = 0;
+                    tokens.push((Token::Newline, newline));
                 }
+                tokens.push((tok, span));
             }
-            ':' =>{ chars.next(); tokens.push(Token::Colon); }
-            '>' => {chars.next(); tokens.push(Token::Gt);}
-            '<' => {chars.next(); tokens.push(Token::Lt);}
-            '+' => { chars.next(); tokens.push(Token::Plus); }
-            '-' => { chars.next(); tokens.push(Token::Minus); }
-            '*' => { chars.next(); tokens.push(Token::Star); }
-            '/' => { chars.next(); tokens.push(Token::Slash); }
-            '(' => { chars.next(); tokens.push(Token::LParen); }
-            ')' => { chars.next(); tokens.push(Token::RParen); }
-            ',' => { chars.next(); tokens.push(Token::Comma); }
-            '0'..='9' => {
-                let mut num = 0i64;
-                while let Some(&d) = chars.peek() {
-                    if d.is_ascii_digit() {
-                        num = num * 10 + (d as i64 - '0' as i64);
-                        chars.next();
-                    } else { break; }
+            Ok(Token::Newline) => {
+                let redundant = matches!(tokens.last(), None | Some((Token::Newline, _)));
+                if paren_depth > 0 {
+                    dropped_newline = Some(span);
+                } else if !redundant {
+                    tokens.push((Token::Newline, span));
                 }
-                tokens.push(Token::Number(num));
             }
-            _ if is_ident_start(c) => {
-                let mut s = String::new();
-                while let Some(&ch) = chars.peek() {
-                    if is_ident_continue(ch) { s.push(ch); chars.next(); } else { break; }
-                }
-                if s== "if"{
-                    tokens.push(Token::If);
-                } else if s == "else" {
-                    tokens.push(Token::Else);
-                } else {
-                    tokens.push(Token::Ident(s));
-                }
+            Ok(tok @ (Token::LParen | Token::LBracket | Token::LBrace)) => {
+                paren_depth += 1;
+                tokens.push((tok, span));
+            }
+            Ok(tok @ (Token::RParen | Token::RBracket | Token::RBrace)) => {
+                paren_depth = paren_depth.saturating_sub(1);
+                tokens.push((tok, span));
+            }
+            Ok(tok) => tokens.push((tok, span)),
+            Err(LexError::UnknownChar) => {
+                let c = &input[range];
+                errors.push(
+                    Diagnostic::error(format!("unknown character `{}`", c.escape_debug()))
+                        .with_code(codes::UNKNOWN_CHARACTER)
+                        .with_primary(span, "not valid in Sprout source"),
+                );
+            }
+            Err(LexError::IntegerOverflow) => {
+                errors.push(
+                    Diagnostic::error("integer literal is too large")
+                        .with_code(codes::INTEGER_OVERFLOW)
+                        .with_primary(span, "does not fit in 64 bits")
+                        .with_note(format!("the largest integer literal is {}", u64::MAX)),
+                );
+                // keep a placeholder so the parser does not report a missing operand as well
+                tokens.push((Token::Number(0), span));
+            }
+            Err(LexError::FloatOverflow) => {
+                errors.push(
+                    Diagnostic::error("float literal is too large")
+                        .with_code(codes::FLOAT_OVERFLOW)
+                        .with_primary(span, "does not fit in an f64")
+                        .with_note(format!("the largest float literal is about {:e}", f64::MAX)),
+                );
+                tokens.push((Token::Float(0.0), span));
             }
-            _ => { chars.next(); }
         }
     }
+    let mut tokens = layout(tokens, input, &mut errors);
     println!("Lexer output tokens: {:?}", tokens);
-    tokens.push(Token::Eof);
-    tokens
+    tokens.push((Token::Eof, Span::new(input.len(), input.len())));
+    (tokens, errors)
+}
+
+// Width of the whitespace between the start of the line and `offset`.
+// Tabs and spaces both count as one column, so files should not mix them.
+fn indent_at(input: &str, offset: usize) -> usize {
+    let line_start = input[..offset].rfind('\n').map_or(0, |i| i + 1);
+    input[line_start..offset].chars().count()
+}
+
+// A tab counts as one column, like a space, so a prefix with both would line
+// up one way in an editor and another way here; it is an error instead of a
+// guess at the tab width.
+fn check_mixed_indent(input: &str, offset: usize, errors: &mut Vec<Diagnostic>) {
+    let line_start = input[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let prefix = &input[line_start..offset];
+    if prefix.contains(' ') && prefix.contains('\t') {
+        errors.push(
+            Diagnostic::error("indentation mixes tabs and spaces")
+                .with_code(codes::MIXED_INDENTATION)
+                .with_primary(Span::new(line_start, offset), "indent with only spaces or only tabs"),
+        );
+    }
+}
+
+// Python-style off-side rule over the raw token stream. For the first token
+// of every line, compare its indentation to the enclosing block's:
+//   deeper    -> the line's Newline becomes an Indent
+//   same      -> the Newline stays
+//   shallower -> one Dedent per closed block, placed before the Newline
+// so an indented body reads as `Indent stmts Dedent Newline`, i.e. a braced
+// block followed by a separator. Blocks still open at end of file are closed.
+fn layout(raw: Vec<Spanned<Token>>, input: &str, errors: &mut Vec<Diagnostic>) -> Vec<Spanned<Token>> {
+    let mut out: Vec<Spanned<Token>> = Vec::with_capacity(raw.len());
+    let mut levels: Vec<usize> = vec![0];
+
+    if let Some((_, span)) = raw.first()
+        && indent_at(input, span.start) > 0
+    {
+        errors.push(
+            Diagnostic::error("unexpected indentation")
+                .with_code(codes::INCONSISTENT_INDENT)
+                .with_primary(*span, "the first line of a file must not be indented"),
+        );
+    }
+
+    let mut iter = raw.into_iter().peekable();
+    while let Some((tok, span)) = iter.next() {
+        let next_start = match (&tok, iter.peek()) {
+            (Token::Newline, Some((_, next))) => next.start,
+            _ => {
+                out.push((tok, span));
+                continue;
+            }
+        };
+
+        check_mixed_indent(input, next_start, errors);
+        let indent = indent_at(input, next_start);
+        let current = *levels.last().unwrap();
+        if indent > current {
+            levels.push(indent);
+            out.push((Token::Indent, Span::new(next_start - (indent - current), next_start)));
+        } else {
+            while indent < *levels.last().unwrap() {
+                levels.pop();
+                out.push((Token::Dedent, Span::new(next_start, next_start)));
+            }
+            if indent != *levels.last().unwrap() {
+                errors.push(
+                    Diagnostic::error("unindent does not match any outer indentation level")
+                        .with_code(codes::INCONSISTENT_INDENT)
+                        .with_primary(Span::new(next_start, next_start), "this line is misaligned")
+                        .with_note(format!("expected an indentation of {} columns", levels.last().unwrap())),
+                );
+            }
+            out.push((tok, span));
+        }
+    }
+
+    // close any blocks still open at end of file, ahead of a trailing Newline
+    let trailing_newline = match out.last() {
+        Some((Token::Newline, _)) => out.pop(),
+        _ => None,
+    };
+    let end = Span::new(input.len(), input.len());
+    for _ in 1..levels.len() {
+        out.push((Token::Dedent, end));
+    }
+    out.extend(trailing_newline);
+    out
 }
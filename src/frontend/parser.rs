@@ -1,207 +1,533 @@
+use chumsky::{error::RichReason, input::ValueInput, prelude::*};
 use crate::frontend::lexer::Token;
-use crate::frontend::ast::{Expr, UnaryOp, BinaryOp};
+use crate::frontend::ast::{Expr, ExprKind, FieldDecl, FieldInit, Stmt, StmtKind, Param, TypeExpr, TypeExprKind, UnaryOp, BinaryOp};
+use crate::frontend::span::{Span, Spanned};
+use crate::diagnostic::{codes, Diagnostic};
 
-struct Parser {
-    tokens: Vec<Token>,
-    pos: usize,
-}
+type ParserError<'t> = extra::Err<Rich<'t, Token, Span>>;
 
-impl Parser {
-    fn new(tokens: Vec<Token>) -> Self { Self { tokens, pos: 0 } }
+// Precedence, loosest first:
+//   disjunction  or              (left assoc)
+//   conjunction  and             (left assoc)
+//   negation     not e
+//   comparison   == != < > <= >= (at most one; `a < b < c` is an error)
+//   bit or       |               (left assoc)
+//   bit xor      ^
+//   bit and      &
+//   shift        << >>
+//   sum          + -
+//   product      * / %
+//   unary        -e ~e !e
+//   postfix      e[i, j], e.field
+//   atom         number, bool, ident, call, struct literal, [a, b], (e), if, while, for
+//
+// Assignment is a statement, not an expression (see `program_parser`).
+// Expressions and statements are mutually recursive because `if` bodies are
+// statements, so both are declared up front and defined afterwards.
+// Blocks are delimited by the lexer's Indent/Dedent tokens, the way other
+// languages use braces.
+fn program_parser<'t, I>() -> impl Parser<'t, I, Vec<Stmt>, ParserError<'t>>
+where
+    I: ValueInput<'t, Token = Token, Span = Span>,
+{
+    let mut expr = Recursive::declare();
+    let mut stmt = Recursive::declare();
+    let mut block = Recursive::declare();
 
-    fn peek(&self) -> &Token {
-        &self.tokens[self.pos]
-    }
+    let ident = select! { Token::Ident(name) => name }.labelled("identifier");
+    let ident_expr = ident
+        .map_with(|name, e| Expr::new(ExprKind::Ident { name, sym: None }, e.span()));
+    let newline = just(Token::Newline).or_not();
 
-    fn next(&mut self) -> &Token {
-        if self.pos < self.tokens.len() - 1 {
-            self.pos += 1;
-        }
-        &self.tokens[self.pos]
-    }
+    let number = select! { Token::Number(n) => ExprKind::Number(n), Token::Float(x) => ExprKind::Float(x) }
+        .labelled("number")
+        .map_with(|kind, e| Expr::new(kind, e.span()));
 
-    fn parse_expression(&mut self) -> Result<Expr, String> {
-        self.parse_prec(0)
-    }
+    let boolean = select! { Token::True => ExprKind::Bool(true), Token::False => ExprKind::Bool(false) }
+        .labelled("boolean")
+        .map_with(|kind, e| Expr::new(kind, e.span()));
 
-    fn precedence(tok: &Token) -> Option<(u8, bool)> {
-        match tok {
-            Token::Plus => Some((1, true)),
-            Token::Minus => Some((1, true)),
-            Token::Star => Some((2, true)),
-            Token::Slash => Some((2, true)),
-
-            Token::Gt => Some((0, true)),
-            Token::Lt => Some((0, true)),
-            Token::EqComp => Some((0, true)),
-            _ => None,
-        }
-    }
+    let args = expr
+        .clone()
+        .separated_by(just(Token::Comma))
+        .collect::<Vec<_>>()
+        .delimited_by(just(Token::LParen), just(Token::RParen))
+        // broken arguments are skipped up to their `)`, so the call still parses
+        .recover_with(via_parser(nested_delimiters(
+            Token::LParen,
+            Token::RParen,
+            [(Token::LBracket, Token::RBracket), (Token::LBrace, Token::RBrace)],
+            |span| vec![Expr::new(ExprKind::Error, span)],
+        )))
+        .labelled("call arguments");
 
-    fn parse_prec(&mut self, min_prec: u8) -> Result<Expr, String> {
-        // prefix
-        let mut left = match self.peek() {
-            Token::If =>{
-                self.next();
-                let cond = self.parse_expression()?;
-                if let Token::Colon = self.peek(){
-                    self.next();
-                } else {
-                    return Err("Expected ':' after if condition".to_string());
-                }
-                let body = self.parse_expression()?;
-
-                // optional else / else if
-                let mut else_branch: Option<Box<Expr>> = None;
-                if let Token::Else = self.peek() {
-                    self.next();
-                    // else if: let the parser parse the following `if` expression as the else-branch
-                    if let Token::If = self.peek() {
-                        // parse the nested if expression (this will consume the `If` and its parts)
-                        let nested = self.parse_prec(0)?;
-                        else_branch = Some(Box::new(nested));
-                    } else {
-                        // plain else: expect ':' then parse body
-                        if let Token::Colon = self.peek() {
-                            self.next();
-                        } else {
-                            return Err("Expected ':' after else".to_string());
-                        }
-                        let else_body = self.parse_expression()?;
-                        else_branch = Some(Box::new(else_body));
-                    }
+    // `array(n, ...)` and `len(a)`/`len(a, d)` look like calls but are built in
+    let call = ident_expr
+        .then(args.map_with(|args, e| (args, e.span())))
+        .map(|(callee, (mut args, args_span))| {
+            let span = callee.span.merge(args_span);
+            let kind = match (&callee.kind, args.len()) {
+                (ExprKind::Ident { name, .. }, 1..) if name == "array" => ExprKind::NewArray { dims: args },
+                (ExprKind::Ident { name, .. }, 1 | 2) if name == "len" => {
+                    let dim = if args.len() == 2 { args.pop().map(Box::new) } else { None };
+                    ExprKind::Len { array: Box::new(args.remove(0)), dim }
                 }
+                _ => ExprKind::Call { callee: Box::new(callee), args },
+            };
+            Expr::new(kind, span)
+        });
 
-                Expr::If { cond: Box::new(cond), body: Box::new(body), else_branch }
-            }
-            Token::Minus => {
-                self.next();
-                let rhs = self.parse_prec(3)?;
-                Expr::Unary { op: UnaryOp::Neg, expr: Box::new(rhs) }
-            }
-            Token::Semicolon =>{
-                self.next();
-                self.parse_expression()?
-            }
-            Token::Number(n) => {
-                let v = *n;
-                self.next();
-                Expr::Number(v)
-            }
-            Token::Ident(s) => {
-                let name = s.clone();
-                self.next();
-                // call?
-                if let Token::LParen = self.peek() {
-                    self.next(); // consume '('
-                    let mut args = Vec::new();
-                    if let Token::RParen = self.peek() {
-                        self.next();
-                        Expr::Call { callee: Box::new(Expr::Ident(name)), args }
-                    } else {
-                        loop {
-                            let e = self.parse_expression()?;
-                            args.push(e);
-                            match self.peek() {
-                                Token::Comma => { self.next(); }
-                                Token::RParen => { self.next(); break; }
-                                t => return Err(format!("Unexpected token in call args: {:?}", t)),
-                            }
-                        }
-                        Expr::Call { callee: Box::new(Expr::Ident(name)), args }
-                    }
-                } else if let Token::Equals = self.peek(){
-                    println!("Ident parsed in else if: {}", name);
-                    self.next();
-                    let expr = self.parse_expression()?;
-                    println!("Assignment expression parsed: {}", expr);
-                    Expr::Binary{
-                        left: Box::new(Expr::Ident(name)),
-                        op: BinaryOp::Assign,
-                        right: Box::new(expr),}
-                }else {
-                    println!("Ident parsed in else: {}", name);
-                    Expr::Ident(name)
-                }
-            }
-            Token::LParen => {
-                self.next();
-                let e = self.parse_expression()?;
-                if let Token::RParen = self.peek() {
-                    self.next();
-                    e
-                } else {
-                    return Err("Expected ')'".to_string());
-                }
-            }
-            t => return Err(format!("Unexpected token: {:?}", t)),
-        };
-
-        // infix / precedence climbing
-        loop {
-            let op_tok = self.peek().clone();
-            if let Some((prec, left_assoc)) = Parser::precedence(&op_tok) {
-                if prec < min_prec { break; }
-                self.next(); // consume op
-                let next_min = if left_assoc { prec + 1 } else { prec };
-                let right = self.parse_prec(next_min)?;
-                let op = match op_tok {
-                    Token::Plus => BinaryOp::Add,
-                    Token::Minus => BinaryOp::Sub,
-                    Token::Star => BinaryOp::Mul,
-                    Token::Slash => BinaryOp::Div,
-
-                    Token::Gt => BinaryOp::Greater,
-                    Token::Lt => BinaryOp::Less,
-                    Token::EqComp => BinaryOp::Equal,
-                    _ => unreachable!(),
-                };
-                left = Expr::Binary {
-                    left: Box::new(left),
-                    op,
-                    right: Box::new(right),
-                };
-            } else {
-                break;
+    // `[a, b, c]`
+    let array = expr
+        .clone()
+        .separated_by(just(Token::Comma))
+        .allow_trailing()
+        .collect::<Vec<_>>()
+        .delimited_by(just(Token::LBracket), just(Token::RBracket))
+        .map_with(|elems, e| Expr::new(ExprKind::Array(elems), e.span()))
+        .recover_with(via_parser(nested_delimiters(
+            Token::LBracket,
+            Token::RBracket,
+            [(Token::LParen, Token::RParen), (Token::LBrace, Token::RBrace)],
+            |span| Expr::new(ExprKind::Error, span),
+        )))
+        .labelled("array");
+
+    // `Particle { x: 1.0, v: 0.0 }`
+    let field_init = ident
+        .map_with(|name, e| (name, e.span()))
+        .then_ignore(just(Token::Colon))
+        .then(expr.clone())
+        .map(|((name, span), value)| FieldInit { name, span, value, index: None });
+    let struct_lit = ident
+        .then(
+            field_init
+                .separated_by(just(Token::Comma))
+                .allow_trailing()
+                .collect::<Vec<_>>()
+                .delimited_by(just(Token::LBrace), just(Token::RBrace)),
+        )
+        .map_with(|(name, fields), e| Expr::new(ExprKind::StructLit { name, fields }, e.span()))
+        .labelled("struct literal");
+
+    // the `[i]` in `a[i]`, the `[i, j]` in `m[i, j]`, or the `.x` in `p.x`
+    let index_suffix = expr
+        .clone()
+        .separated_by(just(Token::Comma))
+        .at_least(1)
+        .collect::<Vec<_>>()
+        .delimited_by(just(Token::LBracket), just(Token::RBracket))
+        .recover_with(via_parser(nested_delimiters(
+            Token::LBracket,
+            Token::RBracket,
+            [(Token::LParen, Token::RParen), (Token::LBrace, Token::RBrace)],
+            |span| vec![Expr::new(ExprKind::Error, span)],
+        )))
+        .map(Suffix::Index)
+        .labelled("index");
+    let field_suffix = just(Token::Dot)
+        .ignore_then(ident)
+        .map(Suffix::Field)
+        .labelled("field");
+    let suffix = index_suffix
+        .or(field_suffix)
+        .map_with(|suffix, e| (suffix, e.span()))
+        .repeated();
+
+    // a body is either an indented block or a single statement, which may
+    // start on the line after its ':'
+    let body = block
+        .clone()
+        .map_with(|stmts, e| Stmt::new(StmtKind::Block(stmts), e.span()))
+        .or(newline.clone().ignore_then(stmt.clone()))
+        .map(Box::new);
+
+    // `if c: a else if d: b else: c` -- an `else if` is parsed as a nested if.
+    // The `else` may sit on the line after the then-body; if no `else`
+    // follows, that newline is left alone to end the statement.
+    let if_expr = recursive(|if_expr| {
+        just(Token::If)
+            .ignore_then(expr.clone())
+            .then_ignore(just(Token::Colon))
+            .then(body.clone())
+            .then(
+                newline
+                    .clone()
+                    .ignore_then(just(Token::Else))
+                    .ignore_then(
+                        if_expr
+                            .map_with(|e: Expr, x| Box::new(Stmt::new(StmtKind::Expr(e), x.span())))
+                            .or(just(Token::Colon).ignore_then(body.clone())),
+                    )
+                    .or_not(),
+            )
+            .map_with(|((cond, body), else_branch), e| {
+                Expr::new(ExprKind::If { cond: Box::new(cond), body, else_branch }, e.span())
+            })
+            .labelled("if expression")
+            .as_context()
+    });
+
+    // `while c: body`
+    let while_expr = just(Token::While)
+        .ignore_then(expr.clone())
+        .then_ignore(just(Token::Colon))
+        .then(body.clone())
+        .map_with(|(cond, body), e| Expr::new(ExprKind::While { cond: Box::new(cond), body }, e.span()))
+        .labelled("while loop")
+        .as_context();
+
+    // `for i in a..b: body`
+    let for_expr = just(Token::For)
+        .ignore_then(ident_expr)
+        .then_ignore(just(Token::In))
+        .then(expr.clone())
+        .then_ignore(just(Token::DotDot))
+        .then(expr.clone())
+        .then_ignore(just(Token::Colon))
+        .then(body.clone())
+        .map_with(|(((var, start), end), body), e| {
+            Expr::new(
+                ExprKind::For { var: Box::new(var), start: Box::new(start), end: Box::new(end), body },
+                e.span(),
+            )
+        })
+        .labelled("for loop")
+        .as_context();
+
+    let atom = number
+        .or(boolean)
+        .or(call)
+        .or(struct_lit)
+        .or(ident_expr)
+        .or(expr.clone().delimited_by(just(Token::LParen), just(Token::RParen)))
+        .or(array)
+        .or(if_expr)
+        .or(while_expr)
+        .or(for_expr)
+        // a broken parenthesised expression becomes an error node so parsing can go on after ')'
+        .recover_with(via_parser(nested_delimiters(
+            Token::LParen,
+            Token::RParen,
+            [(Token::LBracket, Token::RBracket), (Token::LBrace, Token::RBrace)],
+            |span| Expr::new(ExprKind::Error, span),
+        )))
+        .boxed();
+
+    let postfix = atom.foldl(suffix.clone(), apply_suffix);
+
+    // every level below uses the one above it twice, so each is boxed to
+    // keep the parser's type from doubling in size per level
+    let unary_op = just(Token::Minus)
+        .to(UnaryOp::Neg)
+        .or(just(Token::Tilde).to(UnaryOp::BitNot))
+        .or(just(Token::Bang).to(UnaryOp::Not));
+    let unary = unary_op
+        .repeated()
+        .foldr_with(postfix, |op, rhs, e| {
+            Expr::new(ExprKind::Unary { op, expr: Box::new(rhs) }, e.span())
+        })
+        .boxed();
+
+    let product_op = just(Token::Star)
+        .to(BinaryOp::Mul)
+        .or(just(Token::Slash).to(BinaryOp::Div))
+        .or(just(Token::Percent).to(BinaryOp::Rem));
+    let product = binary_level(unary, product_op);
+    let sum_op = just(Token::Plus)
+        .to(BinaryOp::Add)
+        .or(just(Token::Minus).to(BinaryOp::Sub));
+    let sum = binary_level(product, sum_op);
+    let shift_op = just(Token::Shl)
+        .to(BinaryOp::Shl)
+        .or(just(Token::Shr).to(BinaryOp::Shr));
+    let shift = binary_level(sum, shift_op);
+    let bit_and = binary_level(shift, just(Token::Amp).to(BinaryOp::BitAnd));
+    let bit_xor = binary_level(bit_and, just(Token::Caret).to(BinaryOp::BitXor));
+    let bit_or = binary_level(bit_xor, just(Token::Pipe).to(BinaryOp::BitOr));
+
+    // bitwise operators bind tighter than comparisons, so `a & b == 0` is
+    // `(a & b) == 0`. Comparisons do not chain: `a < b < c` is reported
+    // rather than quietly comparing the bool `a < b` with `c`.
+    let comparison_op = just(Token::Gt)
+        .to(BinaryOp::Greater)
+        .or(just(Token::Lt).to(BinaryOp::Less))
+        .or(just(Token::EqComp).to(BinaryOp::Equal))
+        .or(just(Token::NotEq).to(BinaryOp::NotEqual))
+        .or(just(Token::GtEq).to(BinaryOp::GreaterEq))
+        .or(just(Token::LtEq).to(BinaryOp::LessEq));
+    let comparison = bit_or
+        .clone()
+        .then(comparison_op.map_with(|op, e| (op, e.span())).then(bit_or).repeated().collect::<Vec<_>>())
+        .validate(|(first, rest), _, emitter| {
+            if let Some(((_, span), _)) = rest.get(1) {
+                emitter.emit(Rich::custom(*span, "comparison operators cannot be chained"));
             }
-        }
+            rest.into_iter().fold(first, |left, ((op, _), right)| binary(left, op, right))
+        })
+        .boxed();
 
-        Ok(left)
-    }
+    // `not` binds looser than comparisons, so `not a == b` is `not (a == b)`
+    let negation = just(Token::Not)
+        .repeated()
+        .foldr_with(comparison, |_, rhs, e| {
+            Expr::new(ExprKind::Unary { op: UnaryOp::Not, expr: Box::new(rhs) }, e.span())
+        })
+        .boxed();
+
+    let conjunction = binary_level(negation, just(Token::And).to(BinaryOp::And));
+    let disjunction = binary_level(conjunction, just(Token::Or).to(BinaryOp::Or));
+
+    expr.define(disjunction.labelled("expression"));
+
+    // a type name, `[T]` or `[T, N]` for `N` dimensions (not Rust's `[T; N]`,
+    // since `N` is a rank rather than a length); what a name means is decided
+    // by the type checker
+    let rank = just(Token::Comma).ignore_then(select! { Token::Number(n) => n }).or_not();
+    let type_expr = recursive(|type_expr| {
+        ident
+            .map(TypeExprKind::Named)
+            .or(type_expr
+                .then(rank)
+                .delimited_by(just(Token::LBracket), just(Token::RBracket))
+                .map(|(elem, rank)| TypeExprKind::Array { elem: Box::new(elem), rank: rank.unwrap_or(1) }))
+            .map_with(|kind, e| TypeExpr { kind, span: e.span() })
+            .labelled("type")
+    });
+    let annotation = just(Token::Colon).ignore_then(type_expr.clone()).or_not();
+
+    // An expression statement, or an assignment when the expression is
+    // followed by `= v`, `: T = v`, `op= v`, `++` or `--`. The target is parsed
+    // as any expression, so `f() = 1` reaches the resolver and gets a proper
+    // error there, and the expression is only parsed once either way.
+    let assign_op = just(Token::PlusEq)
+        .to(BinaryOp::Add)
+        .or(just(Token::MinusEq).to(BinaryOp::Sub))
+        .or(just(Token::StarEq).to(BinaryOp::Mul))
+        .or(just(Token::SlashEq).to(BinaryOp::Div));
+    let assign_tail = annotation
+        .clone()
+        .then_ignore(just(Token::Equals))
+        .then(expr.clone())
+        .map(|(ann, value)| AssignTail::Plain(ann, value))
+        .or(assign_op.then(expr.clone()).map(|(op, value)| AssignTail::Compound(op, Some(value))))
+        .or(just(Token::PlusPlus).to(AssignTail::Compound(BinaryOp::Add, None)))
+        .or(just(Token::MinusMinus).to(AssignTail::Compound(BinaryOp::Sub, None)));
+    let expr_or_assign = expr
+        .clone()
+        .then(assign_tail.or_not())
+        .map_with(|(target, tail), e| {
+            let kind = match tail {
+                None => StmtKind::Expr(target),
+                Some(AssignTail::Plain(ann, value)) => StmtKind::Assign { target, ann, value },
+                Some(AssignTail::Compound(op, value)) => StmtKind::CompoundAssign { target, op, value },
+            };
+            Stmt::new(kind, e.span())
+        });
+
+    // `fn name(a, b: T) -> T: body`
+    let param = ident
+        .map_with(|name, e| (name, e.span()))
+        .then(annotation)
+        .map(|((name, span), ann)| Param { name, span, sym: None, ann, ty: None });
+    let fn_def = just(Token::Fn)
+        .ignore_then(ident)
+        .then(
+            param
+                .separated_by(just(Token::Comma))
+                .collect::<Vec<_>>()
+                .delimited_by(just(Token::LParen), just(Token::RParen))
+                // a broken parameter list is skipped up to its `)`, so the
+                // body is still parsed and checked
+                .recover_with(via_parser(nested_delimiters(
+                    Token::LParen,
+                    Token::RParen,
+                    [(Token::LBracket, Token::RBracket), (Token::LBrace, Token::RBrace)],
+                    |_| Vec::new(),
+                )))
+                .labelled("parameter list"),
+        )
+        .then(just(Token::Arrow).ignore_then(type_expr.clone()).or_not())
+        .then_ignore(just(Token::Colon))
+        .then(body.clone())
+        .map_with(|(((name, params), ret), body), e| {
+            Stmt::new(StmtKind::Fn { name, params, ret, ret_ty: None, body }, e.span())
+        })
+        .labelled("function definition")
+        .as_context();
+
+    // `struct Name { a: T, b: T }`
+    let field_decl = ident
+        .map_with(|name, e| (name, e.span()))
+        .then_ignore(just(Token::Colon))
+        .then(type_expr)
+        .map(|((name, span), ann)| FieldDecl { name, span, ann, ty: None });
+    let struct_def = just(Token::Struct)
+        .ignore_then(ident)
+        .then(
+            field_decl
+                .separated_by(just(Token::Comma))
+                .allow_trailing()
+                .collect::<Vec<_>>()
+                .delimited_by(just(Token::LBrace), just(Token::RBrace))
+                .labelled("field list"),
+        )
+        .map_with(|(name, fields), e| Stmt::new(StmtKind::Struct { name, fields }, e.span()))
+        .labelled("struct definition")
+        .as_context();
+
+    let return_stmt = just(Token::Return)
+        .ignore_then(expr.clone())
+        .map_with(|value, e| Stmt::new(StmtKind::Return(value), e.span()));
+
+    stmt.define(
+        fn_def
+            .or(struct_def)
+            .or(return_stmt)
+            .or(expr_or_assign),
+    );
+
+    // Statements are separated by ';' or newlines; the last one may omit its
+    // separator (at the end of the file or its block), so a trailing
+    // expression is kept. If a statement fails to parse, everything up to the
+    // next separator is skipped and replaced by an error node, so one run
+    // reports every broken statement and still yields the good ones. Indented
+    // blocks inside the broken statement are skipped whole so the Indent and
+    // Dedent tokens stay balanced.
+    let separator = one_of([Token::Semicolon, Token::Newline]).repeated().at_least(1);
+    let terminator = separator
+        .clone()
+        .ignored()
+        .or(end())
+        .or(just(Token::Dedent).rewind().ignored());
+
+    let skipped_block = recursive(|skipped_block| {
+        just(Token::Indent)
+            .then(skipped_block.or(none_of([Token::Indent, Token::Dedent]).ignored()).repeated())
+            .then(just(Token::Dedent))
+            .ignored()
+    });
+    let skip_statement = skipped_block
+        .or(none_of([Token::Semicolon, Token::Newline, Token::Indent, Token::Dedent]).ignored())
+        .repeated()
+        .at_least(1)
+        .then(terminator.clone())
+        .map_with(|_, e| Stmt::new(StmtKind::Expr(Expr::new(ExprKind::Error, e.span())), e.span()));
+
+    let statement = stmt
+        .then_ignore(terminator)
+        .recover_with(via_parser(skip_statement));
+    let statements = separator.or_not().ignore_then(statement.repeated().collect::<Vec<_>>());
+
+    block.define(
+        statements
+            .clone()
+            .delimited_by(just(Token::Indent), just(Token::Dedent))
+            .labelled("indented block"),
+    );
+
+    statements.then_ignore(end())
 }
 
-fn parse_statement(tokens: Vec<Token>) -> Result<Vec<Vec<Token>>, String> {
-        let mut statements: Vec<Vec<Token>> = Vec::new();
-        let mut tmp = Vec::new();
-        for stmt in tokens{
-            if stmt == Token::Semicolon{
-                statements.push(tmp);
-                tmp = Vec::new();
-            } else{
-                tmp.push(stmt);
-            }
-        };
-        Ok(statements)
-    }
+// One left-associative precedence level, `prev (op prev)*`, boxed like the
+// levels around it.
+fn binary_level<'t, I>(
+    prev: Boxed<'t, 't, I, Expr, ParserError<'t>>,
+    op: impl Parser<'t, I, BinaryOp, ParserError<'t>> + 't,
+) -> Boxed<'t, 't, I, Expr, ParserError<'t>>
+where
+    I: ValueInput<'t, Token = Token, Span = Span>,
+{
+    prev.clone()
+        .foldl(op.then(prev).repeated(), |left, (op, right)| binary(left, op, right))
+        .boxed()
+}
+
+// `left op right`, spanning both operands
+fn binary(left: Expr, op: BinaryOp, right: Expr) -> Expr {
+    let span = left.span.merge(right.span);
+    Expr::new(ExprKind::Binary { left: Box::new(left), op, right: Box::new(right) }, span)
+}
+
+// What can follow an expression, see `apply_suffix`.
+#[derive(Clone)]
+enum Suffix {
+    Index(Vec<Expr>),
+    Field(String),
+}
+
+// What can follow the target of an assignment: `= v` (maybe annotated), or
+// `op= v`, `++` or `--`.
+#[derive(Clone)]
+enum AssignTail {
+    Plain(Option<TypeExpr>, Expr),
+    Compound(BinaryOp, Option<Expr>),
+}
 
-// public entry:
-pub fn parse_tokens(tokens: Vec<Token>) -> Result<Vec<Expr>, String> {
-    let p_stat = parse_statement(tokens)?;
-    let mut p = Vec::new();
+// `base[indices]` or `base.field`, where `suffix_span` covers the suffix
+fn apply_suffix(base: Expr, (suffix, suffix_span): (Suffix, Span)) -> Expr {
+    let span = base.span.merge(suffix_span);
+    let kind = match suffix {
+        Suffix::Index(indices) => ExprKind::Index { array: Box::new(base), indices },
+        Suffix::Field(name) => ExprKind::Field { base: Box::new(base), name, index: None },
+    };
+    Expr::new(kind, span)
+}
 
-    for stmt in p_stat.iter(){
-        let mut parse = Parser::new(stmt.clone());
-        p.push(parse.parse_expression()?);
+fn to_diagnostic(err: &Rich<'_, Token, Span>) -> Diagnostic {
+    // the only custom error, from the comparison parser
+    if let RichReason::Custom(msg) = err.reason() {
+        return Diagnostic::error(msg.clone())
+            .with_code(codes::CHAINED_COMPARISON)
+            .with_primary(*err.span(), "a second comparison")
+            .with_note("write `a < b and b < c` to test both, or add parentheses to compare the bool result");
     }
+    let found = match err.found() {
+        Some(Token::Newline) => "unexpected end of line".to_string(),
+        Some(Token::Indent) => "unexpected indentation".to_string(),
+        Some(Token::Dedent) => "unexpected end of block".to_string(),
+        Some(tok) => format!("unexpected token `{}`", tok),
+        None => "unexpected end of file".to_string(),
+    };
+    let expected: Vec<String> = err.expected().map(|p| p.to_string()).collect();
+    let label = match expected.len() {
+        0 => "unexpected here".to_string(),
+        1 => format!("expected {}", expected[0]),
+        _ => format!("expected one of {}", expected.join(", ")),
+    };
+    // a single expected token (a missing ':' or ')') gets its own code
+    let code = if expected.len() == 1 { codes::EXPECTED_TOKEN } else { codes::UNEXPECTED_TOKEN };
+    let mut diag = Diagnostic::error(found)
+        .with_code(code)
+        .with_primary(*err.span(), label);
+    for (context, span) in err.contexts() {
+        diag = diag.with_label(*span, format!("while parsing this {}", context));
+    }
+    diag
+}
+
+// public entry: always returns whatever AST could be recovered (statements
+// that failed to parse show up as `ExprKind::Error`) alongside every syntax error.
+pub fn parse_tokens(mut tokens: Vec<Spanned<Token>>) -> (Vec<Stmt>, Vec<Diagnostic>) {
+    // the lexer terminates the stream with Eof; chumsky tracks end of input itself
+    let eoi = match tokens.pop() {
+        Some((Token::Eof, span)) => span,
+        Some(other) => {
+            let end = other.1.end;
+            tokens.push(other);
+            Span::new(end, end)
+        }
+        None => Span::default(),
+    };
+
+    let (output, errors) = program_parser()
+        .parse(tokens.as_slice().map(eoi, |(t, s)| (t, s)))
+        .into_output_errors();
 
-    //let expr = p_stat.parse_expression()?;
-    for expr in p.iter(){
-        println!("Parsed expression: {}", expr);
+    let p = output.unwrap_or_default();
+    for stmt in p.iter(){
+        println!("Parsed statement: {}", stmt);
     }
 
-    Ok(p)
-    /*match p.peek() {
-        Token::Eof => Ok(Vec<Expr>),
-        t => Err(format!("Unexpected trailing token: {:?}", t)),
-    }*/
+    (p, errors.iter().map(to_diagnostic).collect())
 }
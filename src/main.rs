@@ -1,9 +1,14 @@
+mod diagnostic;
 mod frontend;
+mod sema;
 mod middle;
 mod backend;
 
 use frontend::{lexer, parser};
+use frontend::span::SourceFile;
 use middle::lower;
+use backend::interp;
+#[cfg(feature = "llvm")]
 use backend::llvm;
 
 //read sprout files
@@ -11,7 +16,16 @@ use std::fs;
 
 
 fn main() {
+    #[cfg(feature = "llvm")]
     backend::llvm::init_llvm();
+    // `--checked` makes integer overflow abort the program instead of wrapping
+    let overflow = if std::env::args().any(|a| a == "--checked") {
+        middle::ir::Overflow::Trap
+    } else {
+        middle::ir::Overflow::Wrap
+    };
+    // `--no-bounds-checks` drops the index checks on array accesses
+    let bounds_checks = !std::env::args().any(|a| a == "--no-bounds-checks");
     let file_path = "tests";
     let paths = fs::read_dir(file_path).unwrap();
 
@@ -23,37 +37,108 @@ fn main() {
 
         let contents = fs::read_to_string(&path);
 
-        tests.push(contents);
+        tests.push((path.display().to_string(), contents));
     }
 
     println!("{:?}", tests);
     
-     for t in tests{
+     for (name, t) in tests{
         let t = t.expect("Error reading test file");
+        let file = SourceFile::new(name.clone(), t.clone());
         
         println!("=== {t} ===");
 
-        let tokens = lexer::lex(&t);
-
-        match parser::parse_tokens(tokens) {
-            Ok(expr) => {
-                for exp in expr.iter(){
-                    println!("AST: {exp}");
+        // `.ir` files are hand-written IR in the textual form (`middle::text`),
+        // settings included, and skip the frontend
+        let mut ir_module = if name.ends_with(".ir") {
+            // unlike lowered IR, a hand-written module is checked in every build
+            match middle::text::parse_module(&t).map_err(|e| vec![*e]).and_then(|(m, map)| middle::ir::verify_parsed(&m, &map).map(|()| m)) {
+                Ok(m) => m,
+                Err(errors) => {
+                    for e in &errors {
+                        eprint!("{}", e.render(&file));
+                    }
+                    continue;
+                }
+            }
+        } else {
+            match lower_source(&file) {
+                Some(mut m) => {
+                    m.overflow = overflow;
+                    m.bounds_checks = bounds_checks;
+                    m
                 }
+                None => continue,
+            }
+        };
 
-                let mut ir_module = lower::lower_program_to_module(&expr);
+        //optimize module
+        middle::opt::optimize_module(&mut ir_module);
 
-                //optimize module
-                middle::opt::optimize_module(&mut ir_module);
+        println!("IR:\n{ir_module}");
 
-                println!("IR: {:#?}", ir_module);
+        // the interpreter needs no LLVM, and reports a trap as an error where
+        // the JIT-compiled program would abort the whole run
+        let expected = match interp::run_main(&ir_module) {
+            Ok(result) => {
+                println!("Result from interpreter: {result}");
+                result
+            }
+            Err(e) => {
+                eprint!("{}", e.render(&file));
+                continue;
+            }
+        };
 
-                match llvm::jit_run_main(&ir_module) {
-                    Ok(result) => println!("Result from JIT: {result}"),
-                    Err(e) => eprintln!("Codegen/JIT error: {e}"),
+        // the interpreter is the reference: the JIT must agree with it
+        #[cfg(feature = "llvm")]
+        match llvm::jit_run_main(&ir_module) {
+            Ok(result) => {
+                println!("Result from JIT: {result}");
+                if result != expected {
+                    eprintln!("error: the JIT returned {result}, but the interpreter returned {expected}");
                 }
             }
-            Err(e) => eprintln!("Parse error for '{t}': {e}"),
+            Err(e) => eprint!("{}", e.render(&file)),
+        }
+        #[cfg(not(feature = "llvm"))]
+        let _ = expected;
+    }
+}
+
+// Lexes, parses, checks and lowers one Sprout source file, printing any
+// errors.
+fn lower_source(file: &SourceFile) -> Option<middle::ir::Module> {
+    // the parser runs even after lex errors, on the placeholder tokens the
+    // lexer leaves in their place, so both kinds are reported together
+    let (tokens, mut errors) = lexer::lex(&file.text);
+    let (mut stmts, parse_errors) = parser::parse_tokens(tokens);
+    errors.extend(parse_errors);
+    if !errors.is_empty() {
+        errors.sort_by_key(|d| d.primary.as_ref().map(|l| l.span.start));
+        for e in &errors {
+            eprint!("{}", e.render(file));
+        }
+        return None;
+    }
+    for stmt in stmts.iter(){
+        println!("AST: {stmt}");
+    }
+
+    let checked = sema::resolve::resolve_program(&mut stmts)
+        .and_then(|symbols| sema::typeck::check_program(&mut stmts, &symbols));
+    if let Err(errors) = checked {
+        for e in &errors {
+            eprint!("{}", e.render(file));
+        }
+        return None;
+    }
+
+    match lower::lower_program_to_module(&stmts) {
+        Ok(m) => Some(m),
+        Err(e) => {
+            eprint!("{}", e.render(file));
+            None
         }
     }
 }
@@ -0,0 +1,184 @@
+use crate::middle::ir::{BlockId, Function};
+
+// Block `a` dominates block `b` when every path from the entry to `b` goes
+// through `a`; a block dominates itself. Only covers reachable blocks.
+pub struct Dominators {
+    // immediate dominator of each block; the entry's is itself and an
+    // unreachable block has none
+    idom: Vec<Option<BlockId>>,
+}
+
+// The control-flow graph of a function: which blocks can follow which.
+// Computed from the terminators, so it has to be rebuilt after they change.
+pub struct Cfg {
+    succs: Vec<Vec<BlockId>>,
+    preds: Vec<Vec<BlockId>>,
+}
+
+impl Cfg {
+    pub fn new(func: &Function) -> Self {
+        let mut succs = vec![Vec::new(); func.blocks.len()];
+        let mut preds = vec![Vec::new(); func.blocks.len()];
+        for (i, block) in func.blocks.iter().enumerate() {
+            let from = BlockId::from_usize(i);
+            let Some(term) = block.insts.last() else { continue };
+            for (to, _) in term.edges() {
+                // a `CondBr` to one block on both sides is still one edge
+                if !succs[i].contains(&to) {
+                    succs[i].push(to);
+                    preds[to.get_usize()].push(from);
+                }
+            }
+        }
+        Cfg { succs, preds }
+    }
+
+    pub fn successors(&self, block: BlockId) -> &[BlockId] {
+        &self.succs[block.get_usize()]
+    }
+
+    pub fn predecessors(&self, block: BlockId) -> &[BlockId] {
+        &self.preds[block.get_usize()]
+    }
+
+    // The blocks reachable from the entry, each one after all of its
+    // predecessors except those reaching it through a loop's back edge.
+    // Unreachable blocks are left out.
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let entry = BlockId::from_usize(0);
+        let mut visited = vec![false; self.succs.len()];
+        let mut postorder = Vec::new();
+        // explicit stack of (block, next successor to visit), so deep
+        // nesting cannot overflow the native stack
+        let mut stack = vec![(entry, 0)];
+        visited[0] = true;
+        while let Some((block, next)) = stack.pop() {
+            match self.successors(block).get(next) {
+                Some(&succ) => {
+                    stack.push((block, next + 1));
+                    if !visited[succ.get_usize()] {
+                        visited[succ.get_usize()] = true;
+                        stack.push((succ, 0));
+                    }
+                }
+                None => postorder.push(block),
+            }
+        }
+        postorder.reverse();
+        postorder
+    }
+}
+
+impl Dominators {
+    // The iterative algorithm of Cooper, Harvey and Kennedy ("A Simple, Fast
+    // Dominance Algorithm"): walk the blocks in reverse postorder, setting
+    // each one's immediate dominator to the nearest common dominator of its
+    // processed predecessors, until nothing changes.
+    pub fn new(cfg: &Cfg) -> Self {
+        let order = cfg.reverse_postorder();
+        let mut rpo_index = vec![usize::MAX; cfg.succs.len()];
+        for (i, b) in order.iter().enumerate() {
+            rpo_index[b.get_usize()] = i;
+        }
+        let mut idom: Vec<Option<BlockId>> = vec![None; cfg.succs.len()];
+        let entry = order[0];
+        idom[entry.get_usize()] = Some(entry);
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in &order[1..] {
+                let mut new_idom: Option<BlockId> = None;
+                for &p in cfg.predecessors(b) {
+                    if idom[p.get_usize()].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(other) => intersect(&idom, &rpo_index, p, other),
+                    });
+                }
+                if new_idom.is_some() && idom[b.get_usize()] != new_idom {
+                    idom[b.get_usize()] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        Dominators { idom }
+    }
+
+    // None for the entry and for unreachable blocks.
+    pub fn idom(&self, block: BlockId) -> Option<BlockId> {
+        self.idom[block.get_usize()].filter(|&d| d != block)
+    }
+
+    pub fn is_reachable(&self, block: BlockId) -> bool {
+        self.idom[block.get_usize()].is_some()
+    }
+
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        if !self.is_reachable(a) || !self.is_reachable(b) {
+            return false;
+        }
+        let mut b = b;
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom(b) {
+                Some(up) => b = up,
+                None => return false,
+            }
+        }
+    }
+
+    // The dominator tree: the blocks each block immediately dominates.
+    pub fn children(&self) -> Vec<Vec<BlockId>> {
+        let mut children = vec![Vec::new(); self.idom.len()];
+        for i in 0..self.idom.len() {
+            let b = BlockId::from_usize(i);
+            if let Some(parent) = self.idom(b) {
+                children[parent.get_usize()].push(b);
+            }
+        }
+        children
+    }
+
+    // The dominance frontier of each block: the blocks where its dominance
+    // ends, so where a value defined in it meets values from other paths.
+    pub fn frontiers(&self, cfg: &Cfg) -> Vec<Vec<BlockId>> {
+        let mut frontiers = vec![Vec::new(); self.idom.len()];
+        for i in 0..self.idom.len() {
+            let b = BlockId::from_usize(i);
+            let preds = cfg.predecessors(b);
+            if preds.len() < 2 || !self.is_reachable(b) {
+                continue;
+            }
+            let idom = self.idom[i].expect("reachable");
+            for &p in preds {
+                let mut runner = p;
+                while self.is_reachable(runner) && runner != idom {
+                    if !frontiers[runner.get_usize()].contains(&b) {
+                        frontiers[runner.get_usize()].push(b);
+                    }
+                    runner = self.idom[runner.get_usize()].expect("reachable");
+                }
+            }
+        }
+        frontiers
+    }
+}
+
+// The nearest common dominator of `a` and `b`, climbing whichever comes
+// later in reverse postorder.
+fn intersect(idom: &[Option<BlockId>], rpo_index: &[usize], mut a: BlockId, mut b: BlockId) -> BlockId {
+    while a != b {
+        while rpo_index[a.get_usize()] > rpo_index[b.get_usize()] {
+            a = idom[a.get_usize()].expect("processed");
+        }
+        while rpo_index[b.get_usize()] > rpo_index[a.get_usize()] {
+            b = idom[b.get_usize()].expect("processed");
+        }
+    }
+    a
+}
@@ -1,46 +1,272 @@
+use std::collections::HashMap;
+use crate::frontend::ast::Type;
+use crate::frontend::span::Span;
+use crate::middle::cfg::Cfg;
+// checks the invariants described on the types below
+pub use crate::middle::verify::{debug_verify, verify_parsed};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ValueId(u32);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockId(u32);
+
 //produce functions
 #[derive(Debug)]
 pub struct Function{
     pub name: String,
-    pub body: Vec<Inst>,
-    next_value: u32 // generate new value id's
+    // each parameter is a named variable slot inside the body. The entry
+    // block has one parameter per function parameter holding the argument,
+    // which the body stores to the slot first
+    pub params: Vec<(String, Type)>,
+    pub ret: Type,
+    pub blocks: Vec<Block>, // indexed by BlockId; the first one is the entry
+    // the `for` loops lowered into this function, or given by `loop` lines
+    // in the text format
+    pub loops: Vec<CountedLoop>,
+    types: Vec<Type>, // type of every value, indexed by ValueId; also hands out new ids
+    spans: HashMap<ValueId, Span>, // source location each value was lowered from
 }
 
 #[derive(Debug)]
 pub struct Module{
     pub functions: Vec<Function>,
+    pub structs: Vec<StructDef>,
+    pub overflow: Overflow,
+    // whether `CheckIndex` does anything
+    pub bounds_checks: bool,
+}
+
+// A struct type, with its fields in declaration order. Values of type
+// `Type::Struct(name)` are laid out as the fields, one after the other.
+#[derive(Debug, Clone)]
+pub struct StructDef {
+    pub name: String,
+    pub fields: Vec<(String, Type)>,
+}
+
+// A basic block: straight-line instructions ending in exactly one
+// terminator (`Br`, `CondBr`, `Return` or `Unreachable`). Values merge where
+// control flow joins through block parameters rather than phi nodes: every
+// branch to the block passes one argument per parameter.
+#[derive(Debug, Clone, Default)]
+pub struct Block {
+    pub params: Vec<ValueId>,
+    pub insts: Vec<Inst>,
+}
+
+// A `for i in start..end` loop as lowered: the header takes the induction
+// variable `counter` as a block parameter, is entered with `start`, and
+// branches to the body while `counter < end`, which then passes `counter + 1`
+// back. Blocks alone do not say which of them form a loop, so lowering
+// records it here for later passes; mem2reg and folding keep it up to date.
+#[derive(Debug, Clone)]
+pub struct CountedLoop {
+    pub header: BlockId,
+    pub counter: ValueId,
+    pub start: ValueId,
+    pub end: ValueId,
+}
+
+// What integer Add/Sub/Mul/Div do when the result does not fit the type.
+// The constant folder and the backend both follow this, so folding never
+// changes a program's result. Division by zero always traps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    // two's complement wrap-around; `MIN / -1` gives `MIN`
+    Wrap,
+    // abort the program (checked mode)
+    Trap,
 }
 
 //defines simple instruction set
 #[derive(Debug, Clone)]
 pub enum Inst { 
+    // `value` is the constant itself, in range for `dst`'s type; only a u64
+    // above i64::MAX is stored as its bit pattern (and so looks negative)
     Const {dst: ValueId, value: i64},
     Boolean {dst: ValueId, value: bool},
+    // `value` is rounded to f32 when `dst` is an f32
+    FConst {dst: ValueId, value: f64},
+    // converts `src` to the type of `dst`: bool -> int, int -> wider int
+    // (sign- or zero-extending by the source's signedness), or float -> int
+    // rounding toward zero and saturating at the int's range, NaN giving 0
+    Cast {dst: ValueId, src: ValueId},
     Add {dst: ValueId, lhs: ValueId, rhs: ValueId},
     Sub {dst: ValueId, lhs: ValueId, rhs: ValueId},
     Mul {dst: ValueId, lhs: ValueId, rhs: ValueId},
     Div {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    // the remainder of `Div`, with the sign of `lhs`; `MIN % -1` is 0 and a
+    // zero `rhs` traps
+    Rem {dst: ValueId, lhs: ValueId, rhs: ValueId},
     Greater {dst: ValueId, lhs: ValueId, rhs: ValueId},
     Less {dst: ValueId, lhs: ValueId, rhs: ValueId},
     Equal {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    GreaterEq {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    LessEq {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    NotEqual {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    BitAnd {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    BitOr {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    BitXor {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    BitNot {dst: ValueId, src: ValueId},
+    // shifts by `rhs` bits; `Shr` is arithmetic for signed types and logical
+    // for unsigned ones. An amount outside `0..bits` is taken modulo the bit
+    // width under `Overflow::Wrap` and traps under `Overflow::Trap`
+    Shl {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    Shr {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    // logical negation of a bool. `and` and `or` have no instruction of their
+    // own: they are lowered to a `CondBr` so the right side can be skipped
+    Not {dst: ValueId, src: ValueId},
+    // IEEE float arithmetic on f32 or f64; comparisons with NaN are false
+    FAdd {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    FSub {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    FMul {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    FDiv {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    // like C's `fmod`: the result has the sign of `lhs`
+    FRem {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    FNeg {dst: ValueId, src: ValueId},
+    FGreater {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    FLess {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    FEqual {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    FGreaterEq {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    FLessEq {dst: ValueId, lhs: ValueId, rhs: ValueId},
+    // the one float comparison that is true when an operand is NaN
+    FNotEqual {dst: ValueId, lhs: ValueId, rhs: ValueId},
     Call  { dst: ValueId, callee: String, args: Vec<ValueId> },
+    // a new zeroed array typed by `dst`, with one extent per dimension; a
+    // negative extent, or a size that does not fit in memory, traps
+    ArrayNew {dst: ValueId, dims: Vec<ValueId>},
+    // the extent of dimension `dim`
+    ArrayLen {dst: ValueId, array: ValueId, dim: u32},
+    // traps unless `0 <= index < len`; does nothing when the module has
+    // bounds checks off
+    CheckIndex {index: ValueId, len: ValueId},
+    // `dst` (a `ptr`) is the address of the element at row-major position
+    // `index` among all of the array's elements. It is not checked: lowering
+    // computes `index` from the per-dimension indices after `CheckIndex`ing them
+    ElemAddr {dst: ValueId, array: ValueId, index: ValueId},
+    // read or write the element at an `ElemAddr` address
+    LoadElem {dst: ValueId, addr: ValueId},
+    StoreElem {addr: ValueId, src: ValueId},
+    // a struct value built from one value per field, in declaration order
+    StructNew {dst: ValueId, fields: Vec<ValueId>},
+    // `dst` is field number `field` of the struct `src`
+    ExtractField {dst: ValueId, src: ValueId, field: u32},
+    // `dst` is a copy of the struct `src` with field number `field` replaced
+    // by `value`; structs are values, so `src` itself is unchanged
+    InsertField {dst: ValueId, src: ValueId, field: u32, value: ValueId},
     Load {dst: ValueId, name: String},
     Store {name: String, src: ValueId},
-    Conditional {cond: ValueId, body: Vec<Inst>, else_insts: Vec<Inst>, dst: ValueId },
+    // The terminators. A branch passes `args` to the target block's
+    // parameters, one each.
+    Br {target: BlockId, args: Vec<ValueId>},
+    // to `then_block` when `cond` is true, to `else_block` otherwise
+    CondBr {cond: ValueId, then_block: BlockId, then_args: Vec<ValueId>, else_block: BlockId, else_args: Vec<ValueId>},
     Return {src: ValueId},
+    // ends a block control never reaches, like the code after a `return`
+    Unreachable,
 }
 
-//create and getter for ValueId
-impl ValueId{
-    pub fn new(id: u32) -> Self {
-        ValueId(id)
+impl Inst {
+    pub fn is_terminator(&self) -> bool {
+        matches!(self, Inst::Br { .. } | Inst::CondBr { .. } | Inst::Return { .. } | Inst::Unreachable)
     }
 
-    pub fn id(&self) -> u32 {
-        self.0
+    // The value the instruction defines, if any.
+    pub fn dst(&self) -> Option<ValueId> {
+        match self {
+            Inst::Const { dst, .. } | Inst::Boolean { dst, .. } | Inst::FConst { dst, .. } | Inst::Cast { dst, .. }
+            | Inst::Add { dst, .. } | Inst::Sub { dst, .. } | Inst::Mul { dst, .. } | Inst::Div { dst, .. }
+            | Inst::Rem { dst, .. } | Inst::Greater { dst, .. } | Inst::Less { dst, .. } | Inst::Equal { dst, .. }
+            | Inst::GreaterEq { dst, .. } | Inst::LessEq { dst, .. } | Inst::NotEqual { dst, .. }
+            | Inst::BitAnd { dst, .. } | Inst::BitOr { dst, .. } | Inst::BitXor { dst, .. } | Inst::BitNot { dst, .. }
+            | Inst::Shl { dst, .. } | Inst::Shr { dst, .. } | Inst::Not { dst, .. } | Inst::FAdd { dst, .. }
+            | Inst::FSub { dst, .. } | Inst::FMul { dst, .. } | Inst::FDiv { dst, .. } | Inst::FRem { dst, .. }
+            | Inst::FNeg { dst, .. } | Inst::FGreater { dst, .. } | Inst::FLess { dst, .. } | Inst::FEqual { dst, .. }
+            | Inst::FGreaterEq { dst, .. } | Inst::FLessEq { dst, .. } | Inst::FNotEqual { dst, .. }
+            | Inst::Call { dst, .. } | Inst::ArrayNew { dst, .. } | Inst::ArrayLen { dst, .. }
+            | Inst::ElemAddr { dst, .. } | Inst::LoadElem { dst, .. } | Inst::StructNew { dst, .. }
+            | Inst::ExtractField { dst, .. } | Inst::InsertField { dst, .. } | Inst::Load { dst, .. } => Some(*dst),
+            Inst::CheckIndex { .. } | Inst::StoreElem { .. } | Inst::Store { .. } | Inst::Br { .. }
+            | Inst::CondBr { .. } | Inst::Return { .. } | Inst::Unreachable => None,
+        }
+    }
+
+    // Every value the instruction reads, in the order `operands_mut` gives.
+    pub fn operands(&self) -> Vec<ValueId> {
+        match self {
+            Inst::Const { .. } | Inst::Boolean { .. } | Inst::FConst { .. } | Inst::Load { .. } | Inst::Unreachable => Vec::new(),
+            Inst::Cast { src, .. } | Inst::BitNot { src, .. } | Inst::Not { src, .. } | Inst::FNeg { src, .. }
+            | Inst::ExtractField { src, .. } | Inst::Store { src, .. } | Inst::Return { src } => vec![*src],
+            Inst::Add { lhs, rhs, .. } | Inst::Sub { lhs, rhs, .. } | Inst::Mul { lhs, rhs, .. }
+            | Inst::Div { lhs, rhs, .. } | Inst::Rem { lhs, rhs, .. } | Inst::Greater { lhs, rhs, .. }
+            | Inst::Less { lhs, rhs, .. } | Inst::Equal { lhs, rhs, .. } | Inst::GreaterEq { lhs, rhs, .. }
+            | Inst::LessEq { lhs, rhs, .. } | Inst::NotEqual { lhs, rhs, .. } | Inst::BitAnd { lhs, rhs, .. }
+            | Inst::BitOr { lhs, rhs, .. } | Inst::BitXor { lhs, rhs, .. } | Inst::Shl { lhs, rhs, .. }
+            | Inst::Shr { lhs, rhs, .. } | Inst::FAdd { lhs, rhs, .. } | Inst::FSub { lhs, rhs, .. }
+            | Inst::FMul { lhs, rhs, .. } | Inst::FDiv { lhs, rhs, .. } | Inst::FRem { lhs, rhs, .. }
+            | Inst::FGreater { lhs, rhs, .. } | Inst::FLess { lhs, rhs, .. } | Inst::FEqual { lhs, rhs, .. }
+            | Inst::FGreaterEq { lhs, rhs, .. } | Inst::FLessEq { lhs, rhs, .. } | Inst::FNotEqual { lhs, rhs, .. } => {
+                vec![*lhs, *rhs]
+            }
+            Inst::Call { args, .. } | Inst::Br { args, .. } => args.clone(),
+            Inst::ArrayNew { dims, .. } => dims.clone(),
+            Inst::StructNew { fields, .. } => fields.clone(),
+            Inst::ArrayLen { array, .. } => vec![*array],
+            Inst::CheckIndex { index, len } => vec![*index, *len],
+            Inst::ElemAddr { array, index, .. } => vec![*array, *index],
+            Inst::LoadElem { addr, .. } => vec![*addr],
+            Inst::StoreElem { addr, src } => vec![*addr, *src],
+            Inst::InsertField { src, value, .. } => vec![*src, *value],
+            Inst::CondBr { cond, then_args, else_args, .. } => {
+                std::iter::once(*cond).chain(then_args.iter().copied()).chain(else_args.iter().copied()).collect()
+            }
+        }
+    }
+
+    // Every value the instruction reads, branch arguments included, for
+    // passes that rewrite uses.
+    pub fn operands_mut(&mut self) -> Vec<&mut ValueId> {
+        match self {
+            Inst::Const { .. } | Inst::Boolean { .. } | Inst::FConst { .. } | Inst::Load { .. } | Inst::Unreachable => Vec::new(),
+            Inst::Cast { src, .. } | Inst::BitNot { src, .. } | Inst::Not { src, .. } | Inst::FNeg { src, .. }
+            | Inst::ExtractField { src, .. } | Inst::Store { src, .. } | Inst::Return { src } => vec![src],
+            Inst::Add { lhs, rhs, .. } | Inst::Sub { lhs, rhs, .. } | Inst::Mul { lhs, rhs, .. }
+            | Inst::Div { lhs, rhs, .. } | Inst::Rem { lhs, rhs, .. } | Inst::Greater { lhs, rhs, .. }
+            | Inst::Less { lhs, rhs, .. } | Inst::Equal { lhs, rhs, .. } | Inst::GreaterEq { lhs, rhs, .. }
+            | Inst::LessEq { lhs, rhs, .. } | Inst::NotEqual { lhs, rhs, .. } | Inst::BitAnd { lhs, rhs, .. }
+            | Inst::BitOr { lhs, rhs, .. } | Inst::BitXor { lhs, rhs, .. } | Inst::Shl { lhs, rhs, .. }
+            | Inst::Shr { lhs, rhs, .. } | Inst::FAdd { lhs, rhs, .. } | Inst::FSub { lhs, rhs, .. }
+            | Inst::FMul { lhs, rhs, .. } | Inst::FDiv { lhs, rhs, .. } | Inst::FRem { lhs, rhs, .. }
+            | Inst::FGreater { lhs, rhs, .. } | Inst::FLess { lhs, rhs, .. } | Inst::FEqual { lhs, rhs, .. }
+            | Inst::FGreaterEq { lhs, rhs, .. } | Inst::FLessEq { lhs, rhs, .. } | Inst::FNotEqual { lhs, rhs, .. } => {
+                vec![lhs, rhs]
+            }
+            Inst::Call { args, .. } | Inst::Br { args, .. } => args.iter_mut().collect(),
+            Inst::ArrayNew { dims, .. } => dims.iter_mut().collect(),
+            Inst::StructNew { fields, .. } => fields.iter_mut().collect(),
+            Inst::ArrayLen { array, .. } => vec![array],
+            Inst::CheckIndex { index, len } => vec![index, len],
+            Inst::ElemAddr { array, index, .. } => vec![array, index],
+            Inst::LoadElem { addr, .. } => vec![addr],
+            Inst::StoreElem { addr, src } => vec![addr, src],
+            Inst::InsertField { src, value, .. } => vec![src, value],
+            Inst::CondBr { cond, then_args, else_args, .. } => {
+                std::iter::once(cond).chain(then_args.iter_mut()).chain(else_args.iter_mut()).collect()
+            }
+        }
+    }
+
+    // The blocks a terminator may go to, each with the arguments it passes;
+    // empty for any other instruction.
+    pub fn edges(&self) -> Vec<(BlockId, &[ValueId])> {
+        match self {
+            Inst::Br { target, args } => vec![(*target, args.as_slice())],
+            Inst::CondBr { then_block, then_args, else_block, else_args, .. } => {
+                vec![(*then_block, then_args.as_slice()), (*else_block, else_args.as_slice())]
+            }
+            _ => Vec::new(),
+        }
     }
 }
 
@@ -49,30 +275,118 @@ impl Function{
     pub fn new(name: String) -> Self {
         Function {
             name,
-            body: Vec::new(),
-            next_value: 0,
+            params: Vec::new(),
+            ret: Type::I64,
+            blocks: vec![Block::default()],
+            loops: Vec::new(),
+            types: Vec::new(),
+            spans: HashMap::new(),
         }
     }
 
-    pub fn fresh_value(&mut self) -> ValueId {
-        let id = ValueId(self.next_value);
-        self.next_value += 1;
+    pub fn fresh_value(&mut self, ty: Type) -> ValueId {
+        let id = ValueId(self.types.len() as u32);
+        self.types.push(ty);
         id
     }
 
-    pub fn dump(&self) {
-        println!("fn {}() {{", self.name);
-        for (i, inst) in self.body.iter().enumerate() {
-            println!("  v{:02}: {:?}", i, inst);
+    pub fn value_type(&self, id: ValueId) -> Type {
+        self.types[id.get_usize()].clone()
+    }
+
+    // Gives `id` a type, for building a function whose ids are already
+    // chosen (`text::parse_module`). Ids skipped over get `unit`.
+    pub fn set_value_type(&mut self, id: ValueId, ty: Type) {
+        if self.types.len() <= id.get_usize() {
+            self.types.resize(id.get_usize() + 1, Type::Unit);
+        }
+        self.types[id.get_usize()] = ty;
+    }
+
+    // how many values have been handed out, one more than the highest ValueId
+    pub fn value_count(&self) -> usize {
+        self.types.len()
+    }
+
+    pub fn set_span(&mut self, id: ValueId, span: Span) {
+        self.spans.entry(id).or_insert(span);
+    }
+
+    pub fn span_of(&self, id: ValueId) -> Option<Span> {
+        self.spans.get(&id).copied()
+    }
+
+    pub fn entry(&self) -> BlockId {
+        BlockId(0)
+    }
+
+    pub fn block(&self, id: BlockId) -> &Block {
+        &self.blocks[id.get_usize()]
+    }
+
+    pub fn block_mut(&mut self, id: BlockId) -> &mut Block {
+        &mut self.blocks[id.get_usize()]
+    }
+
+    pub fn add_block(&mut self) -> BlockId {
+        self.blocks.push(Block::default());
+        BlockId(self.blocks.len() as u32 - 1)
+    }
+
+    pub fn add_block_param(&mut self, block: BlockId, ty: Type) -> ValueId {
+        let id = self.fresh_value(ty);
+        self.block_mut(block).params.push(id);
+        id
+    }
+
+    // The last instruction of `block`, if it is a terminator.
+    pub fn terminator(&self, block: BlockId) -> Option<&Inst> {
+        self.block(block).insts.last().filter(|i| i.is_terminator())
+    }
+
+    // Drops the blocks that cannot be reached from the entry and renumbers
+    // the rest, keeping their order. Lowering leaves such blocks behind
+    // after a `return`, and folding a branch can cut more off.
+    pub fn remove_unreachable_blocks(&mut self) {
+        let mut renumber: Vec<Option<BlockId>> = vec![None; self.blocks.len()];
+        for b in Cfg::new(self).reverse_postorder() {
+            renumber[b.get_usize()] = Some(b);
+        }
+        for (kept, slot) in renumber.iter_mut().flatten().enumerate() {
+            *slot = BlockId(kept as u32);
+        }
+        let blocks = std::mem::take(&mut self.blocks);
+        for (block, new_id) in blocks.into_iter().zip(&renumber) {
+            if new_id.is_some() {
+                self.blocks.push(block);
+            }
+        }
+        // a loop whose header is gone went with it
+        self.loops.retain_mut(|l| match renumber[l.header.get_usize()] {
+            Some(header) => {
+                l.header = header;
+                true
+            }
+            None => false,
+        });
+        let target = |b: &mut BlockId| *b = renumber[b.get_usize()].expect("a reachable block only branches to reachable ones");
+        for block in &mut self.blocks {
+            match block.insts.last_mut() {
+                Some(Inst::Br { target: t, .. }) => target(t),
+                Some(Inst::CondBr { then_block, else_block, .. }) => {
+                    target(then_block);
+                    target(else_block);
+                }
+                _ => {}
+            }
         }
-        println!("}}");
     }
 }
 
 //create and add function for Module
 impl Module{
     pub fn new() -> Self{
-        Self { functions: Vec::new() }
+        Self { functions: Vec::new(), structs: Vec::new(), overflow: Overflow::Wrap, bounds_checks: true }
     }
 
     pub fn add_function(&mut self, func: Function){
@@ -80,6 +394,15 @@ impl Module{
     }
 }
 
+impl BlockId{
+    pub fn from_usize(id: usize) -> Self {
+        BlockId(id as u32)
+    }
+    pub fn get_usize(&self) -> usize {
+        self.0 as usize
+    }
+}
+
 impl ValueId{
     pub fn from_usize(id: usize) -> Self {
         ValueId(id as u32)
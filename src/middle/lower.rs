@@ -1,277 +1,587 @@
-use crate::frontend::ast::{Expr, UnaryOp, BinaryOp};
-use crate::middle::ir::{Module, Function, Inst, ValueId};
+use crate::frontend::ast::{Expr, ExprKind, Stmt, StmtKind, Param, SymbolId, Type, UnaryOp, BinaryOp};
+use crate::diagnostic::{codes, Diagnostic};
+use crate::middle::ir::{debug_verify, BlockId, CountedLoop, Module, Function, Inst, StructDef, ValueId};
 
-pub fn lower_program_to_module(exprs: &[Expr]) -> Module {
+// Every top-level `fn` becomes its own IR function; all remaining statements
+// form `main`. Expects a program that `sema` has resolved and type-checked.
+pub fn lower_program_to_module(stmts: &[Stmt]) -> Result<Module, Box<Diagnostic>> {
     let mut module = Module::new();
-    let mut func = Function::new("main".to_string());
 
-    let mut last: Option<ValueId> = None;
-
-    for e in exprs {
-        let v = lower_expr(e, &mut func);
-        last = Some(v);
+    for s in stmts {
+        if let StmtKind::Struct { name, fields } = &s.kind {
+            let fields = fields
+                .iter()
+                .map(|f| (f.name.clone(), f.ty.clone().expect("fields are type-checked before lowering")))
+                .collect();
+            module.structs.push(StructDef { name: name.clone(), fields });
+        }
+        if let StmtKind::Fn { name, params, ret_ty, body, .. } = &s.kind {
+            let ret = ret_ty.clone().expect("functions are type-checked before lowering");
+            module.add_function(lower_function(name, params, ret, body)?);
+        }
     }
 
-    let result = match last {
-        Some(v) => v,
-        None => {
-            let dst = func.fresh_value();
-            func.body.push(Inst::Const { dst, value: 0 });
+    let mut b = Builder::new(Function::new("main".to_string()));
+
+    // the program evaluates to its last statement; `main` always returns an
+    // i64, so other results are converted (a float is truncated, an array
+    // or a struct gives 0 like unit)
+    let top_level = stmts.iter().filter(|s| !matches!(s.kind, StmtKind::Fn { .. } | StmtKind::Struct { .. }));
+    let result = lower_stmts(top_level, &mut b)?;
+    let src = match b.func.value_type(result) {
+        Type::I64 => result,
+        Type::Unit | Type::Array { .. } | Type::Struct(_) => {
+            let dst = b.func.fresh_value(Type::I64);
+            b.push(Inst::Const { dst, value: 0 });
+            dst
+        }
+        _ => {
+            let dst = b.func.fresh_value(Type::I64);
+            b.push(Inst::Cast { dst, src: result });
             dst
         }
     };
 
-    func.body.push(Inst::Return { src: result });
-    module.add_function(func);
-    module
+    b.push(Inst::Return { src });
+    module.add_function(b.finish());
+    debug_verify(&module, "lowering");
+    Ok(module)
+}
+
+fn lower_function(name: &str, params: &[Param], ret: Type, body: &Stmt) -> Result<Function, Box<Diagnostic>> {
+    let mut func = Function::new(name.to_string());
+    func.params = params
+        .iter()
+        .map(|p| (slot_name(&p.name, p.sym), p.ty.clone().expect("parameters are type-checked before lowering")))
+        .collect();
+    func.ret = ret;
+
+    // falling off the end returns the body's value. The type checker only
+    // lets the body be unit when every path has already returned, so the
+    // block left open then is never reached.
+    let mut b = Builder::new(func);
+    for (name, ty) in b.func.params.clone() {
+        let arg = b.func.add_block_param(b.block, ty);
+        b.push(Inst::Store { name, src: arg });
+    }
+    let result = lower_stmt(body, &mut b)?;
+    if b.func.value_type(result) != Type::Unit {
+        b.push(Inst::Return { src: result });
+    } else {
+        b.push(Inst::Unreachable);
+    }
+    Ok(b.finish())
+}
+
+// The function being lowered and the block instructions are appended to.
+// Control flow ends that block with a terminator and carries on in another.
+struct Builder {
+    func: Function,
+    block: BlockId,
+}
+
+impl Builder {
+    fn new(func: Function) -> Self {
+        let block = func.entry();
+        Builder { func, block }
+    }
+
+    fn push(&mut self, inst: Inst) {
+        self.func.block_mut(self.block).insts.push(inst);
+    }
+
+    // End the current block with `term` and continue in `next`.
+    fn switch_to(&mut self, term: Inst, next: BlockId) {
+        self.push(term);
+        self.block = next;
+    }
+
+    // The code after a `return` lands in blocks nothing branches to; they
+    // are dropped here.
+    fn finish(mut self) -> Function {
+        self.func.remove_unreachable_blocks();
+        self.func
+    }
 }
 
-fn lower_expr(expr: &Expr, func: &mut Function) -> ValueId {
-    match expr {
-        Expr::If {cond, body, else_branch } => {
-            // lower condition into the function's body
-            let cond_val = lower_expr(cond, func);
+// Variables live in named slots. Different symbols may share a name (a loop
+// variable shadowing a local), so the slot name includes the symbol id.
+fn slot_name(name: &str, sym: Option<SymbolId>) -> String {
+    let sym = sym.expect("names are resolved before lowering");
+    format!("{}.{}", name, sym.0)
+}
 
-            // prepare then_insts by lowering the body into a temporary Vec
-            let mut then_insts: Vec<Inst> = Vec::new();
-            let then_val = lower_into(body, func, &mut then_insts);
+fn ty(expr: &Expr) -> Type {
+    expr.ty.clone().expect("expressions are type-checked before lowering")
+}
 
-            // create a temp name based on the dst id that we'll use to store branch result
-            let dst = func.fresh_value();
-            let temp_name = format!("__if_tmp_{}", dst.get_usize());
+// Code without a value still yields a ValueId so every lowering function
+// returns one, but a unit value is never defined by an instruction and is
+// never used by one (the type checker rejects unit operands).
+fn unit_value(b: &mut Builder) -> ValueId {
+    b.func.fresh_value(Type::Unit)
+}
 
-            // append a store to temp at the end of then_insts
-            then_insts.push(Inst::Store { name: temp_name.clone(), src: then_val });
+// Lower a statement list, returning the value of the last statement (unit when empty).
+fn lower_stmts<'a>(
+    stmts: impl IntoIterator<Item = &'a Stmt>,
+    b: &mut Builder,
+) -> Result<ValueId, Box<Diagnostic>> {
+    let mut last: Option<ValueId> = None;
+    for s in stmts {
+        last = Some(lower_stmt(s, b)?);
+    }
+    match last {
+        Some(v) => Ok(v),
+        None => Ok(unit_value(b)),
+    }
+}
 
-            // else: if present lower into else_insts, otherwise default to 0
-            let mut else_insts: Vec<Inst> = Vec::new();
-            if let Some(else_expr) = else_branch {
-                let else_val = lower_into(else_expr, func, &mut else_insts);
-                else_insts.push(Inst::Store { name: temp_name.clone(), src: else_val });
+fn lower_stmt(stmt: &Stmt, b: &mut Builder) -> Result<ValueId, Box<Diagnostic>> {
+    match &stmt.kind {
+        StmtKind::Expr(e) => lower_expr(e, b),
+        StmtKind::Assign { target, value, .. } => {
+            let ExprKind::Ident { name, sym } = &target.kind else {
+                // the value first, matching the order the checker sees it in.
+                // A field store also reads the structs around the field
+                let src = lower_expr(value, b)?;
+                let place = lower_place(target, b)?;
+                let levels = match place.path.len() {
+                    0 => Vec::new(),
+                    n => load_place(&place, n - 1, b),
+                };
+                store_place(place, &levels, src, b);
+                return Ok(src);
+            };
+            let name = slot_name(name, *sym);
+            let rhs = lower_expr(value, b)?;
+            // reload so the statement's value comes from the variable
+            let dst = b.func.fresh_value(ty(value));
+            b.push(Inst::Store { name: name.clone(), src: rhs });
+            b.push(Inst::Load { dst, name });
+            b.func.set_span(dst, stmt.span);
+            Ok(dst)
+        }
+        StmtKind::CompoundAssign { target, op, value } => {
+            let ty = ty(target);
+            let rhs = match value {
+                Some(value) => lower_expr(value, b)?,
+                None => {
+                    // `x++` and `x--` step by one
+                    let dst = b.func.fresh_value(ty.clone());
+                    b.push(if ty.is_float() {
+                        Inst::FConst { dst, value: 1.0 }
+                    } else {
+                        Inst::Const { dst, value: 1 }
+                    });
+                    dst
+                }
+            };
+            let place = lower_place(target, b)?;
+            let levels = load_place(&place, place.path.len(), b);
+            let lhs = *levels.last().unwrap();
+            let dst = b.func.fresh_value(ty.clone());
+            b.push(match (op, ty.is_float()) {
+                (BinaryOp::Add, false) => Inst::Add { dst, lhs, rhs },
+                (BinaryOp::Sub, false) => Inst::Sub { dst, lhs, rhs },
+                (BinaryOp::Mul, false) => Inst::Mul { dst, lhs, rhs },
+                (BinaryOp::Div, false) => Inst::Div { dst, lhs, rhs },
+                (BinaryOp::Add, true) => Inst::FAdd { dst, lhs, rhs },
+                (BinaryOp::Sub, true) => Inst::FSub { dst, lhs, rhs },
+                (BinaryOp::Mul, true) => Inst::FMul { dst, lhs, rhs },
+                (BinaryOp::Div, true) => Inst::FDiv { dst, lhs, rhs },
+                _ => unreachable!("the parser only builds `+=`, `-=`, `*=` and `/=`"),
+            });
+            b.func.set_span(dst, stmt.span);
+            store_place(place, &levels, dst, b);
+            Ok(dst)
+        }
+        StmtKind::Block(stmts) => lower_stmts(stmts, b),
+        StmtKind::Fn { .. } => unreachable!("the resolver rejects functions outside the top level"),
+        StmtKind::Struct { .. } => unreachable!("the resolver rejects structs outside the top level"),
+        StmtKind::Return(value) => {
+            let src = lower_expr(value, b)?;
+            // anything after the return goes to a block nothing reaches
+            let dead = b.func.add_block();
+            b.switch_to(Inst::Return { src }, dead);
+            Ok(src)
+        }
+    }
+}
+
+// Lower an expression into the builder's current block. Control flow (if,
+// loops, `and`/`or`) adds blocks and leaves the builder in the block where
+// the expression's value is available. Every produced value remembers the
+// span of the expression it came from.
+fn lower_expr(expr: &Expr, b: &mut Builder) -> Result<ValueId, Box<Diagnostic>> {
+    let v = lower_expr_kind(expr, b)?;
+    b.func.set_span(v, expr.span);
+    Ok(v)
+}
+
+fn lower_expr_kind(expr: &Expr, b: &mut Builder) -> Result<ValueId, Box<Diagnostic>> {
+    match &expr.kind {
+        ExprKind::If {cond, body, else_branch } => {
+            let cond_val = lower_expr(cond, b)?;
+            let then_block = b.func.add_block();
+            let else_block = b.func.add_block();
+            let join = b.func.add_block();
+            // an `if` with a value passes each branch's value to the join
+            let dst = match ty(expr) {
+                Type::Unit => None,
+                ty => Some(b.func.add_block_param(join, ty)),
+            };
+            // without an `else`, a false condition goes straight to the join
+            let else_target = if else_branch.is_some() { else_block } else { join };
+            b.switch_to(
+                Inst::CondBr { cond: cond_val, then_block, then_args: Vec::new(), else_block: else_target, else_args: Vec::new() },
+                then_block,
+            );
+
+            let then_val = lower_stmt(body, b)?;
+            let args = |v: ValueId| if dst.is_some() { vec![v] } else { Vec::new() };
+            b.switch_to(Inst::Br { target: join, args: args(then_val) }, else_block);
+            if let Some(else_stmt) = else_branch {
+                let else_val = lower_stmt(else_stmt, b)?;
+                b.switch_to(Inst::Br { target: join, args: args(else_val) }, join);
             } else {
-                let else_val = func.fresh_value();
-                else_insts.push(Inst::Const { dst: else_val, value: 0 });
-                else_insts.push(Inst::Store { name: temp_name.clone(), src: else_val });
+                // the unused else block is dropped with the other dead blocks
+                b.switch_to(Inst::Unreachable, join);
             }
+            Ok(dst.unwrap_or_else(|| unit_value(b)))
+        }
+        ExprKind::While { cond, body } => {
+            // the condition is re-evaluated in the header before every iteration
+            let header = b.func.add_block();
+            let body_block = b.func.add_block();
+            let exit = b.func.add_block();
+            b.switch_to(Inst::Br { target: header, args: Vec::new() }, header);
+            let cond_val = lower_expr(cond, b)?;
+            b.switch_to(
+                Inst::CondBr { cond: cond_val, then_block: body_block, then_args: Vec::new(), else_block: exit, else_args: Vec::new() },
+                body_block,
+            );
+            lower_stmt(body, b)?;
+            b.switch_to(Inst::Br { target: header, args: Vec::new() }, exit);
+            Ok(unit_value(b))
+        }
+        ExprKind::For { var, start, end, body } => {
+            let name = match &var.kind {
+                ExprKind::Ident { name, sym } => slot_name(name, *sym),
+                _ => unreachable!("the parser only accepts an identifier as loop variable"),
+            };
+            // bounds are evaluated once, before the loop
+            let start_val = lower_expr(start, b)?;
+            let end_val = lower_expr(end, b)?;
 
-            // emit conditional instruction which will be expanded in codegen
-            func.body.push(Inst::Conditional { cond: cond_val, body: then_insts, else_insts, dst });
+            // The counter is a parameter of the header, apart from `var`, so
+            // assigning to `var` in the body cannot change the trip count.
+            // It only grows while below `end`, so `+ 1` cannot overflow.
+            let counter_ty = b.func.value_type(start_val);
+            let header = b.func.add_block();
+            let counter = b.func.add_block_param(header, counter_ty.clone());
+            let body_block = b.func.add_block();
+            let exit = b.func.add_block();
+            b.switch_to(Inst::Br { target: header, args: vec![start_val] }, header);
+            let cond_val = b.func.fresh_value(Type::Bool);
+            b.push(Inst::Less { dst: cond_val, lhs: counter, rhs: end_val });
+            b.switch_to(
+                Inst::CondBr { cond: cond_val, then_block: body_block, then_args: Vec::new(), else_block: exit, else_args: Vec::new() },
+                body_block,
+            );
 
-            // The Conditional codegen will load the temp into dst; return dst here
-            dst
+            b.push(Inst::Store { name, src: counter });
+            lower_stmt(body, b)?;
+            let one = b.func.fresh_value(counter_ty.clone());
+            b.push(Inst::Const { dst: one, value: 1 });
+            let next = b.func.fresh_value(counter_ty);
+            b.push(Inst::Add { dst: next, lhs: counter, rhs: one });
+            b.switch_to(Inst::Br { target: header, args: vec![next] }, exit);
+            b.func.loops.push(CountedLoop { header, counter, start: start_val, end: end_val });
+            Ok(unit_value(b))
         }
-        Expr::Number(n) => {
-            let dst = func.fresh_value();
-            func.body.push(Inst::Const { dst, value: *n });
-            dst
+        ExprKind::Number(n) => {
+            let dst = b.func.fresh_value(ty(expr));
+            // in range for its type, so only a u64 can be above i64::MAX,
+            // which `Const` keeps as its bit pattern
+            b.push(Inst::Const { dst, value: *n as i64 });
+            Ok(dst)
         }
-        Expr::Ident(name) => {
-            let dst = func.fresh_value();
-            //boolean check
-            if name == "true" {
-                func.body.push(Inst::Boolean { dst, value: true });
-            }else if name == "false" {
-                func.body.push(Inst::Boolean { dst, value: false });
-            }else{
-                func.body.push(Inst::Load { dst, name: name.clone()});
-            }
-            dst
+        ExprKind::Float(x) => {
+            let dst = b.func.fresh_value(ty(expr));
+            b.push(Inst::FConst { dst, value: *x });
+            Ok(dst)
         }
-        Expr::Unary { op, expr } => {
-            let val = lower_expr(expr, func);
+        ExprKind::Bool(value) => {
+            let dst = b.func.fresh_value(Type::Bool);
+            b.push(Inst::Boolean { dst, value: *value });
+            Ok(dst)
+        }
+        ExprKind::Ident { name, sym } => {
+            let dst = b.func.fresh_value(ty(expr));
+            b.push(Inst::Load { dst, name: slot_name(name, *sym) });
+            Ok(dst)
+        }
+        ExprKind::Unary { op, expr: inner } => {
+            let val = lower_expr(inner, b)?;
             match op {
-                //will add increment operation here later
+                UnaryOp::Neg if ty(expr).is_float() => {
+                    let dst = b.func.fresh_value(ty(expr));
+                    b.push(Inst::FNeg { dst, src: val });
+                    Ok(dst)
+                }
+                UnaryOp::Not => {
+                    let dst = b.func.fresh_value(Type::Bool);
+                    b.push(Inst::Not { dst, src: val });
+                    Ok(dst)
+                }
+                UnaryOp::BitNot => {
+                    let dst = b.func.fresh_value(ty(expr));
+                    b.push(Inst::BitNot { dst, src: val });
+                    Ok(dst)
+                }
                 UnaryOp::Neg => {
-                    let zero = func.fresh_value();
-                    func.body.push(Inst::Const { dst: zero, value: 0 });
-                    let dst = func.fresh_value();
-                    func.body.push(Inst::Sub { dst, lhs: zero, rhs: val });
-                    dst
+                    let zero = b.func.fresh_value(ty(expr));
+                    b.push(Inst::Const { dst: zero, value: 0 });
+                    let dst = b.func.fresh_value(ty(expr));
+                    b.push(Inst::Sub { dst, lhs: zero, rhs: val });
+                    Ok(dst)
                 }
             }
         }
-        Expr::Binary { left, op, right } => {
-            let rhs = lower_expr(right, func);
-            let dst = func.fresh_value();
+        ExprKind::Binary { left, op: op @ (BinaryOp::And | BinaryOp::Or), right } => {
+            // `a and b` is `if a: b else: false`, `a or b` is `if a: true else: b`:
+            // the side `a` decides goes straight to the join with that constant
+            let cond = lower_expr(left, b)?;
+            let decided = b.func.fresh_value(Type::Bool);
+            b.push(Inst::Boolean { dst: decided, value: *op == BinaryOp::Or });
+            let rhs_block = b.func.add_block();
+            let join = b.func.add_block();
+            let dst = b.func.add_block_param(join, Type::Bool);
+            let (then_block, then_args, else_block, else_args) = match op {
+                BinaryOp::And => (rhs_block, Vec::new(), join, vec![decided]),
+                _ => (join, vec![decided], rhs_block, Vec::new()),
+            };
+            b.switch_to(Inst::CondBr { cond, then_block, then_args, else_block, else_args }, rhs_block);
+            let rhs = lower_expr(right, b)?;
+            b.switch_to(Inst::Br { target: join, args: vec![rhs] }, join);
+            Ok(dst)
+        }
+        ExprKind::Binary { left, op, right } => {
+            // left to right, so side effects happen in source order
+            let lhs = lower_expr(left, b)?;
+            let rhs = lower_expr(right, b)?;
+            let dst = b.func.fresh_value(ty(expr));
+            // both operands have the same type, so the left one picks int or float instructions
+            let float = ty(left).is_float();
 
-            match op {
-                BinaryOp::Assign => {
-                    let name = match &**left{
-                        Expr::Ident(n) => n.clone(),
-                        _ => panic!("Left side of assignment must be an identifier!"),
-                    };
-                    func.body.push(Inst::Store { name: name.clone(), src: rhs });
-                    func.body.push(Inst::Load { dst, name });
-
-                },
-                BinaryOp::Add => {
-                    let lhs = lower_expr(left, func);
-                    func.body.push(Inst::Add { dst, lhs, rhs })
-                },
-                BinaryOp::Sub => {
-                    let lhs = lower_expr(left, func);
-                    func.body.push(Inst::Sub { dst, lhs, rhs })
-                },
-                BinaryOp::Mul =>{
-                    let lhs = lower_expr(left, func);
-                    func.body.push(Inst::Mul { dst, lhs, rhs })
-                },
-                BinaryOp::Div => {
-                    let lhs = lower_expr(left, func);
-                    func.body.push(Inst::Div { dst, lhs, rhs })
-                },
-                BinaryOp::Greater => {
-                    let lhs = lower_expr(left, func);
-                    func.body.push(Inst::Greater {dst, lhs, rhs})
-                },
-                BinaryOp::Less => {
-                    let lhs = lower_expr(left, func);
-                    func.body.push(Inst::Less {dst, lhs, rhs})
-                },
-                BinaryOp::Equal => {
-                    let lhs = lower_expr(left, func);
-                    func.body.push(Inst::Equal {dst, lhs, rhs})
-                }
-            }
-            dst
+            b.push(match op {
+                BinaryOp::Add => if float { Inst::FAdd { dst, lhs, rhs } } else { Inst::Add { dst, lhs, rhs } },
+                BinaryOp::Sub => if float { Inst::FSub { dst, lhs, rhs } } else { Inst::Sub { dst, lhs, rhs } },
+                BinaryOp::Mul => if float { Inst::FMul { dst, lhs, rhs } } else { Inst::Mul { dst, lhs, rhs } },
+                BinaryOp::Div => if float { Inst::FDiv { dst, lhs, rhs } } else { Inst::Div { dst, lhs, rhs } },
+                BinaryOp::Rem => if float { Inst::FRem { dst, lhs, rhs } } else { Inst::Rem { dst, lhs, rhs } },
+                BinaryOp::Greater => if float { Inst::FGreater { dst, lhs, rhs } } else { Inst::Greater { dst, lhs, rhs } },
+                BinaryOp::Less => if float { Inst::FLess { dst, lhs, rhs } } else { Inst::Less { dst, lhs, rhs } },
+                BinaryOp::Equal => if float { Inst::FEqual { dst, lhs, rhs } } else { Inst::Equal { dst, lhs, rhs } },
+                BinaryOp::GreaterEq => if float { Inst::FGreaterEq { dst, lhs, rhs } } else { Inst::GreaterEq { dst, lhs, rhs } },
+                BinaryOp::LessEq => if float { Inst::FLessEq { dst, lhs, rhs } } else { Inst::LessEq { dst, lhs, rhs } },
+                BinaryOp::NotEqual => if float { Inst::FNotEqual { dst, lhs, rhs } } else { Inst::NotEqual { dst, lhs, rhs } },
+                // integers only, see the type checker
+                BinaryOp::BitAnd => Inst::BitAnd { dst, lhs, rhs },
+                BinaryOp::BitOr => Inst::BitOr { dst, lhs, rhs },
+                BinaryOp::BitXor => Inst::BitXor { dst, lhs, rhs },
+                BinaryOp::Shl => Inst::Shl { dst, lhs, rhs },
+                BinaryOp::Shr => Inst::Shr { dst, lhs, rhs },
+                BinaryOp::And | BinaryOp::Or => unreachable!("short-circuit operators are lowered above"),
+            });
+            Ok(dst)
         }
-        Expr::Call { callee, args } => {
-            let callee_name = match &**callee{
-                Expr::Ident(name) => name.clone(),
-                _ => panic!("non-ident callee not supported yet!"),
+        ExprKind::Call { callee, args } => {
+            let callee_name = match &callee.kind {
+                ExprKind::Ident { name, .. } => name.clone(),
+                _ => return Err(Box::new(Diagnostic::error("only named functions can be called")
+                    .with_code(codes::UNSUPPORTED_CALLEE)
+                    .with_primary(callee.span, "this expression is not a function name"))),
             };
 
-            let arg_ids: Vec<ValueId> = args.iter().map(|a| lower_expr(a, func)).collect();
+            let mut arg_ids: Vec<ValueId> = Vec::new();
+            for a in args {
+                arg_ids.push(lower_expr(a, b)?);
+            }
 
-            let dst = func.fresh_value();
-            func.body.push(Inst::Call {
+            let dst = b.func.fresh_value(ty(expr));
+            b.push(Inst::Call {
                 dst,
                 callee: callee_name,
                 args: arg_ids,
             });
 
-            dst
+            Ok(dst)
         }
-    }
-}
-
-// Lower an expression into a provided instruction vector. This uses the same
-// Function `fresh_value` allocator so ValueIds remain unique across the function.
-fn lower_into(expr: &Expr, func: &mut Function, out: &mut Vec<Inst>) -> ValueId {
-    match expr {
-        Expr::If { cond, body, else_branch } => {
-            // lower condition into the provided out vector
-            let cond_val = lower_into(cond, func, out);
-
-            // then branch lowered into its own instruction vector
-            let mut then_insts: Vec<Inst> = Vec::new();
-            let then_val = lower_into(body, func, &mut then_insts);
-
-            // allocate a dst and temp name that both branches will store into
-            let dst = func.fresh_value();
-            let temp_name = format!("__if_tmp_{}", dst.get_usize());
-            then_insts.push(Inst::Store { name: temp_name.clone(), src: then_val });
-
-            // else branch: if present, lower into else_insts; otherwise default to 0
-            let mut else_insts: Vec<Inst> = Vec::new();
-            if let Some(else_expr) = else_branch {
-                let else_val = lower_into(else_expr, func, &mut else_insts);
-                else_insts.push(Inst::Store { name: temp_name.clone(), src: else_val });
-            } else {
-                let else_val = func.fresh_value();
-                else_insts.push(Inst::Const { dst: else_val, value: 0 });
-                else_insts.push(Inst::Store { name: temp_name.clone(), src: else_val });
+        ExprKind::Array(elems) => {
+            let len = b.func.fresh_value(Type::I64);
+            b.push(Inst::Const { dst: len, value: elems.len() as i64 });
+            let dst = b.func.fresh_value(ty(expr));
+            b.push(Inst::ArrayNew { dst, dims: vec![len] });
+            // the indices are in range by construction, so they are not checked
+            for (i, e) in elems.iter().enumerate() {
+                let src = lower_expr(e, b)?;
+                let index = b.func.fresh_value(Type::I64);
+                b.push(Inst::Const { dst: index, value: i as i64 });
+                let addr = b.func.fresh_value(Type::Ptr);
+                b.push(Inst::ElemAddr { dst: addr, array: dst, index });
+                b.func.set_span(addr, e.span);
+                b.push(Inst::StoreElem { addr, src });
             }
-
-            // push a Conditional inst into the provided vector
-            out.push(Inst::Conditional { cond: cond_val, body: then_insts, else_insts, dst });
-            
-            // after the Conditional, emit a Load from the temp into a fresh result ValueId
-            // so that the caller can use the result before it's actually loaded
-            let result = func.fresh_value();
-            out.push(Inst::Load { dst: result, name: temp_name });
-            result
-        }
-        Expr::Number(n) => {
-            let dst = func.fresh_value();
-            out.push(Inst::Const { dst, value: *n });
-            dst
+            Ok(dst)
         }
-        Expr::Ident(name) => {
-            let dst = func.fresh_value();
-            if name == "true" {
-                out.push(Inst::Boolean { dst, value: true });
-            } else if name == "false" {
-                out.push(Inst::Boolean { dst, value: false });
-            } else {
-                out.push(Inst::Load { dst, name: name.clone() });
+        ExprKind::NewArray { dims } => {
+            let mut dim_ids = Vec::new();
+            for d in dims {
+                dim_ids.push(lower_expr(d, b)?);
             }
-            dst
+            let dst = b.func.fresh_value(ty(expr));
+            b.push(Inst::ArrayNew { dst, dims: dim_ids });
+            Ok(dst)
         }
-        Expr::Unary { op, expr } => {
-            let val = lower_into(expr, func, out);
-            match op {
-                UnaryOp::Neg => {
-                    let zero = func.fresh_value();
-                    out.push(Inst::Const { dst: zero, value: 0 });
-                    let dst = func.fresh_value();
-                    out.push(Inst::Sub { dst, lhs: zero, rhs: val });
-                    dst
-                }
-            }
+        ExprKind::Len { array, dim } => {
+            let array = lower_expr(array, b)?;
+            // the type checker made sure `dim` is a literal below the rank
+            let dim = match dim.as_deref().map(|d| &d.kind) {
+                Some(ExprKind::Number(n)) => *n as u32,
+                _ => 0,
+            };
+            let dst = b.func.fresh_value(Type::I64);
+            b.push(Inst::ArrayLen { dst, array, dim });
+            Ok(dst)
         }
-        Expr::Binary { left, op, right } => {
-            let rhs = lower_into(right, func, out);
-            let dst = func.fresh_value();
-            match op {
-                BinaryOp::Assign => {
-                    let name = match &**left {
-                        Expr::Ident(n) => n.clone(),
-                        _ => panic!("Left side of assignment must be an identifier!"),
-                    };
-                    out.push(Inst::Store { name: name.clone(), src: rhs });
-                    out.push(Inst::Load { dst, name });
-                }
-                BinaryOp::Add => {
-                    let lhs = lower_into(left, func, out);
-                    out.push(Inst::Add { dst, lhs, rhs })
-                }
-                BinaryOp::Sub => {
-                    let lhs = lower_into(left, func, out);
-                    out.push(Inst::Sub { dst, lhs, rhs })
-                }
-                BinaryOp::Mul => {
-                    let lhs = lower_into(left, func, out);
-                    out.push(Inst::Mul { dst, lhs, rhs })
-                }
-                BinaryOp::Div => {
-                    let lhs = lower_into(left, func, out);
-                    out.push(Inst::Div { dst, lhs, rhs })
-                }
-                BinaryOp::Greater => {
-                    let lhs = lower_into(left, func, out);
-                    out.push(Inst::Greater { dst, lhs, rhs })
-                }
-                BinaryOp::Less => {
-                    let lhs = lower_into(left, func, out);
-                    out.push(Inst::Less { dst, lhs, rhs })
-                }
-                BinaryOp::Equal => {
-                    let lhs = lower_into(left, func, out);
-                    out.push(Inst::Equal { dst, lhs, rhs })
-                }
+        ExprKind::Index { array, indices } => {
+            let addr = lower_elem_addr(array, indices, b)?;
+            b.func.set_span(addr, expr.span);
+            let dst = b.func.fresh_value(ty(expr));
+            b.push(Inst::LoadElem { dst, addr });
+            Ok(dst)
+        }
+        ExprKind::StructLit { fields, .. } => {
+            // evaluated in source order, then put in declaration order
+            let mut values = Vec::new();
+            for f in fields {
+                let index = f.index.expect("fields are type-checked before lowering");
+                values.push((index, lower_expr(&f.value, b)?));
             }
-            dst
+            values.sort_by_key(|&(index, _)| index);
+            let dst = b.func.fresh_value(ty(expr));
+            b.push(Inst::StructNew { dst, fields: values.into_iter().map(|(_, v)| v).collect() });
+            Ok(dst)
         }
-        Expr::Call { callee, args } => {
-            let callee_name = match &**callee {
-                Expr::Ident(name) => name.clone(),
-                _ => panic!("non-ident callee not supported yet!"),
-            };
-            let arg_ids: Vec<ValueId> = args.iter().map(|a| lower_into(a, func, out)).collect();
-            let dst = func.fresh_value();
-            out.push(Inst::Call { dst, callee: callee_name, args: arg_ids });
-            dst
+        ExprKind::Field { base, index, .. } => {
+            let src = lower_expr(base, b)?;
+            let dst = b.func.fresh_value(ty(expr));
+            let field = index.expect("fields are type-checked before lowering");
+            b.push(Inst::ExtractField { dst, src, field });
+            Ok(dst)
         }
+        ExprKind::Error => unreachable!("programs with syntax errors are rejected before lowering"),
     }
-}
\ No newline at end of file
+}
+
+// The address of `array[i, j, ...]`, for reading or writing the element.
+// Each index is checked against its own extent, then they are combined
+// row-major: `((i * n1) + j) * n2 + k` for extents `n0, n1, n2`.
+fn lower_elem_addr(array: &Expr, indices: &[Expr], b: &mut Builder) -> Result<ValueId, Box<Diagnostic>> {
+    let array = lower_expr(array, b)?;
+    let mut flat: Option<ValueId> = None;
+    for (dim, index_expr) in indices.iter().enumerate() {
+        let index = lower_expr(index_expr, b)?;
+        let len = b.func.fresh_value(Type::I64);
+        // where a failed bounds check is reported
+        b.func.set_span(len, index_expr.span);
+        b.push(Inst::ArrayLen { dst: len, array, dim: dim as u32 });
+        b.push(Inst::CheckIndex { index, len });
+        flat = Some(match flat {
+            None => index,
+            Some(outer) => {
+                let scaled = b.func.fresh_value(Type::I64);
+                b.push(Inst::Mul { dst: scaled, lhs: outer, rhs: len });
+                let sum = b.func.fresh_value(Type::I64);
+                b.push(Inst::Add { dst: sum, lhs: scaled, rhs: index });
+                sum
+            }
+        });
+    }
+    let dst = b.func.fresh_value(Type::Ptr);
+    b.push(Inst::ElemAddr { dst, array, index: flat.expect("at least one index") });
+    Ok(dst)
+}
+
+// Where an assignment writes: a variable or an array element.
+enum Root {
+    Var(String),
+    Elem(ValueId),
+}
+
+// An assignment target with its root already evaluated, so the indices in
+// `a[f()].x += 1` run once: the root, then the fields (their index and the
+// access expression) to follow from it, outermost first.
+struct Place<'a> {
+    root: Root,
+    root_ty: Type,
+    path: Vec<(u32, &'a Expr)>,
+}
+
+fn lower_place<'a>(target: &'a Expr, b: &mut Builder) -> Result<Place<'a>, Box<Diagnostic>> {
+    let mut path = Vec::new();
+    let mut root = target;
+    while let ExprKind::Field { base, index, .. } = &root.kind {
+        path.push((index.expect("fields are type-checked before lowering"), root));
+        root = base;
+    }
+    path.reverse();
+
+    let place = match &root.kind {
+        ExprKind::Ident { name, sym } => Root::Var(slot_name(name, *sym)),
+        ExprKind::Index { array, indices } => {
+            let addr = lower_elem_addr(array, indices, b)?;
+            b.func.set_span(addr, root.span);
+            Root::Elem(addr)
+        }
+        _ => unreachable!("the resolver rejects other assignment targets"),
+    };
+    Ok(Place { root: place, root_ty: ty(root), path })
+}
+
+// Reads the root of `place` and extracts the first `depth` fields of its
+// path, returning the value at each level, root first.
+fn load_place(place: &Place, depth: usize, b: &mut Builder) -> Vec<ValueId> {
+    let dst = b.func.fresh_value(place.root_ty.clone());
+    match &place.root {
+        Root::Var(name) => b.push(Inst::Load { dst, name: name.clone() }),
+        Root::Elem(addr) => b.push(Inst::LoadElem { dst, addr: *addr }),
+    }
+    let mut levels = vec![dst];
+    for &(field, access) in &place.path[..depth] {
+        let src = *levels.last().unwrap();
+        let dst = b.func.fresh_value(ty(access));
+        b.push(Inst::ExtractField { dst, src, field });
+        levels.push(dst);
+    }
+    levels
+}
+
+// Stores `src` to `place`. `p.a.b = src`: structs are values, so this inserts
+// `src` as field `b` of `p.a`, inserts the new `p.a` back into `p` and stores
+// the result to the root. `levels` are the structs along the path as read
+// by `load_place`, root first; it is unused when the path is empty.
+fn store_place(place: Place, levels: &[ValueId], src: ValueId, b: &mut Builder) {
+    let mut value = src;
+    for (&(field, _), &outer) in place.path.iter().zip(&levels[..place.path.len()]).rev() {
+        let dst = b.func.fresh_value(b.func.value_type(outer));
+        b.push(Inst::InsertField { dst, src: outer, field, value });
+        value = dst;
+    }
+    match place.root {
+        Root::Var(name) => b.push(Inst::Store { name, src: value }),
+        Root::Elem(addr) => b.push(Inst::StoreElem { addr, src: value }),
+    }
+}
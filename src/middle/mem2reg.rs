@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use crate::frontend::ast::Type;
+use crate::middle::cfg::{Cfg, Dominators};
+use crate::middle::ir::{BlockId, Function, Inst, ValueId};
+
+// Promote the variable slots of scalar type (ints, bools and floats) to SSA
+// values: every `Load` is replaced by the value last stored on the way to
+// it, and every `Store` disappears. Where stores from different paths meet
+// the slot becomes a block parameter, placed at the iterated dominance
+// frontier of the stores (Cytron et al.). Arrays and structs keep their
+// slots.
+pub fn mem2reg(function: &mut Function) {
+    let slots = promotable_slots(function);
+    if slots.is_empty() {
+        return;
+    }
+    let cfg = Cfg::new(function);
+    let doms = Dominators::new(&cfg);
+    let frontiers = doms.frontiers(&cfg);
+
+    // the block parameters added for each block, with the slot each stands for
+    let mut added: Vec<Vec<(String, ValueId)>> = vec![Vec::new(); function.blocks.len()];
+    let mut names: Vec<&String> = slots.keys().collect();
+    // a fixed order, so the same program always gets the same parameters
+    names.sort();
+    for name in names {
+        let mut work: Vec<BlockId> = (0..function.blocks.len())
+            .map(BlockId::from_usize)
+            .filter(|&b| function.block(b).insts.iter().any(|i| matches!(i, Inst::Store { name: n, .. } if n == name)))
+            .collect();
+        let mut has_param = vec![false; function.blocks.len()];
+        while let Some(b) = work.pop() {
+            for &f in &frontiers[b.get_usize()] {
+                if !has_param[f.get_usize()] {
+                    has_param[f.get_usize()] = true;
+                    let param = function.add_block_param(f, slots[name].clone());
+                    added[f.get_usize()].push((name.clone(), param));
+                    // the parameter is a new definition of the slot
+                    work.push(f);
+                }
+            }
+        }
+    }
+
+    let mut renamer = Renamer { slots, added, stacks: HashMap::new(), replaced: HashMap::new(), undefined: HashMap::new() };
+    renamer.rename(function, &doms);
+    // a loop's bounds are often variables, now replaced like any other read
+    for l in &mut function.loops {
+        for v in [&mut l.start, &mut l.end] {
+            if let Some(&r) = renamer.replaced.get(v) {
+                *v = r;
+            }
+        }
+    }
+    remove_unused_params(function);
+    renamer.define_undefined(function);
+}
+
+// Slots that are only read and written whole by Load and Store, which is all
+// of them, with their type; only those of a scalar type are promoted.
+fn promotable_slots(function: &Function) -> HashMap<String, Type> {
+    let mut slots = HashMap::new();
+    for block in &function.blocks {
+        for inst in &block.insts {
+            let (name, ty) = match inst {
+                Inst::Load { dst, name } => (name, function.value_type(*dst)),
+                Inst::Store { name, src } => (name, function.value_type(*src)),
+                _ => continue,
+            };
+            if ty.is_int() || ty.is_float() || ty == Type::Bool {
+                slots.insert(name.clone(), ty);
+            }
+        }
+    }
+    slots
+}
+
+struct Renamer {
+    slots: HashMap<String, Type>,
+    added: Vec<Vec<(String, ValueId)>>,
+    // the value each slot holds at the current point of the walk, innermost last
+    stacks: HashMap<String, Vec<ValueId>>,
+    // what each removed Load's result became
+    replaced: HashMap<ValueId, ValueId>,
+    // stands for a slot read on a path that never stored to it, which only
+    // happens in a parameter that turns out unused; see `define_undefined`
+    undefined: HashMap<String, ValueId>,
+}
+
+impl Renamer {
+    // Walk the dominator tree from the entry, so every block is visited
+    // after all the stores that can reach it without passing a parameter.
+    fn rename(&mut self, function: &mut Function, doms: &Dominators) {
+        let children = doms.children();
+        // (block, whether it is being left) on an explicit stack, so deep
+        // nesting cannot overflow the native stack
+        let mut walk = vec![(function.entry(), false)];
+        // the slots each open block pushed a value for, to pop on leaving it
+        let mut pushed: Vec<Vec<String>> = vec![Vec::new(); function.blocks.len()];
+        while let Some((block, leaving)) = walk.pop() {
+            if leaving {
+                for name in &pushed[block.get_usize()] {
+                    self.stacks.get_mut(name).expect("pushed").pop();
+                }
+                continue;
+            }
+            pushed[block.get_usize()] = self.rename_block(function, block);
+            walk.push((block, true));
+            for &child in children[block.get_usize()].iter().rev() {
+                walk.push((child, false));
+            }
+        }
+    }
+
+    // Rewrites one block, returning the slots it pushed a value for.
+    fn rename_block(&mut self, function: &mut Function, block: BlockId) -> Vec<String> {
+        let mut pushed = Vec::new();
+        for (name, param) in &self.added[block.get_usize()] {
+            self.stacks.entry(name.clone()).or_default().push(*param);
+            pushed.push(name.clone());
+        }
+        let insts = std::mem::take(&mut function.block_mut(block).insts);
+        let mut kept = Vec::new();
+        for mut inst in insts {
+            for v in inst.operands_mut() {
+                if let Some(&r) = self.replaced.get(v) {
+                    *v = r;
+                }
+            }
+            match &mut inst {
+                Inst::Load { dst, name } if self.slots.contains_key(name) => {
+                    let value = self.current(function, name);
+                    self.replaced.insert(*dst, value);
+                    continue;
+                }
+                Inst::Store { name, src } if self.slots.contains_key(name) => {
+                    self.stacks.entry(name.clone()).or_default().push(*src);
+                    pushed.push(name.clone());
+                    continue;
+                }
+                // the arguments for the parameters added to the targets
+                Inst::Br { target, args } => self.pass_args(function, *target, args),
+                Inst::CondBr { then_block, then_args, else_block, else_args, .. } => {
+                    self.pass_args(function, *then_block, then_args);
+                    self.pass_args(function, *else_block, else_args);
+                }
+                _ => {}
+            }
+            kept.push(inst);
+        }
+        function.block_mut(block).insts = kept;
+        pushed
+    }
+
+    fn pass_args(&mut self, function: &mut Function, target: BlockId, args: &mut Vec<ValueId>) {
+        let names: Vec<String> = self.added[target.get_usize()].iter().map(|(name, _)| name.clone()).collect();
+        for name in names {
+            args.push(self.current(function, &name));
+        }
+    }
+
+    fn current(&mut self, function: &mut Function, name: &str) -> ValueId {
+        if let Some(&v) = self.stacks.get(name).and_then(|s| s.last()) {
+            return v;
+        }
+        let ty = self.slots[name].clone();
+        *self.undefined.entry(name.to_string()).or_insert_with(|| function.fresh_value(ty))
+    }
+
+    // A parameter added where a slot's live range does not actually reach
+    // can get an argument from a path that never stored to the slot. The
+    // unused ones are gone by now; any undefined value still used starts
+    // out as zero, defined at the top of the entry block.
+    fn define_undefined(&self, function: &mut Function) {
+        let mut used = vec![false; function.value_count()];
+        for inst in function.blocks.iter().flat_map(|b| &b.insts) {
+            for v in inst.operands() {
+                used[v.get_usize()] = true;
+            }
+        }
+        let mut zeros = Vec::new();
+        for &dst in self.undefined.values() {
+            if !used[dst.get_usize()] {
+                continue;
+            }
+            zeros.push(match function.value_type(dst) {
+                Type::Bool => Inst::Boolean { dst, value: false },
+                ty if ty.is_float() => Inst::FConst { dst, value: 0.0 },
+                _ => Inst::Const { dst, value: 0 },
+            });
+        }
+        let entry = function.entry();
+        function.block_mut(entry).insts.splice(0..0, zeros);
+    }
+}
+
+// Drops the block parameters (other than the entry's, the function's
+// arguments) whose value never reaches anything but other block parameters,
+// with the matching branch arguments. Stores to a variable that is no longer
+// read, such as a loop counter after its loop, leave chains of those behind.
+fn remove_unused_params(function: &mut Function) {
+    // the arguments passed to each parameter, from every branch
+    let mut incoming: HashMap<ValueId, Vec<ValueId>> = HashMap::new();
+    let mut live: Vec<ValueId> = Vec::new();
+    for inst in function.blocks.iter().flat_map(|b| &b.insts) {
+        if let Inst::CondBr { cond, .. } = inst {
+            live.push(*cond);
+        }
+        if inst.is_terminator() {
+            for (target, args) in inst.edges() {
+                for (param, arg) in function.block(target).params.iter().zip(args) {
+                    incoming.entry(*param).or_default().push(*arg);
+                }
+            }
+            if let Inst::Return { src } = inst {
+                live.push(*src);
+            }
+            continue;
+        }
+        live.extend(inst.operands());
+    }
+
+    // a parameter is needed if its value is read, directly or through the
+    // parameters it is passed on to
+    let mut needed = vec![false; function.value_count()];
+    while let Some(v) = live.pop() {
+        if needed[v.get_usize()] {
+            continue;
+        }
+        needed[v.get_usize()] = true;
+        live.extend(incoming.get(&v).into_iter().flatten());
+    }
+
+    for b in 1..function.blocks.len() {
+        let keep: Vec<bool> = function.blocks[b].params.iter().map(|p| needed[p.get_usize()]).collect();
+        if keep.iter().all(|&k| k) {
+            continue;
+        }
+        let target = BlockId::from_usize(b);
+        let retain = |args: &mut Vec<ValueId>| {
+            let mut i = 0;
+            args.retain(|_| { i += 1; keep[i - 1] });
+        };
+        function.blocks[b].params.retain(|p| needed[p.get_usize()]);
+        for block in &mut function.blocks {
+            match block.insts.last_mut() {
+                Some(Inst::Br { target: t, args }) if *t == target => retain(args),
+                Some(Inst::CondBr { then_block, then_args, else_block, else_args, .. }) => {
+                    if *then_block == target {
+                        retain(then_args);
+                    }
+                    if *else_block == target {
+                        retain(else_args);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -1,3 +1,7 @@
 pub mod ir;
+pub mod cfg;
 pub mod lower;
-pub mod opt;
\ No newline at end of file
+pub mod mem2reg;
+pub mod opt;
+pub mod text;
+pub mod verify;
@@ -1,124 +1,334 @@
-use crate::middle::ir::{Module, Function, Inst};
+use crate::frontend::ast::Type;
+use crate::middle::cfg::Cfg;
+use crate::middle::mem2reg::mem2reg;
+use crate::middle::ir::{debug_verify, BlockId, Module, Function, Inst, Overflow, ValueId};
 use std::collections::HashMap;
 
 pub fn optimize_module(module: &mut Module)-> &mut Module {
 
+    let overflow = module.overflow;
+    // first, so folding sees through variables
     for func in module.functions.iter_mut(){
-        constant_folding(func);
+        mem2reg(func);
     }
+    debug_verify(module, "mem2reg");
+    for func in module.functions.iter_mut(){
+        constant_folding(func, overflow);
+    }
+    debug_verify(module, "constant folding");
     module
 }
 
-pub fn constant_folding(function: &mut Function){
+// Round a folded float result the way the target type would. For f32, doing
+// the operation in f64 and rounding once gives the same result as doing it in
+// f32, as LLVM does at runtime, because f64 has more than twice the precision.
+fn round_to(function: &Function, dst: ValueId, value: f64) -> f64 {
+    match function.value_type(dst) {
+        Type::F32 => value as f32 as f64,
+        _ => value,
+    }
+}
+
+// An integer constant as a mathematical value (see `Inst::Const`).
+pub fn widen(ty: &Type, value: i64) -> i128 {
+    if *ty == Type::U64 { value as u64 as i128 } else { value as i128 }
+}
+
+// Wrap a mathematical value into `ty` two's complement style, giving the
+// representation `Inst::Const` uses. This is what LLVM's integer instructions do.
+pub fn wrap(ty: &Type, value: i128) -> i64 {
+    let bits = ty.int_bits().expect("integer type");
+    let modulus = 1i128 << bits;
+    let mut r = value.rem_euclid(modulus);
+    if ty.is_signed() && r >= modulus / 2 {
+        r -= modulus;
+    }
+    // a u64 above i64::MAX keeps its bit pattern
+    r as i64
+}
+
+// Fold an integer operation whose operands are known. `None` leaves the
+// instruction for runtime: an operand is unknown, or the operation traps
+// there (division by zero, or overflow in checked mode).
+fn fold_int(
+    function: &Function,
+    overflow: Overflow,
+    const_map: &HashMap<ValueId, i64>,
+    dst: ValueId,
+    lhs: ValueId,
+    rhs: ValueId,
+    op: fn(i128, i128) -> Option<i128>,
+) -> Option<i64> {
+    let ty = function.value_type(dst);
+    let exact = op(widen(&ty, *const_map.get(&lhs)?), widen(&ty, *const_map.get(&rhs)?))?;
+    let (min, max) = ty.int_range()?;
+    if overflow == Overflow::Trap && (exact < min || exact > max) {
+        return None;
+    }
+    Some(wrap(&ty, exact))
+}
+
+pub fn constant_folding(function: &mut Function, overflow: Overflow){
     let mut const_map = HashMap::new();
-    let mut new_body = Vec::new();
+    let mut float_map: HashMap<ValueId, f64> = HashMap::new();
+
+    // in reverse postorder a value is always seen before its uses; only
+    // block parameters fed through a loop's back edge are not known yet
+    let cfg = Cfg::new(function);
+    let mut visited = vec![false; function.blocks.len()];
+    for block in cfg.reverse_postorder() {
+        fold_params(function, &cfg, &visited, block, &mut const_map, &mut float_map);
+        let body = std::mem::take(&mut function.block_mut(block).insts);
+        let mut new_body = Vec::new();
+        fold_insts(function, overflow, &body, &mut const_map, &mut float_map, &mut new_body);
+        function.block_mut(block).insts = new_body;
+        visited[block.get_usize()] = true;
+    }
+    // a folded `CondBr` can leave blocks behind that nothing branches to
+    function.remove_unreachable_blocks();
+}
+
+// A parameter of `block` is known when every branch to it passes the same
+// known value, as `true and x` does once its `CondBr` is folded. A branch
+// from a block not visited yet is a loop's back edge and leaves it unknown.
+fn fold_params(
+    function: &Function,
+    cfg: &Cfg,
+    visited: &[bool],
+    block: BlockId,
+    const_map: &mut HashMap<ValueId, i64>,
+    float_map: &mut HashMap<ValueId, f64>,
+) {
+    let preds = cfg.predecessors(block);
+    if preds.iter().any(|p| !visited[p.get_usize()]) {
+        return;
+    }
+    // the arguments on every edge into `block` that is still there
+    let incoming: Vec<&[ValueId]> = preds
+        .iter()
+        .filter_map(|&p| function.terminator(p))
+        .flat_map(|term| term.edges())
+        .filter(|&(to, _)| to == block)
+        .map(|(_, args)| args)
+        .collect();
+    for (i, &param) in function.block(block).params.iter().enumerate() {
+        let mut args = incoming.iter().map(|args| args[i]);
+        let Some(first) = args.next() else { continue };
+        if let Some(&v) = const_map.get(&first) {
+            if args.all(|a| const_map.get(&a) == Some(&v)) {
+                const_map.insert(param, v);
+            }
+        } else if let Some(&v) = float_map.get(&first)
+            && args.all(|a| float_map.get(&a).map(|x| x.to_bits()) == Some(v.to_bits()))
+        {
+            float_map.insert(param, v);
+        }
+    }
+}
 
-    for expr in function.body.iter(){
-        println!("{:?}", expr);
+// Fold `insts` into `new_body`. Known ints and bools (as 0/1) are tracked in
+// `const_map`, known floats in `float_map`.
+fn fold_insts(
+    function: &Function,
+    overflow: Overflow,
+    insts: &[Inst],
+    const_map: &mut HashMap<ValueId, i64>,
+    float_map: &mut HashMap<ValueId, f64>,
+    new_body: &mut Vec<Inst>,
+) {
+    for expr in insts.iter(){
         match expr{
             Inst::Const {dst, value} =>{
                 const_map.insert(*dst, *value);
                 new_body.push(expr.clone());
             }
-            Inst::Add {dst, lhs, rhs} =>{
-                if let (Some(lv), Some(rv)) = (const_map.get(lhs), const_map.get(rhs)){
-                    let res = lv + rv;
-                    const_map.insert(*dst, res);
-                    new_body.push(Inst::Const {dst: *dst, value: res});
-                } else {
+            Inst::Add {dst, lhs, rhs} | Inst::Sub {dst, lhs, rhs}
+            | Inst::Mul {dst, lhs, rhs} | Inst::Div {dst, lhs, rhs} | Inst::Rem {dst, lhs, rhs}
+            | Inst::BitAnd {dst, lhs, rhs} | Inst::BitOr {dst, lhs, rhs} | Inst::BitXor {dst, lhs, rhs} => {
+                let op: fn(i128, i128) -> Option<i128> = match expr {
+                    Inst::Add { .. } => |a, b| Some(a + b),
+                    Inst::Sub { .. } => |a, b| Some(a - b),
+                    // only a u64 product can leave i128, and it overflows u64 too
+                    Inst::Mul { .. } => |a, b| a.checked_mul(b),
+                    // avoid folding division by zero at compile time; it traps at runtime
+                    Inst::Div { .. } => |a, b| if b == 0 { None } else { Some(a / b) },
+                    // i128 `%` truncates like the runtime one, and `MIN % -1` is 0
+                    Inst::Rem { .. } => |a, b| if b == 0 { None } else { Some(a % b) },
+                    // on the mathematical values these agree with the bitwise
+                    // ops on the two's complement representation
+                    Inst::BitAnd { .. } => |a, b| Some(a & b),
+                    Inst::BitOr { .. } => |a, b| Some(a | b),
+                    _ => |a, b| Some(a ^ b),
+                };
+                match fold_int(function, overflow, const_map, *dst, *lhs, *rhs, op) {
+                    Some(res) => {
+                        const_map.insert(*dst, res);
+                        new_body.push(Inst::Const {dst: *dst, value: res});
+                    }
                     // cannot fold: keep original instruction
-                    new_body.push(expr.clone());
+                    None => new_body.push(expr.clone()),
                 }
             }
-            Inst::Sub {dst, lhs, rhs} =>{
-                if let (Some(lv), Some(rv)) = (const_map.get(lhs), const_map.get(rhs)){
-                    let res = lv - rv;
-                    const_map.insert(*dst, res);
-                    new_body.push(Inst::Const {dst: *dst, value: res});
+            Inst::Store { .. } | Inst::Load { .. } => {
+                // what a slot holds is not tracked; mem2reg has already
+                // removed the loads and stores of scalar variables
+                new_body.push(expr.clone());
+            }
+            Inst::Boolean {dst, value} => {
+                const_map.insert(*dst, *value as i64);
+                new_body.push(expr.clone());
+            },
+            Inst::Not { dst, src } => {
+                if let Some(&v) = const_map.get(src) {
+                    let res = v == 0;
+                    const_map.insert(*dst, res as i64);
+                    new_body.push(Inst::Boolean { dst: *dst, value: res });
                 } else {
                     new_body.push(expr.clone());
                 }
             }
-            Inst::Mul { dst, lhs, rhs } => {
-                if let (Some(lv), Some(rv)) = (const_map.get(lhs), const_map.get(rhs)) {
-                    let res = lv * rv;
+            Inst::Shl {dst, lhs, rhs} | Inst::Shr {dst, lhs, rhs} => {
+                let ty = function.value_type(*dst);
+                let bits = ty.int_bits().expect("integer type") as i128;
+                let folded = match (const_map.get(lhs), const_map.get(rhs)) {
+                    (Some(&lv), Some(&rv)) => {
+                        let amount = widen(&ty, rv);
+                        // an out-of-range amount traps at runtime in checked mode
+                        if overflow == Overflow::Trap && !(0..bits).contains(&amount) {
+                            None
+                        } else {
+                            let amount = amount.rem_euclid(bits) as u32;
+                            // bits shifted out are dropped, not an overflow;
+                            // `>>` of the widened value is arithmetic for
+                            // signed types and logical for unsigned ones
+                            let res = match expr {
+                                Inst::Shl { .. } => widen(&ty, lv) << amount,
+                                _ => widen(&ty, lv) >> amount,
+                            };
+                            Some(wrap(&ty, res))
+                        }
+                    }
+                    _ => None,
+                };
+                match folded {
+                    Some(res) => {
+                        const_map.insert(*dst, res);
+                        new_body.push(Inst::Const {dst: *dst, value: res});
+                    }
+                    None => new_body.push(expr.clone()),
+                }
+            }
+            Inst::BitNot {dst, src} => {
+                if let Some(&v) = const_map.get(src) {
+                    let ty = function.value_type(*dst);
+                    let res = wrap(&ty, !widen(&ty, v));
                     const_map.insert(*dst, res);
-                    new_body.push(Inst::Const { dst: *dst, value: res });
+                    new_body.push(Inst::Const {dst: *dst, value: res});
                 } else {
                     new_body.push(expr.clone());
                 }
             }
-
-            Inst::Div { dst, lhs, rhs } => {
-                if let (Some(lv), Some(rv)) = (const_map.get(lhs), const_map.get(rhs)) {
-                    // avoid folding division by zero at compile time
-                    if *rv != 0 {
-                        let res = lv / rv;
-                        const_map.insert(*dst, res);
-                        new_body.push(Inst::Const { dst: *dst, value: res });
-                    } else {
-                        new_body.push(expr.clone());
-                    }
+            Inst::Greater {dst, lhs, rhs} | Inst::Less {dst, lhs, rhs} | Inst::Equal {dst, lhs, rhs}
+            | Inst::GreaterEq {dst, lhs, rhs} | Inst::LessEq {dst, lhs, rhs} | Inst::NotEqual {dst, lhs, rhs} => {
+                if let (Some(&lv), Some(&rv)) = (const_map.get(lhs), const_map.get(rhs)){
+                    let ty = function.value_type(*lhs);
+                    let (lv, rv) = (widen(&ty, lv), widen(&ty, rv));
+                    let res = match expr {
+                        Inst::Greater { .. } => lv > rv,
+                        Inst::Less { .. } => lv < rv,
+                        Inst::Equal { .. } => lv == rv,
+                        Inst::GreaterEq { .. } => lv >= rv,
+                        Inst::LessEq { .. } => lv <= rv,
+                        _ => lv != rv,
+                    };
+                    const_map.insert(*dst, if res {1} else {0});
+                    new_body.push(Inst::Boolean {dst: *dst, value: res});
                 } else {
                     new_body.push(expr.clone());
                 }
-            },
-            Inst::Store {name, src} => {
-                new_body.push(expr.clone());
-            },
-            Inst::Load {dst, name} => {
-                new_body.push(expr.clone());
-            },
-            Inst::Return{src} => {
-                new_body.push(expr.clone());
-            },
-            Inst::Boolean {dst, value} => {
-                //currently do not support constant folding for bool expr
+            }
+            Inst::FConst { dst, value } => {
+                float_map.insert(*dst, round_to(function, *dst, *value));
                 new_body.push(expr.clone());
-            },
-            Inst::Greater {dst, lhs, rhs} => {
-                if let (Some(lv), Some(rv)) = (const_map.get(lhs), const_map.get(rhs)){
-                    let res = lv > rv;
-                    const_map.insert(*dst, if res {1} else {0});
-                    new_body.push(Inst::Boolean {dst: *dst, value: res});
+            }
+            Inst::FAdd { dst, lhs, rhs } | Inst::FSub { dst, lhs, rhs }
+            | Inst::FMul { dst, lhs, rhs } | Inst::FDiv { dst, lhs, rhs } | Inst::FRem { dst, lhs, rhs } => {
+                if let (Some(lv), Some(rv)) = (float_map.get(lhs), float_map.get(rhs)) {
+                    // IEEE semantics: dividing by zero gives an infinity or NaN, just like at runtime
+                    let res = match expr {
+                        Inst::FAdd { .. } => lv + rv,
+                        Inst::FSub { .. } => lv - rv,
+                        Inst::FMul { .. } => lv * rv,
+                        Inst::FDiv { .. } => lv / rv,
+                        // Rust's float `%` is `fmod`, as is LLVM's `frem`
+                        _ => lv % rv,
+                    };
+                    let res = round_to(function, *dst, res);
+                    float_map.insert(*dst, res);
+                    new_body.push(Inst::FConst { dst: *dst, value: res });
                 } else {
                     new_body.push(expr.clone());
                 }
-            },
-            Inst::Less {dst, lhs, rhs} => {
-                if let (Some(lv), Some(rv)) = (const_map.get(lhs), const_map.get(rhs)){
-                    let res = lv < rv;
-                    const_map.insert(*dst, if res {1} else {0});
-                    new_body.push(Inst::Boolean {dst: *dst, value: res});
+            }
+            Inst::FNeg { dst, src } => {
+                if let Some(&v) = float_map.get(src) {
+                    float_map.insert(*dst, -v);
+                    new_body.push(Inst::FConst { dst: *dst, value: -v });
                 } else {
                     new_body.push(expr.clone());
                 }
-            },
-            Inst::Equal {dst, lhs, rhs} => {
-                if let (Some(lv), Some(rv)) = (const_map.get(lhs), const_map.get(rhs)){
-                    let res = lv == rv;
+            }
+            Inst::FGreater { dst, lhs, rhs } | Inst::FLess { dst, lhs, rhs } | Inst::FEqual { dst, lhs, rhs }
+            | Inst::FGreaterEq { dst, lhs, rhs } | Inst::FLessEq { dst, lhs, rhs } | Inst::FNotEqual { dst, lhs, rhs } => {
+                if let (Some(lv), Some(rv)) = (float_map.get(lhs), float_map.get(rhs)) {
+                    // Rust's comparisons match the backend's: anything
+                    // involving NaN is false, except `!=`
+                    let res = match expr {
+                        Inst::FGreater { .. } => lv > rv,
+                        Inst::FLess { .. } => lv < rv,
+                        Inst::FEqual { .. } => lv == rv,
+                        Inst::FGreaterEq { .. } => lv >= rv,
+                        Inst::FLessEq { .. } => lv <= rv,
+                        _ => lv != rv,
+                    };
                     const_map.insert(*dst, if res {1} else {0});
-                    new_body.push(Inst::Boolean {dst: *dst, value: res});
+                    new_body.push(Inst::Boolean { dst: *dst, value: res });
                 } else {
                     new_body.push(expr.clone());
                 }
             }
-            Inst::Conditional { .. } => {
-                // preserve conditional branches as-is; nested optimization happens elsewhere
+            Inst::Cast { .. } => {
+                new_body.push(expr.clone());
+            }
+            Inst::CondBr { cond, then_block, then_args, else_block, else_args } => {
+                // a known condition (`true and x`, `if false: ...`) picks its
+                // branch now; the other side may become unreachable
+                match const_map.get(cond) {
+                    Some(&c) if c != 0 => new_body.push(Inst::Br { target: *then_block, args: then_args.clone() }),
+                    Some(_) => new_body.push(Inst::Br { target: *else_block, args: else_args.clone() }),
+                    None => new_body.push(expr.clone()),
+                }
+            }
+            Inst::Br { .. } | Inst::Unreachable => {
                 new_body.push(expr.clone());
             }
             Inst::Call { .. } => {
                 // preserve function calls as-is
                 new_body.push(expr.clone());
             }
+            Inst::ArrayNew { .. } | Inst::ArrayLen { .. } | Inst::CheckIndex { .. } | Inst::ElemAddr { .. }
+            | Inst::LoadElem { .. } | Inst::StoreElem { .. } => {
+                // array contents live in memory and are not tracked
+                new_body.push(expr.clone());
+            }
+            Inst::StructNew { .. } | Inst::ExtractField { .. } | Inst::InsertField { .. } => {
+                // struct fields are not tracked, so nothing is folded through them
+                new_body.push(expr.clone());
+            }
             Inst::Return { .. } => {
                 // preserve return as-is
                 new_body.push(expr.clone());
             }
-            _ => todo!()
-
-
         }
     }
-    function.body = new_body; 
 }
\ No newline at end of file
@@ -0,0 +1,788 @@
+use std::collections::HashMap;
+use std::fmt;
+use logos::Logos;
+use crate::diagnostic::{codes, Diagnostic};
+use crate::frontend::ast::Type;
+use crate::frontend::span::{Span, Spanned};
+use crate::middle::ir::{Block, BlockId, CountedLoop, Function, Inst, Module, Overflow, StructDef, ValueId};
+
+// The textual form of the IR, for reading dumps and for writing IR test
+// cases by hand (`tests/*.ir`). `parse_module` reads back exactly what the
+// `Display` impls print:
+//
+//     struct Pair { a: i64, b: bool }
+//
+//     fn count(n.1: i64) -> i64 {
+//     loop header=b1 iv=%v2 start=%v1 end=%v3
+//     b0(%v0: i64):
+//       store n.1, %v0
+//       %v1: i64 = const 0
+//       %v3: i64 = load n.1
+//       br b1(%v1)
+//     b1(%v2: i64):
+//       %v4: bool = lt %v2, %v3
+//       cond_br %v4, b2, b3
+//       ...
+//     }
+//
+// Every value an instruction defines is written with its type. The
+// function's recorded `for` loops (`Function.loops`) come before its blocks,
+// one `loop` line each. Module settings other than the defaults come first,
+// as `overflow trap` and `bounds_checks off`. Spans are not part of the
+// text; instead `parse_module` gives a `SourceMap` of where each block,
+// instruction and loop was written, which `verify_parsed` points its errors
+// at. `#` starts a comment.
+//
+// When parsing, values must be named `%v<number>` and keep that number;
+// blocks may have any name and are numbered in the order they appear, the
+// first being the entry. The parser only checks the syntax, the types and
+// that every branch target and loop header exists, not where values are
+// defined or whether a loop line is right about its loop.
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut header = false;
+        if self.overflow == Overflow::Trap {
+            writeln!(f, "overflow trap")?;
+            header = true;
+        }
+        if !self.bounds_checks {
+            writeln!(f, "bounds_checks off")?;
+            header = true;
+        }
+        for def in &self.structs {
+            let fields: Vec<String> = def.fields.iter().map(|(name, ty)| format!("{name}: {ty}")).collect();
+            writeln!(f, "struct {} {{ {} }}", def.name, fields.join(", "))?;
+            header = true;
+        }
+        for (i, func) in self.functions.iter().enumerate() {
+            if header || i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{func}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params: Vec<String> = self.params.iter().map(|(name, ty)| format!("{name}: {ty}")).collect();
+        writeln!(f, "fn {}({}) -> {} {{", self.name, params.join(", "), self.ret)?;
+        for l in &self.loops {
+            writeln!(
+                f,
+                "loop header=b{} iv=%v{} start=%v{} end=%v{}",
+                l.header.get_usize(),
+                l.counter.get_usize(),
+                l.start.get_usize(),
+                l.end.get_usize()
+            )?;
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            if block.params.is_empty() {
+                writeln!(f, "b{i}:")?;
+            } else {
+                let params: Vec<String> = block.params.iter().map(|&p| format!("%v{}: {}", p.get_usize(), self.value_type(p))).collect();
+                writeln!(f, "b{i}({}):", params.join(", "))?;
+            }
+            for inst in &block.insts {
+                writeln!(f, "  {}", self.display_inst(inst))?;
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl Function {
+    // One instruction in the textual form, with the type of what it defines.
+    pub fn display_inst(&self, inst: &Inst) -> String {
+        let v = |id: &ValueId| format!("%v{}", id.get_usize());
+        let list = |ids: &[ValueId]| ids.iter().map(v).collect::<Vec<_>>().join(", ");
+        let target = |block: &BlockId, args: &[ValueId]| {
+            if args.is_empty() {
+                format!("b{}", block.get_usize())
+            } else {
+                format!("b{}({})", block.get_usize(), list(args))
+            }
+        };
+        let def = |dst: &ValueId, rest: String| format!("{}: {} = {}", v(dst), self.value_type(*dst), rest);
+        if let Some((name, dst, lhs, rhs)) = binary_parts(inst) {
+            return def(dst, format!("{name} {}, {}", v(lhs), v(rhs)));
+        }
+        match inst {
+            Inst::Const { dst, value } => {
+                // a u64 is stored as its bit pattern but written as the number
+                let value = if self.value_type(*dst) == Type::U64 { (*value as u64).to_string() } else { value.to_string() };
+                def(dst, format!("const {value}"))
+            }
+            Inst::Boolean { dst, value } => def(dst, format!("const {value}")),
+            Inst::FConst { dst, value } => def(dst, format!("const {value:?}")),
+            Inst::Cast { dst, src } => def(dst, format!("cast {}", v(src))),
+            Inst::BitNot { dst, src } => def(dst, format!("bitnot {}", v(src))),
+            Inst::Not { dst, src } => def(dst, format!("not {}", v(src))),
+            Inst::FNeg { dst, src } => def(dst, format!("fneg {}", v(src))),
+            Inst::Call { dst, callee, args } => def(dst, format!("call {callee}({})", list(args))),
+            Inst::ArrayNew { dst, dims } => def(dst, format!("array_new {}", list(dims))),
+            Inst::ArrayLen { dst, array, dim } => def(dst, format!("array_len {}, {dim}", v(array))),
+            Inst::CheckIndex { index, len } => format!("check_index {}, {}", v(index), v(len)),
+            Inst::ElemAddr { dst, array, index } => def(dst, format!("elem_addr {}, {}", v(array), v(index))),
+            Inst::LoadElem { dst, addr } => def(dst, format!("load_elem {}", v(addr))),
+            Inst::StoreElem { addr, src } => format!("store_elem {}, {}", v(addr), v(src)),
+            Inst::StructNew { dst, fields } => def(dst, format!("struct_new {}", list(fields))),
+            Inst::ExtractField { dst, src, field } => def(dst, format!("extract_field {}, {field}", v(src))),
+            Inst::InsertField { dst, src, field, value } => def(dst, format!("insert_field {}, {field}, {}", v(src), v(value))),
+            Inst::Load { dst, name } => def(dst, format!("load {name}")),
+            Inst::Store { name, src } => format!("store {name}, {}", v(src)),
+            Inst::Br { target: t, args } => format!("br {}", target(t, args)),
+            Inst::CondBr { cond, then_block, then_args, else_block, else_args } => {
+                format!("cond_br {}, {}, {}", v(cond), target(then_block, then_args), target(else_block, else_args))
+            }
+            Inst::Return { src } => format!("ret {}", v(src)),
+            Inst::Unreachable => "unreachable".to_string(),
+            _ => unreachable!("binary instructions are handled above"),
+        }
+    }
+}
+
+// Builds a binary instruction from its `dst`, `lhs` and `rhs`.
+type MakeBinary = fn(ValueId, ValueId, ValueId) -> Inst;
+
+// The instructions of the form `%d: T = op %lhs, %rhs`, by name.
+const BINARY: &[(&str, MakeBinary)] = &[
+    ("add", |dst, lhs, rhs| Inst::Add { dst, lhs, rhs }),
+    ("sub", |dst, lhs, rhs| Inst::Sub { dst, lhs, rhs }),
+    ("mul", |dst, lhs, rhs| Inst::Mul { dst, lhs, rhs }),
+    ("div", |dst, lhs, rhs| Inst::Div { dst, lhs, rhs }),
+    ("rem", |dst, lhs, rhs| Inst::Rem { dst, lhs, rhs }),
+    ("gt", |dst, lhs, rhs| Inst::Greater { dst, lhs, rhs }),
+    ("lt", |dst, lhs, rhs| Inst::Less { dst, lhs, rhs }),
+    ("eq", |dst, lhs, rhs| Inst::Equal { dst, lhs, rhs }),
+    ("ge", |dst, lhs, rhs| Inst::GreaterEq { dst, lhs, rhs }),
+    ("le", |dst, lhs, rhs| Inst::LessEq { dst, lhs, rhs }),
+    ("ne", |dst, lhs, rhs| Inst::NotEqual { dst, lhs, rhs }),
+    ("bitand", |dst, lhs, rhs| Inst::BitAnd { dst, lhs, rhs }),
+    ("bitor", |dst, lhs, rhs| Inst::BitOr { dst, lhs, rhs }),
+    ("bitxor", |dst, lhs, rhs| Inst::BitXor { dst, lhs, rhs }),
+    ("shl", |dst, lhs, rhs| Inst::Shl { dst, lhs, rhs }),
+    ("shr", |dst, lhs, rhs| Inst::Shr { dst, lhs, rhs }),
+    ("fadd", |dst, lhs, rhs| Inst::FAdd { dst, lhs, rhs }),
+    ("fsub", |dst, lhs, rhs| Inst::FSub { dst, lhs, rhs }),
+    ("fmul", |dst, lhs, rhs| Inst::FMul { dst, lhs, rhs }),
+    ("fdiv", |dst, lhs, rhs| Inst::FDiv { dst, lhs, rhs }),
+    ("frem", |dst, lhs, rhs| Inst::FRem { dst, lhs, rhs }),
+    ("fgt", |dst, lhs, rhs| Inst::FGreater { dst, lhs, rhs }),
+    ("flt", |dst, lhs, rhs| Inst::FLess { dst, lhs, rhs }),
+    ("feq", |dst, lhs, rhs| Inst::FEqual { dst, lhs, rhs }),
+    ("fge", |dst, lhs, rhs| Inst::FGreaterEq { dst, lhs, rhs }),
+    ("fle", |dst, lhs, rhs| Inst::FLessEq { dst, lhs, rhs }),
+    ("fne", |dst, lhs, rhs| Inst::FNotEqual { dst, lhs, rhs }),
+];
+
+fn binary_parts(inst: &Inst) -> Option<(&'static str, &ValueId, &ValueId, &ValueId)> {
+    let (name, dst, lhs, rhs) = match inst {
+        Inst::Add { dst, lhs, rhs } => ("add", dst, lhs, rhs),
+        Inst::Sub { dst, lhs, rhs } => ("sub", dst, lhs, rhs),
+        Inst::Mul { dst, lhs, rhs } => ("mul", dst, lhs, rhs),
+        Inst::Div { dst, lhs, rhs } => ("div", dst, lhs, rhs),
+        Inst::Rem { dst, lhs, rhs } => ("rem", dst, lhs, rhs),
+        Inst::Greater { dst, lhs, rhs } => ("gt", dst, lhs, rhs),
+        Inst::Less { dst, lhs, rhs } => ("lt", dst, lhs, rhs),
+        Inst::Equal { dst, lhs, rhs } => ("eq", dst, lhs, rhs),
+        Inst::GreaterEq { dst, lhs, rhs } => ("ge", dst, lhs, rhs),
+        Inst::LessEq { dst, lhs, rhs } => ("le", dst, lhs, rhs),
+        Inst::NotEqual { dst, lhs, rhs } => ("ne", dst, lhs, rhs),
+        Inst::BitAnd { dst, lhs, rhs } => ("bitand", dst, lhs, rhs),
+        Inst::BitOr { dst, lhs, rhs } => ("bitor", dst, lhs, rhs),
+        Inst::BitXor { dst, lhs, rhs } => ("bitxor", dst, lhs, rhs),
+        Inst::Shl { dst, lhs, rhs } => ("shl", dst, lhs, rhs),
+        Inst::Shr { dst, lhs, rhs } => ("shr", dst, lhs, rhs),
+        Inst::FAdd { dst, lhs, rhs } => ("fadd", dst, lhs, rhs),
+        Inst::FSub { dst, lhs, rhs } => ("fsub", dst, lhs, rhs),
+        Inst::FMul { dst, lhs, rhs } => ("fmul", dst, lhs, rhs),
+        Inst::FDiv { dst, lhs, rhs } => ("fdiv", dst, lhs, rhs),
+        Inst::FRem { dst, lhs, rhs } => ("frem", dst, lhs, rhs),
+        Inst::FGreater { dst, lhs, rhs } => ("fgt", dst, lhs, rhs),
+        Inst::FLess { dst, lhs, rhs } => ("flt", dst, lhs, rhs),
+        Inst::FEqual { dst, lhs, rhs } => ("feq", dst, lhs, rhs),
+        Inst::FGreaterEq { dst, lhs, rhs } => ("fge", dst, lhs, rhs),
+        Inst::FLessEq { dst, lhs, rhs } => ("fle", dst, lhs, rhs),
+        Inst::FNotEqual { dst, lhs, rhs } => ("fne", dst, lhs, rhs),
+        _ => return None,
+    };
+    Some((name, dst, lhs, rhs))
+}
+
+#[derive(Logos, Clone, Debug, PartialEq)]
+#[logos(skip r"[ \t\r\n]+")]
+#[logos(skip r"#[^\n]*")]
+enum Tok {
+    #[regex("%v[0-9]+", |lex| lex.slice()[2..].parse::<u32>().ok())]
+    Value(u32),
+    // wide enough for every i64 and u64 constant
+    #[regex("-?[0-9]+", |lex| lex.slice().parse::<i128>().ok())]
+    Int(i128),
+    // as `{:?}` prints an f64. `inf` and `NaN` could be names, so they are
+    // identifiers until `Parser::constant` reads them as floats
+    #[regex(r"-?([0-9]+\.[0-9]+([eE][+-]?[0-9]+)?|[0-9]+[eE][+-]?[0-9]+)", |lex| lex.slice().parse::<f64>().ok())]
+    #[token("-inf", |_| f64::NEG_INFINITY)]
+    Float(f64),
+    // slot names like `x.3` contain a dot
+    #[regex("[A-Za-z_][A-Za-z0-9_.]*", |lex| lex.slice().to_string())]
+    Ident(String),
+    #[token("(")]
+    LParen,
+    #[token(")")]
+    RParen,
+    #[token("[")]
+    LBracket,
+    #[token("]")]
+    RBracket,
+    #[token("{")]
+    LBrace,
+    #[token("}")]
+    RBrace,
+    #[token(",")]
+    Comma,
+    #[token(":")]
+    Colon,
+    #[token("=")]
+    Equals,
+    #[token("->")]
+    Arrow,
+}
+
+/// Parse a module in the textual IR form. Stops at the first error.
+/// Where the parts of a parsed module were written, for `verify_parsed` to
+/// point its errors at. Functions are numbered in module order.
+#[derive(Default)]
+pub struct SourceMap {
+    // by function, block and position: 0 for the block's label, i + 1 for
+    // its instruction i
+    places: HashMap<(usize, BlockId, usize), Span>,
+    // by function and index in its `loops`
+    loops: HashMap<(usize, usize), Span>,
+}
+
+impl SourceMap {
+    pub fn place(&self, func: usize, block: BlockId, pos: usize) -> Option<Span> {
+        self.places.get(&(func, block, pos)).copied()
+    }
+
+    pub fn loop_line(&self, func: usize, index: usize) -> Option<Span> {
+        self.loops.get(&(func, index)).copied()
+    }
+}
+
+pub fn parse_module(text: &str) -> Result<(Module, SourceMap), Box<Diagnostic>> {
+    let mut tokens = Vec::new();
+    for (result, range) in Tok::lexer(text).spanned() {
+        let span = Span::new(range.start, range.end);
+        match result {
+            Ok(tok) => tokens.push((tok, span)),
+            Err(()) => {
+                return Err(Box::new(Diagnostic::error(format!("unexpected `{}`", text[range].escape_debug()))
+                    .with_code(codes::UNKNOWN_CHARACTER)
+                    .with_primary(span, "not valid in IR text")));
+            }
+        }
+    }
+    let end = Span::new(text.len(), text.len());
+    let mut p = Parser { text, tokens, pos: 0, end, module: Module::new(), map: SourceMap::default() };
+    while p.peek().is_some() {
+        let (word, span) = p.ident("`fn`, `struct` or a module setting")?;
+        match word.as_str() {
+            "overflow" => {
+                p.module.overflow = match p.ident("`wrap` or `trap`")?.0.as_str() {
+                    "wrap" => Overflow::Wrap,
+                    "trap" => Overflow::Trap,
+                    _ => return Err(p.error_at(p.prev_span(), "`wrap` or `trap`")),
+                };
+            }
+            "bounds_checks" => {
+                p.module.bounds_checks = match p.ident("`on` or `off`")?.0.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(p.error_at(p.prev_span(), "`on` or `off`")),
+                };
+            }
+            "struct" => {
+                let (name, _) = p.ident("a struct name")?;
+                p.expect(Tok::LBrace, "`{`")?;
+                let fields = p.named_list(Tok::RBrace)?;
+                p.module.structs.push(StructDef { name, fields });
+            }
+            "fn" => {
+                let func = p.function()?;
+                p.module.add_function(func);
+            }
+            _ => return Err(p.error_at(span, "`fn`, `struct` or a module setting")),
+        }
+    }
+    Ok((p.module, p.map))
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<Spanned<Tok>>,
+    pos: usize,
+    end: Span,
+    module: Module,
+    map: SourceMap,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos).map(|(tok, _)| tok)
+    }
+
+    fn peek_at(&self, n: usize) -> Option<&Tok> {
+        self.tokens.get(self.pos + n).map(|(tok, _)| tok)
+    }
+
+    fn span(&self) -> Span {
+        self.tokens.get(self.pos).map(|(_, span)| *span).unwrap_or(self.end)
+    }
+
+    fn prev_span(&self) -> Span {
+        self.tokens[self.pos - 1].1
+    }
+
+    fn next(&mut self) -> Option<Tok> {
+        let tok = self.tokens.get(self.pos).map(|(tok, _)| tok.clone());
+        self.pos += 1;
+        tok
+    }
+
+    fn error_at(&self, span: Span, expected: &str) -> Box<Diagnostic> {
+        let found = if span.start < self.text.len() { format!("`{}`", &self.text[span.start..span.end]) } else { "end of input".to_string() };
+        Box::new(Diagnostic::error(format!("expected {expected}, found {found}"))
+            .with_code(codes::EXPECTED_TOKEN)
+            .with_primary(span, format!("expected {expected}")))
+    }
+
+    fn error(&self, expected: &str) -> Box<Diagnostic> {
+        self.error_at(self.span(), expected)
+    }
+
+    fn eat(&mut self, tok: Tok) -> bool {
+        if self.peek() == Some(&tok) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, tok: Tok, what: &str) -> Result<(), Box<Diagnostic>> {
+        if self.eat(tok) { Ok(()) } else { Err(self.error(what)) }
+    }
+
+    // `key=`, as in a `loop` line
+    fn key(&mut self, key: &str) -> Result<(), Box<Diagnostic>> {
+        let what = format!("`{key}=`");
+        match self.peek() {
+            Some(Tok::Ident(name)) if name == key => self.pos += 1,
+            _ => return Err(self.error(&what)),
+        }
+        self.expect(Tok::Equals, &what)
+    }
+
+    fn ident(&mut self, what: &str) -> Result<(String, Span), Box<Diagnostic>> {
+        let span = self.span();
+        match self.peek() {
+            Some(Tok::Ident(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok((name, span))
+            }
+            _ => Err(self.error(what)),
+        }
+    }
+
+    fn value(&mut self) -> Result<ValueId, Box<Diagnostic>> {
+        match self.peek() {
+            Some(Tok::Value(n)) => {
+                let id = ValueId::from_usize(*n as usize);
+                self.pos += 1;
+                Ok(id)
+            }
+            _ => Err(self.error("a value like `%v1`")),
+        }
+    }
+
+    fn int(&mut self, what: &str) -> Result<i128, Box<Diagnostic>> {
+        match self.peek() {
+            Some(Tok::Int(n)) => {
+                let n = *n;
+                self.pos += 1;
+                Ok(n)
+            }
+            _ => Err(self.error(what)),
+        }
+    }
+
+    // `field`, `dim`: a small non-negative number
+    fn index(&mut self, what: &str) -> Result<u32, Box<Diagnostic>> {
+        let span = self.span();
+        let n = self.int(what)?;
+        u32::try_from(n).map_err(|_| self.error_at(span, what))
+    }
+
+    // `%v1, %v2, ...` up to (not including) `close`
+    fn values_until(&mut self, close: &Tok) -> Result<Vec<ValueId>, Box<Diagnostic>> {
+        let mut values = Vec::new();
+        while self.peek().is_some_and(|t| t != close) {
+            values.push(self.value()?);
+            if !self.eat(Tok::Comma) {
+                break;
+            }
+        }
+        Ok(values)
+    }
+
+    // `name: type, ...` then `close`, as in struct fields and function parameters
+    fn named_list(&mut self, close: Tok) -> Result<Vec<(String, Type)>, Box<Diagnostic>> {
+        let mut items = Vec::new();
+        while self.peek().is_some_and(|t| *t != close) {
+            let (name, _) = self.ident("a name")?;
+            self.expect(Tok::Colon, "`:`")?;
+            items.push((name, self.ty()?));
+            if !self.eat(Tok::Comma) {
+                break;
+            }
+        }
+        let what = if close == Tok::RBrace { "`}`" } else { "`)`" };
+        self.expect(close, what)?;
+        Ok(items)
+    }
+
+    fn ty(&mut self) -> Result<Type, Box<Diagnostic>> {
+        if self.eat(Tok::LBracket) {
+            let elem = self.ty()?;
+            let rank = if self.eat(Tok::Comma) { self.index("an array rank")? } else { 1 };
+            self.expect(Tok::RBracket, "`]`")?;
+            return Ok(Type::Array { elem: Box::new(elem), rank });
+        }
+        let (name, span) = self.ident("a type")?;
+        Ok(match name.as_str() {
+            "i8" => Type::I8,
+            "i16" => Type::I16,
+            "i32" => Type::I32,
+            "i64" => Type::I64,
+            "u8" => Type::U8,
+            "u16" => Type::U16,
+            "u32" => Type::U32,
+            "u64" => Type::U64,
+            "bool" => Type::Bool,
+            "f32" => Type::F32,
+            "f64" => Type::F64,
+            "unit" => Type::Unit,
+            "ptr" => Type::Ptr,
+            _ if self.module.structs.iter().any(|s| s.name == name) => Type::Struct(name),
+            _ => {
+                return Err(Box::new(Diagnostic::error(format!("unknown type `{name}`"))
+                    .with_code(codes::UNKNOWN_TYPE)
+                    .with_primary(span, "not a built-in type or a struct declared above")));
+            }
+        })
+    }
+
+    // a branch target: `label` or `label(%v1, ...)`
+    fn target(&mut self, labels: &mut Labels) -> Result<(BlockId, Vec<ValueId>), Box<Diagnostic>> {
+        let (name, span) = self.ident("a block name")?;
+        let block = labels.mention(name, span);
+        let args = if self.eat(Tok::LParen) {
+            let args = self.values_until(&Tok::RParen)?;
+            self.expect(Tok::RParen, "`)`")?;
+            args
+        } else {
+            Vec::new()
+        };
+        Ok((block, args))
+    }
+
+    fn function(&mut self) -> Result<Function, Box<Diagnostic>> {
+        let (name, _) = self.ident("a function name")?;
+        let mut func = Function::new(name);
+        self.expect(Tok::LParen, "`(`")?;
+        func.params = self.named_list(Tok::RParen)?;
+        self.expect(Tok::Arrow, "`->`")?;
+        func.ret = self.ty()?;
+        self.expect(Tok::LBrace, "`{`")?;
+        let index = self.module.functions.len();
+
+        let mut labels = Labels::default();
+        // `loop header=b1 iv=%v2 start=%v1 end=%v3` lines; a block may be
+        // called `loop` too, but its label is followed by `:` or `(`
+        while matches!(self.peek(), Some(Tok::Ident(w)) if w == "loop") && !matches!(self.peek_at(1), Some(Tok::Colon | Tok::LParen)) {
+            let start = self.span();
+            self.pos += 1;
+            self.key("header")?;
+            let (name, span) = self.ident("a block name")?;
+            let header = labels.mention(name, span);
+            self.key("iv")?;
+            let counter = self.value()?;
+            self.key("start")?;
+            let start_value = self.value()?;
+            self.key("end")?;
+            let end = self.value()?;
+            self.map.loops.insert((index, func.loops.len()), start.merge(self.prev_span()));
+            func.loops.push(CountedLoop { header, counter, start: start_value, end });
+        }
+
+        // the blocks by the id their label got, filled in as they are defined
+        let mut blocks: Vec<Option<Block>> = Vec::new();
+        // where each label and instruction was, by that id and position
+        let mut places = Vec::new();
+        while !self.eat(Tok::RBrace) {
+            let (label, span) = self.ident("a block name like `b0:`")?;
+            let id = labels.mention(label.clone(), span);
+            places.push((id, 0, span));
+            if labels.defined.contains(&id) {
+                return Err(Box::new(Diagnostic::error(format!("block `{label}` is defined twice"))
+                    .with_code(codes::UNEXPECTED_TOKEN)
+                    .with_primary(span, "already defined above")));
+            }
+            labels.defined.push(id);
+            let mut params = Vec::new();
+            if self.eat(Tok::LParen) {
+                while self.peek().is_some_and(|t| *t != Tok::RParen) {
+                    let param = self.value()?;
+                    self.expect(Tok::Colon, "`:`")?;
+                    func.set_value_type(param, self.ty()?);
+                    params.push(param);
+                    if !self.eat(Tok::Comma) {
+                        break;
+                    }
+                }
+                self.expect(Tok::RParen, "`)`")?;
+            }
+            self.expect(Tok::Colon, "`:`")?;
+
+            let mut insts = Vec::new();
+            // instructions run until the next label (a name followed by `:`
+            // or `(`) or the closing brace
+            loop {
+                let label_next = matches!(self.peek(), Some(Tok::Ident(_))) && matches!(self.peek_at(1), Some(Tok::Colon | Tok::LParen));
+                if label_next || matches!(self.peek(), Some(Tok::RBrace) | None) {
+                    break;
+                }
+                let start = self.span();
+                insts.push(self.inst(&mut func, &mut labels)?);
+                places.push((id, insts.len(), start.merge(self.prev_span())));
+            }
+            if blocks.len() <= id.get_usize() {
+                blocks.resize(id.get_usize() + 1, None);
+            }
+            blocks[id.get_usize()] = Some(Block { params, insts });
+        }
+
+        if let Some((name, span)) = labels.undefined() {
+            return Err(Box::new(Diagnostic::error(format!("no block named `{name}`"))
+                .with_code(codes::UNEXPECTED_TOKEN)
+                .with_primary(span, "refers to a block that is not defined")));
+        }
+        if labels.defined.is_empty() {
+            return Err(self.error_at(self.prev_span(), "at least one block"));
+        }
+        // number the blocks in the order they were written
+        let mut renumber = vec![BlockId::from_usize(0); blocks.len()];
+        for (new, old) in labels.defined.iter().enumerate() {
+            renumber[old.get_usize()] = BlockId::from_usize(new);
+        }
+        func.blocks.clear();
+        for old in &labels.defined {
+            let mut block = blocks[old.get_usize()].take().expect("every label is defined");
+            match block.insts.last_mut() {
+                Some(Inst::Br { target, .. }) => *target = renumber[target.get_usize()],
+                Some(Inst::CondBr { then_block, else_block, .. }) => {
+                    *then_block = renumber[then_block.get_usize()];
+                    *else_block = renumber[else_block.get_usize()];
+                }
+                _ => {}
+            }
+            func.blocks.push(block);
+        }
+        for l in &mut func.loops {
+            l.header = renumber[l.header.get_usize()];
+        }
+        for (old, pos, span) in places {
+            self.map.places.insert((index, renumber[old.get_usize()], pos), span);
+        }
+        Ok(func)
+    }
+
+    fn inst(&mut self, func: &mut Function, labels: &mut Labels) -> Result<Inst, Box<Diagnostic>> {
+        // `%v3: i64 = ` before anything that defines a value
+        let dst = if matches!(self.peek(), Some(Tok::Value(_))) {
+            let dst = self.value()?;
+            self.expect(Tok::Colon, "`:` and the value's type")?;
+            let ty = self.ty()?;
+            self.expect(Tok::Equals, "`=`")?;
+            func.set_value_type(dst, ty);
+            Some(dst)
+        } else {
+            None
+        };
+        let (op, op_span) = self.ident("an instruction")?;
+        // the instruction's own name decides whether it needs a `%v: T =`
+        let needs_dst = !matches!(op.as_str(), "check_index" | "store_elem" | "store" | "br" | "cond_br" | "ret" | "unreachable");
+        let dst = match (dst, needs_dst) {
+            (Some(dst), true) => dst,
+            (None, false) => ValueId::from_usize(0),
+            (None, true) => {
+                return Err(Box::new(Diagnostic::error(format!("`{op}` defines a value"))
+                    .with_code(codes::EXPECTED_TOKEN)
+                    .with_primary(op_span, "write it as `%v<n>: <type> = ...`")));
+            }
+            (Some(_), false) => {
+                return Err(Box::new(Diagnostic::error(format!("`{op}` does not define a value"))
+                    .with_code(codes::UNEXPECTED_TOKEN)
+                    .with_primary(op_span, "remove the `%v<n>: <type> =`")));
+            }
+        };
+
+        if let Some((_, make)) = BINARY.iter().find(|(name, _)| *name == op) {
+            let lhs = self.value()?;
+            self.expect(Tok::Comma, "`,`")?;
+            return Ok(make(dst, lhs, self.value()?));
+        }
+        Ok(match op.as_str() {
+            "const" => self.constant(func, dst)?,
+            "cast" => Inst::Cast { dst, src: self.value()? },
+            "bitnot" => Inst::BitNot { dst, src: self.value()? },
+            "not" => Inst::Not { dst, src: self.value()? },
+            "fneg" => Inst::FNeg { dst, src: self.value()? },
+            "call" => {
+                let (callee, _) = self.ident("a function name")?;
+                self.expect(Tok::LParen, "`(`")?;
+                let args = self.values_until(&Tok::RParen)?;
+                self.expect(Tok::RParen, "`)`")?;
+                Inst::Call { dst, callee, args }
+            }
+            "array_new" => Inst::ArrayNew { dst, dims: self.operands()? },
+            "array_len" => {
+                let array = self.value()?;
+                self.expect(Tok::Comma, "`,`")?;
+                Inst::ArrayLen { dst, array, dim: self.index("a dimension number")? }
+            }
+            "check_index" => {
+                let index = self.value()?;
+                self.expect(Tok::Comma, "`,`")?;
+                Inst::CheckIndex { index, len: self.value()? }
+            }
+            "elem_addr" => {
+                let array = self.value()?;
+                self.expect(Tok::Comma, "`,`")?;
+                Inst::ElemAddr { dst, array, index: self.value()? }
+            }
+            "load_elem" => Inst::LoadElem { dst, addr: self.value()? },
+            "store_elem" => {
+                let addr = self.value()?;
+                self.expect(Tok::Comma, "`,`")?;
+                Inst::StoreElem { addr, src: self.value()? }
+            }
+            "struct_new" => Inst::StructNew { dst, fields: self.operands()? },
+            "extract_field" => {
+                let src = self.value()?;
+                self.expect(Tok::Comma, "`,`")?;
+                Inst::ExtractField { dst, src, field: self.index("a field number")? }
+            }
+            "insert_field" => {
+                let src = self.value()?;
+                self.expect(Tok::Comma, "`,`")?;
+                let field = self.index("a field number")?;
+                self.expect(Tok::Comma, "`,`")?;
+                Inst::InsertField { dst, src, field, value: self.value()? }
+            }
+            "load" => Inst::Load { dst, name: self.ident("a variable name")?.0 },
+            "store" => {
+                let (name, _) = self.ident("a variable name")?;
+                self.expect(Tok::Comma, "`,`")?;
+                Inst::Store { name, src: self.value()? }
+            }
+            "br" => {
+                let (target, args) = self.target(labels)?;
+                Inst::Br { target, args }
+            }
+            "cond_br" => {
+                let cond = self.value()?;
+                self.expect(Tok::Comma, "`,`")?;
+                let (then_block, then_args) = self.target(labels)?;
+                self.expect(Tok::Comma, "`,`")?;
+                let (else_block, else_args) = self.target(labels)?;
+                Inst::CondBr { cond, then_block, then_args, else_block, else_args }
+            }
+            "ret" => Inst::Return { src: self.value()? },
+            "unreachable" => Inst::Unreachable,
+            _ => {
+                return Err(Box::new(Diagnostic::error(format!("unknown instruction `{op}`"))
+                    .with_code(codes::UNEXPECTED_TOKEN)
+                    .with_primary(op_span, "not an IR instruction")));
+            }
+        })
+    }
+
+    // an instruction's comma-separated value operands, which may be none
+    fn operands(&mut self) -> Result<Vec<ValueId>, Box<Diagnostic>> {
+        let mut values = Vec::new();
+        if matches!(self.peek(), Some(Tok::Value(_))) {
+            values.push(self.value()?);
+            while self.eat(Tok::Comma) {
+                values.push(self.value()?);
+            }
+        }
+        Ok(values)
+    }
+
+    // `const` makes a `Const`, `Boolean` or `FConst` by the type of `dst`
+    fn constant(&mut self, func: &Function, dst: ValueId) -> Result<Inst, Box<Diagnostic>> {
+        let ty = func.value_type(dst);
+        let span = self.span();
+        match (self.next(), &ty) {
+            (Some(Tok::Ident(b)), Type::Bool) if b == "true" || b == "false" => Ok(Inst::Boolean { dst, value: b == "true" }),
+            (Some(Tok::Float(x)), t) if t.is_float() => Ok(Inst::FConst { dst, value: x }),
+            (Some(Tok::Ident(w)), t) if t.is_float() && w == "inf" => Ok(Inst::FConst { dst, value: f64::INFINITY }),
+            (Some(Tok::Ident(w)), t) if t.is_float() && w == "NaN" => Ok(Inst::FConst { dst, value: f64::NAN }),
+            (Some(Tok::Int(n)), t) if t.is_float() => Ok(Inst::FConst { dst, value: n as f64 }),
+            (Some(Tok::Int(n)), t) if t.is_int() => {
+                // not checked against `t`: lowering negates `-128` as `0 - 128`
+                if n < i64::MIN as i128 || n > u64::MAX as i128 {
+                    return Err(Box::new(Diagnostic::error("constant does not fit in 64 bits")
+                        .with_code(codes::INTEGER_OVERFLOW)
+                        .with_primary(span, format!("not a valid {ty}"))));
+                }
+                // a u64 above i64::MAX is kept as its bit pattern
+                Ok(Inst::Const { dst, value: n as u64 as i64 })
+            }
+            _ => Err(self.error_at(span, &format!("a {ty} constant"))),
+        }
+    }
+}
+
+// Block names seen so far in a function. Each name gets an id when first
+// mentioned, by a branch or by its own label; the blocks are numbered by the
+// order of their labels once the whole function has been read.
+#[derive(Default)]
+struct Labels {
+    ids: HashMap<String, (BlockId, Span)>,
+    // in the order the labels appear
+    defined: Vec<BlockId>,
+}
+
+impl Labels {
+    fn mention(&mut self, name: String, span: Span) -> BlockId {
+        let next = BlockId::from_usize(self.ids.len());
+        self.ids.entry(name).or_insert((next, span)).0
+    }
+
+    // a name some branch uses but no label defines, with where it was first used
+    fn undefined(&self) -> Option<(&String, Span)> {
+        self.ids
+            .iter()
+            .filter(|(_, (id, _))| !self.defined.contains(id))
+            .min_by_key(|(_, (_, span))| span.start)
+            .map(|(name, (_, span))| (name, *span))
+    }
+}
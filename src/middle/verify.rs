@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use crate::diagnostic::{codes, Diagnostic};
+use crate::frontend::ast::Type;
+use crate::frontend::span::Span;
+use crate::middle::cfg::{Cfg, Dominators};
+use crate::middle::ir::{BlockId, Function, Inst, Module, ValueId};
+use crate::middle::text::SourceMap;
+
+/// Check that a module is well formed: every block ends in its one
+/// terminator, branches pass one argument of the right type per parameter
+/// of their target, every value is defined once and before each use (in the
+/// same block or one that dominates it), instructions get operands of the
+/// types they work on, calls match the callee's signature, and each
+/// recorded `for` loop's counter and bounds are where it says.
+pub fn verify(module: &Module) -> Result<(), Vec<Diagnostic>> {
+    verify_parsed(module, &SourceMap::default())
+}
+
+/// `verify` for a module read by `text::parse_module`, with each error
+/// pointing at the line it is about.
+pub fn verify_parsed(module: &Module, map: &SourceMap) -> Result<(), Vec<Diagnostic>> {
+    let mut errors = Vec::new();
+    for (index, func) in module.functions.iter().enumerate() {
+        let mut v = Verifier { module, func, map, index, errors: &mut errors, block: func.entry(), inst: None, span: None };
+        if v.structure() && v.definitions() {
+            v.types();
+        }
+    }
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+/// `verify` in debug builds, panicking with every problem and the module
+/// itself, as invalid IR is a compiler bug. `stage` says what produced it.
+pub fn debug_verify(module: &Module, stage: &str) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    if let Err(errors) = verify(module) {
+        let mut message = format!("invalid IR after {stage}:\n");
+        for e in &errors {
+            message.push_str(&format!("{e}\n"));
+            for note in &e.notes {
+                message.push_str(&format!("  = note: {note}\n"));
+            }
+        }
+        panic!("{message}\n{module}");
+    }
+}
+
+struct Verifier<'a> {
+    module: &'a Module,
+    func: &'a Function,
+    errors: &'a mut Vec<Diagnostic>,
+    // where the function was written, if it was parsed, and its index there
+    map: &'a SourceMap,
+    index: usize,
+    // where the checks are, for the notes and spans on errors
+    block: BlockId,
+    inst: Option<&'a Inst>,
+    span: Option<(Span, &'static str)>,
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&mut self, message: String) {
+        let mut place = format!("in `{}`, block b{}", self.func.name, self.block.get_usize());
+        if let Some(inst) = self.inst {
+            place.push_str(&format!(": `{}`", self.func.display_inst(inst)));
+        }
+        let mut error = Diagnostic::error(message).with_code(codes::INVALID_IR);
+        if let Some((span, label)) = self.span {
+            error = error.with_primary(span, label);
+        }
+        self.errors.push(error.with_note(place));
+    }
+
+    // Moves the checks to a block's label (`pos` 0) or to its instruction
+    // `pos - 1`, the positions `definitions` numbers values by.
+    fn at(&mut self, block: BlockId, pos: usize) {
+        let func = self.func;
+        self.block = block;
+        self.inst = pos.checked_sub(1).map(|i| &func.block(block).insts[i]);
+        let label = if pos == 0 { "in this block" } else { "in this instruction" };
+        self.span = self.map.place(self.index, block, pos).map(|span| (span, label));
+    }
+
+    // Terminators and branch targets, which the CFG needs to be built at
+    // all. Returns whether they are fine.
+    fn structure(&mut self) -> bool {
+        let before = self.errors.len();
+        let func = self.func;
+        let entry = func.block(func.entry());
+        if entry.params.len() != func.params.len() {
+            self.at(func.entry(), 0);
+            self.error(format!("the entry block has {} parameters for {} function parameters", entry.params.len(), func.params.len()));
+        }
+        for (b, block) in func.blocks.iter().enumerate() {
+            self.at(BlockId::from_usize(b), 0);
+            match block.insts.last() {
+                Some(last) if last.is_terminator() => {}
+                _ => self.error("block does not end in a terminator".to_string()),
+            }
+            let body = block.insts.len().saturating_sub(1);
+            for (i, inst) in block.insts[..body].iter().enumerate() {
+                if inst.is_terminator() {
+                    self.at(self.block, i + 1);
+                    self.error("terminator in the middle of a block".to_string());
+                }
+            }
+            let Some(last) = block.insts.last() else { continue };
+            self.at(self.block, block.insts.len());
+            for (target, args) in last.edges() {
+                if target.get_usize() >= func.blocks.len() {
+                    self.error(format!("branch to b{}, which does not exist", target.get_usize()));
+                } else if target == func.entry() {
+                    self.error("branch to the entry block".to_string());
+                } else if args.len() != func.block(target).params.len() {
+                    self.error(format!(
+                        "passes {} arguments to b{}, which has {} parameters",
+                        args.len(),
+                        target.get_usize(),
+                        func.block(target).params.len()
+                    ));
+                }
+            }
+        }
+        self.errors.len() == before
+    }
+
+    // Every value is defined once, and each use comes after its definition
+    // in the same block or in a block dominating it. Returns whether so.
+    fn definitions(&mut self) -> bool {
+        let before = self.errors.len();
+        let func = self.func;
+        // (block, position) of each value's definition; parameters are at 0,
+        // the instruction at index i at i + 1
+        let mut defs: Vec<Option<(BlockId, usize)>> = vec![None; func.value_count()];
+        for (b, block) in func.blocks.iter().enumerate() {
+            let block_id = BlockId::from_usize(b);
+            let params = block.params.iter().map(|&p| (p, 0));
+            let insts = block.insts.iter().enumerate().filter_map(|(i, inst)| Some((inst.dst()?, i + 1)));
+            for (value, pos) in params.chain(insts) {
+                self.at(block_id, pos);
+                match defs.get(value.get_usize()) {
+                    None => self.error(format!("`%v{}` has no type", value.get_usize())),
+                    Some(Some(_)) => self.error(format!("`%v{}` is defined more than once", value.get_usize())),
+                    Some(None) => defs[value.get_usize()] = Some((self.block, pos)),
+                }
+            }
+        }
+
+        let doms = Dominators::new(&Cfg::new(func));
+        for (b, block) in func.blocks.iter().enumerate() {
+            for (i, inst) in block.insts.iter().enumerate() {
+                self.at(BlockId::from_usize(b), i + 1);
+                for value in inst.operands() {
+                    let Some((def_block, def_pos)) = defs.get(value.get_usize()).copied().flatten() else {
+                        self.error(format!("`%v{}` is used but never defined", value.get_usize()));
+                        continue;
+                    };
+                    // code that never runs cannot read anything too early
+                    if !doms.is_reachable(self.block) {
+                        continue;
+                    }
+                    if def_block == self.block {
+                        if def_pos > i {
+                            self.error(format!("`%v{}` is used before it is defined", value.get_usize()));
+                        }
+                    } else if !doms.dominates(def_block, self.block) {
+                        self.error(format!(
+                            "`%v{}` is defined in b{}, which does not dominate this use",
+                            value.get_usize(),
+                            def_block.get_usize()
+                        ));
+                    }
+                }
+            }
+        }
+
+        // each recorded loop still names its header's parameter and bounds
+        // available on entry to the header
+        self.inst = None;
+        for (i, l) in func.loops.iter().enumerate() {
+            self.block = l.header;
+            self.span = self.map.loop_line(self.index, i).map(|span| (span, "in this loop"));
+            if l.header.get_usize() >= func.blocks.len() {
+                self.error(format!("a loop has header b{}, which does not exist", l.header.get_usize()));
+                continue;
+            }
+            if !func.block(l.header).params.contains(&l.counter) {
+                self.error(format!("the loop counter `%v{}` is not a parameter of its header", l.counter.get_usize()));
+            }
+            // as with other uses, a loop that never runs is not checked
+            if !doms.is_reachable(l.header) {
+                continue;
+            }
+            for bound in [l.start, l.end] {
+                match defs.get(bound.get_usize()).copied().flatten() {
+                    None => self.error(format!("the loop bound `%v{}` is never defined", bound.get_usize())),
+                    Some((def_block, _)) if def_block == l.header || !doms.dominates(def_block, l.header) => {
+                        self.error(format!("the loop bound `%v{}` is not defined before the loop", bound.get_usize()))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        self.errors.len() == before
+    }
+
+    // The operands of each instruction have types it works on. Only run once
+    // every value is known to be defined, so each has a real type.
+    fn types(&mut self) {
+        let func = self.func;
+        let entry = func.block(func.entry());
+        for ((name, ty), param) in func.params.iter().zip(&entry.params) {
+            if func.value_type(*param) != *ty {
+                self.at(func.entry(), 0);
+                self.error(format!("entry parameter `%v{}` has type {}, but `{name}` is {ty}", param.get_usize(), func.value_type(*param)));
+            }
+        }
+        // each variable slot holds one type
+        let mut slots: HashMap<&str, Type> = HashMap::new();
+        for (b, block) in func.blocks.iter().enumerate() {
+            for (i, inst) in block.insts.iter().enumerate() {
+                self.at(BlockId::from_usize(b), i + 1);
+                if let Inst::Load { dst: value, name } | Inst::Store { src: value, name } = inst {
+                    let ty = func.value_type(*value);
+                    match slots.get(name.as_str()) {
+                        Some(slot) if *slot != ty => self.error(format!("`{name}` holds {ty} here but {slot} elsewhere")),
+                        Some(_) => {}
+                        None => {
+                            slots.insert(name, ty);
+                        }
+                    }
+                }
+                self.inst_types(inst);
+            }
+        }
+    }
+
+    fn inst_types(&mut self, inst: &Inst) {
+        let func = self.func;
+        let ty = |v: &ValueId| func.value_type(*v);
+        match inst {
+            Inst::Const { dst, .. } => self.check(ty(dst).is_int(), "an integer constant needs an integer type"),
+            Inst::Boolean { dst, .. } => self.check(ty(dst) == Type::Bool, "a bool constant needs the bool type"),
+            Inst::FConst { dst, .. } => self.check(ty(dst).is_float(), "a float constant needs a float type"),
+            Inst::Cast { dst, src } => {
+                let (to, from) = (ty(dst), ty(src));
+                let widens = match (from.int_bits(), to.int_bits()) {
+                    (Some(from), Some(to)) => from <= to,
+                    _ => true,
+                };
+                self.check(to.is_int() && (from.is_int() || from.is_float() || from == Type::Bool) && widens, "casts only from a bool, an int or a float to an int at least as wide");
+            }
+            Inst::Add { dst, lhs, rhs } | Inst::Sub { dst, lhs, rhs } | Inst::Mul { dst, lhs, rhs }
+            | Inst::Div { dst, lhs, rhs } | Inst::Rem { dst, lhs, rhs } | Inst::BitAnd { dst, lhs, rhs }
+            | Inst::BitOr { dst, lhs, rhs } | Inst::BitXor { dst, lhs, rhs } | Inst::Shl { dst, lhs, rhs }
+            | Inst::Shr { dst, lhs, rhs } => {
+                self.check(ty(lhs).is_int() && ty(lhs) == ty(rhs) && ty(lhs) == ty(dst), "needs two ints of the result's type");
+            }
+            Inst::Greater { dst, lhs, rhs } | Inst::Less { dst, lhs, rhs } | Inst::GreaterEq { dst, lhs, rhs }
+            | Inst::LessEq { dst, lhs, rhs } => {
+                self.check(ty(lhs).is_int() && ty(lhs) == ty(rhs) && ty(dst) == Type::Bool, "compares two ints of one type to a bool");
+            }
+            Inst::Equal { dst, lhs, rhs } | Inst::NotEqual { dst, lhs, rhs } => {
+                let operand = ty(lhs).is_int() || ty(lhs) == Type::Bool;
+                self.check(operand && ty(lhs) == ty(rhs) && ty(dst) == Type::Bool, "compares two ints or bools of one type to a bool");
+            }
+            Inst::BitNot { dst, src } => self.check(ty(src).is_int() && ty(src) == ty(dst), "needs an int of the result's type"),
+            Inst::Not { dst, src } => self.check(ty(src) == Type::Bool && ty(dst) == Type::Bool, "negates a bool"),
+            Inst::FAdd { dst, lhs, rhs } | Inst::FSub { dst, lhs, rhs } | Inst::FMul { dst, lhs, rhs }
+            | Inst::FDiv { dst, lhs, rhs } | Inst::FRem { dst, lhs, rhs } => {
+                self.check(ty(lhs).is_float() && ty(lhs) == ty(rhs) && ty(lhs) == ty(dst), "needs two floats of the result's type");
+            }
+            Inst::FGreater { dst, lhs, rhs } | Inst::FLess { dst, lhs, rhs } | Inst::FEqual { dst, lhs, rhs }
+            | Inst::FGreaterEq { dst, lhs, rhs } | Inst::FLessEq { dst, lhs, rhs } | Inst::FNotEqual { dst, lhs, rhs } => {
+                self.check(ty(lhs).is_float() && ty(lhs) == ty(rhs) && ty(dst) == Type::Bool, "compares two floats of one type to a bool");
+            }
+            Inst::FNeg { dst, src } => self.check(ty(src).is_float() && ty(src) == ty(dst), "needs a float of the result's type"),
+            Inst::Call { dst, callee, args } => {
+                let Some(target) = self.module.functions.iter().find(|f| f.name == *callee) else {
+                    self.error(format!("calls `{callee}`, which is not in the module"));
+                    return;
+                };
+                if args.len() != target.params.len() {
+                    self.error(format!("passes {} arguments to `{callee}`, which takes {}", args.len(), target.params.len()));
+                    return;
+                }
+                for (i, (arg, (name, param))) in args.iter().zip(&target.params).enumerate() {
+                    if ty(arg) != *param {
+                        self.error(format!("argument {} has type {}, but `{name}` of `{callee}` is {param}", i + 1, ty(arg)));
+                    }
+                }
+                self.check(ty(dst) == target.ret, "the result has a different type from what the callee returns");
+            }
+            Inst::ArrayNew { dst, dims } => {
+                let rank_matches = matches!(ty(dst), Type::Array { rank, .. } if rank as usize == dims.len());
+                self.check(rank_matches && dims.iter().all(|d| ty(d) == Type::I64), "needs an i64 extent per dimension of the array type");
+            }
+            Inst::ArrayLen { dst, array, dim } => {
+                let in_rank = matches!(ty(array), Type::Array { rank, .. } if *dim < rank);
+                self.check(in_rank && ty(dst) == Type::I64, "gives the i64 extent of one of the array's dimensions");
+            }
+            Inst::CheckIndex { index, len } => self.check(ty(index) == Type::I64 && ty(len) == Type::I64, "compares two i64s"),
+            Inst::ElemAddr { dst, array, index } => {
+                let array = matches!(ty(array), Type::Array { .. });
+                self.check(array && ty(index) == Type::I64 && ty(dst) == Type::Ptr, "takes an array and an i64 index to a ptr");
+            }
+            Inst::LoadElem { addr, .. } => self.check(ty(addr) == Type::Ptr, "loads through a ptr"),
+            Inst::StoreElem { addr, .. } => self.check(ty(addr) == Type::Ptr, "stores through a ptr"),
+            Inst::StructNew { dst, fields } => {
+                let Some(def) = self.struct_def(&ty(dst)) else { return };
+                let matches = def.len() == fields.len() && def.iter().zip(fields).all(|((_, t), f)| *t == ty(f));
+                self.check(matches, "needs one value of the right type per field");
+            }
+            Inst::ExtractField { dst, src, field } => {
+                let Some(def) = self.struct_def(&ty(src)) else { return };
+                let field_ty = def.get(*field as usize).map(|(_, t)| t.clone());
+                self.check(field_ty == Some(ty(dst)), "gives a field of the struct, with its type");
+            }
+            Inst::InsertField { dst, src, field, value } => {
+                let Some(def) = self.struct_def(&ty(src)) else { return };
+                let field_ty = def.get(*field as usize).map(|(_, t)| t.clone());
+                self.check(field_ty == Some(ty(value)) && ty(dst) == ty(src), "replaces a field of the struct with a value of its type");
+            }
+            Inst::Load { .. } | Inst::Store { .. } | Inst::Unreachable => {}
+            Inst::Br { target, args } => self.args(*target, args),
+            Inst::CondBr { cond, then_block, then_args, else_block, else_args } => {
+                self.check(ty(cond) == Type::Bool, "branches on a bool");
+                self.args(*then_block, then_args);
+                self.args(*else_block, else_args);
+            }
+            Inst::Return { src } => {
+                let ret = func.ret.clone();
+                self.check(ty(src) == ret, &format!("returns {} from a function returning {ret}", ty(src)));
+            }
+        }
+    }
+
+    fn check(&mut self, ok: bool, message: &str) {
+        if !ok {
+            self.error(format!("operand types do not fit: {message}"));
+        }
+    }
+
+    // the fields of a struct type, reporting anything else
+    fn struct_def(&mut self, ty: &Type) -> Option<&'a [(String, Type)]> {
+        let module = self.module;
+        let def = match ty {
+            Type::Struct(name) => module.structs.iter().find(|s| s.name == *name),
+            _ => None,
+        };
+        if def.is_none() {
+            self.error(format!("operand types do not fit: `{ty}` is not a struct of the module"));
+        }
+        def.map(|d| d.fields.as_slice())
+    }
+
+    fn args(&mut self, target: BlockId, args: &[ValueId]) {
+        let params = &self.func.block(target).params;
+        for (arg, param) in args.iter().zip(params) {
+            let (from, to) = (self.func.value_type(*arg), self.func.value_type(*param));
+            if from != to {
+                self.error(format!("passes {from} to `%v{}` of b{}, which is {to}", param.get_usize(), target.get_usize()));
+            }
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use std::collections::{HashMap, HashSet};
+use crate::frontend::ast::{Expr, ExprKind, Stmt, StmtKind, SymbolId};
+use crate::frontend::span::Span;
+use crate::diagnostic::{codes, Diagnostic};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function { arity: usize },
+    Param,
+    Local,
+    LoopVar,
+}
+
+/// Something a name can refer to. `span` is where it was defined (for locals,
+/// their first assignment).
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub span: Span,
+}
+
+/// Every symbol in the program, indexed by the `SymbolId`s stored in the AST.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    fn add(&mut self, name: &str, kind: SymbolKind, span: Span) -> SymbolId {
+        let id = SymbolId(self.symbols.len() as u32);
+        self.symbols.push(Symbol { name: name.to_string(), kind, span });
+        id
+    }
+
+    pub fn get(&self, id: SymbolId) -> &Symbol {
+        &self.symbols[id.0 as usize]
+    }
+}
+
+// Which variables are definitely assigned at the current point. Code after a
+// `return` is unreachable, so anything counts as assigned there.
+#[derive(Debug, Clone, Default)]
+struct Flow {
+    assigned: HashSet<SymbolId>,
+    diverged: bool,
+}
+
+impl Flow {
+    // state after two branches join: assigned only if assigned on both
+    fn join(self, other: Flow) -> Flow {
+        match (self.diverged, other.diverged) {
+            (true, _) => other,
+            (_, true) => self,
+            _ => Flow {
+                assigned: self.assigned.intersection(&other.assigned).copied().collect(),
+                diverged: false,
+            },
+        }
+    }
+}
+
+// Names that look like calls but are parsed into their own expressions.
+const BUILTINS: &[&str] = &["array", "len"];
+
+struct Resolver {
+    symbols: SymbolTable,
+    functions: HashMap<String, SymbolId>,
+    // innermost scope last; scopes[0] holds the current function's params and locals
+    scopes: Vec<HashMap<String, SymbolId>>,
+    flow: Flow,
+    errors: Vec<Diagnostic>,
+}
+
+/// Resolve every name in the program, filling in the `sym` slots of
+/// identifiers and parameters.
+///
+/// Scoping follows Python: assigning to a new name declares a local of the
+/// enclosing function (or of the top-level program), visible from then on in
+/// the whole function; only a `for` loop variable is scoped to its loop.
+/// Functions are global and may be called before their definition, but they
+/// cannot see the variables of the code that calls them.
+///
+/// A variable may only be read where it has been assigned on every path
+/// leading there: after an `if` without `else`, or after a loop that might
+/// not run, names first assigned inside are not usable yet.
+pub fn resolve_program(stmts: &mut [Stmt]) -> Result<SymbolTable, Vec<Diagnostic>> {
+    let mut r = Resolver {
+        symbols: SymbolTable::default(),
+        functions: HashMap::new(),
+        scopes: Vec::new(),
+        flow: Flow::default(),
+        errors: Vec::new(),
+    };
+
+    // declare every function first so calls can refer to later definitions
+    for s in stmts.iter() {
+        if let StmtKind::Fn { name, params, .. } = &s.kind {
+            r.declare_function(name, params.len(), s.span);
+        }
+    }
+
+    for s in stmts.iter_mut() {
+        if let StmtKind::Fn { params, body, .. } = &mut s.kind {
+            r.enter_function();
+            let mut seen: HashMap<&str, Span> = HashMap::new();
+            for p in params.iter_mut() {
+                if let Some(first) = seen.insert(&p.name, p.span) {
+                    r.errors.push(
+                        Diagnostic::error(format!("parameter `{}` is declared more than once", p.name))
+                            .with_code(codes::DUPLICATE_PARAMETER)
+                            .with_primary(p.span, "used again here")
+                            .with_label(first, "first declared here"),
+                    );
+                }
+                p.sym = Some(r.define(&p.name, SymbolKind::Param, p.span));
+            }
+            r.stmt(body);
+        }
+    }
+
+    // everything that is not a function or struct definition makes up `main`
+    r.enter_function();
+    for s in stmts.iter_mut() {
+        if !matches!(s.kind, StmtKind::Fn { .. } | StmtKind::Struct { .. }) {
+            r.stmt(s);
+        }
+    }
+
+    if r.errors.is_empty() {
+        Ok(r.symbols)
+    } else {
+        // functions are resolved before `main`; report in source order instead
+        r.errors.sort_by_key(|d| d.primary.as_ref().map(|l| l.span.start));
+        Err(r.errors)
+    }
+}
+
+impl Resolver {
+    fn declare_function(&mut self, name: &str, arity: usize, span: Span) {
+        if name == "main" {
+            self.errors.push(
+                Diagnostic::error("cannot define a function named `main`")
+                    .with_code(codes::DUPLICATE_FUNCTION)
+                    .with_primary(span, "`main` is reserved")
+                    .with_note("the top-level statements of a program form its `main` function"),
+            );
+            return;
+        }
+        if BUILTINS.contains(&name) {
+            self.errors.push(
+                Diagnostic::error(format!("cannot define a function named `{name}`"))
+                    .with_code(codes::DUPLICATE_FUNCTION)
+                    .with_primary(span, format!("`{name}` is built in")),
+            );
+            return;
+        }
+        if let Some(&previous) = self.functions.get(name) {
+            self.errors.push(
+                Diagnostic::error(format!("function `{name}` is defined more than once"))
+                    .with_code(codes::DUPLICATE_FUNCTION)
+                    .with_primary(span, "redefined here")
+                    .with_label(self.symbols.get(previous).span, "first defined here"),
+            );
+            return;
+        }
+        let id = self.symbols.add(name, SymbolKind::Function { arity }, span);
+        self.functions.insert(name.to_string(), id);
+    }
+
+    fn enter_function(&mut self) {
+        self.scopes = vec![HashMap::new()];
+        self.flow = Flow::default();
+    }
+
+    // declare `name` in the innermost scope and mark it assigned
+    fn define(&mut self, name: &str, kind: SymbolKind, span: Span) -> SymbolId {
+        let id = self.symbols.add(name, kind, span);
+        self.scopes.last_mut().expect("inside a function").insert(name.to_string(), id);
+        self.flow.assigned.insert(id);
+        id
+    }
+
+    fn lookup(&self, name: &str) -> Option<SymbolId> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+
+    fn stmt(&mut self, stmt: &mut Stmt) {
+        match &mut stmt.kind {
+            StmtKind::Expr(e) => self.expr(e),
+            StmtKind::Assign { target, ann, value } => {
+                // the value is resolved first: in `x = x + 1` the right-hand `x` must already exist
+                self.expr(value);
+                if let ExprKind::Ident { name, sym } = &mut target.kind {
+                    let id = match self.lookup(name) {
+                        Some(id) => id,
+                        None => {
+                            // new locals belong to the function, not to the block they appear in
+                            let id = self.symbols.add(name, SymbolKind::Local, target.span);
+                            self.scopes[0].insert(name.clone(), id);
+                            id
+                        }
+                    };
+                    self.flow.assigned.insert(id);
+                    *sym = Some(id);
+                } else {
+                    // storing into `a[i]` reads `a` and `i`, and storing into `p.x` reads `p`
+                    if self.check_target(target)
+                        && let Some(ann) = ann
+                    {
+                        self.errors.push(
+                            Diagnostic::error("cannot annotate an array element or a field")
+                                .with_code(codes::INVALID_ASSIGN_TARGET)
+                                .with_primary(ann.span, "annotation not allowed here")
+                                .with_note("only variables take annotations; elements and fields already have a type"),
+                        );
+                    }
+                    self.expr(target);
+                }
+            }
+            StmtKind::CompoundAssign { target, value, .. } => {
+                // `x += v` reads `x` first, so unlike `=` it cannot create a variable
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+                self.check_target(target);
+                self.expr(target);
+            }
+            StmtKind::Block(stmts) => {
+                for s in stmts {
+                    self.stmt(s);
+                }
+            }
+            StmtKind::Fn { name, .. } => {
+                self.errors.push(
+                    Diagnostic::error(format!("function `{name}` is not defined at the top level"))
+                        .with_code(codes::NESTED_FUNCTION)
+                        .with_primary(stmt.span, "functions cannot be defined inside other code")
+                        .with_note("move this definition to the top level of the file"),
+                );
+            }
+            StmtKind::Struct { name, .. } => {
+                self.errors.push(
+                    Diagnostic::error(format!("struct `{name}` is not defined at the top level"))
+                        .with_code(codes::NESTED_STRUCT)
+                        .with_primary(stmt.span, "structs cannot be defined inside other code")
+                        .with_note("move this definition to the top level of the file"),
+                );
+            }
+            StmtKind::Return(value) => {
+                self.expr(value);
+                self.flow.diverged = true;
+            }
+        }
+    }
+
+    // Reports `target` unless it can be assigned to; returns whether it can.
+    fn check_target(&mut self, target: &Expr) -> bool {
+        if is_place(target) {
+            return true;
+        }
+        self.errors.push(
+            Diagnostic::error("cannot assign to this expression")
+                .with_code(codes::INVALID_ASSIGN_TARGET)
+                .with_primary(target.span, "not a variable, array element or field")
+                .with_note("only a variable, an element `a[i]`, or a field `p.x` of one of those can be assigned to"),
+        );
+        false
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::Number(_) | ExprKind::Float(_) | ExprKind::Bool(_) | ExprKind::Error => {}
+            ExprKind::Ident { name, sym } => {
+                *sym = self.variable(name, expr.span);
+            }
+            ExprKind::Unary { expr: inner, .. }
+            | ExprKind::Len { array: inner, dim: None }
+            | ExprKind::Field { base: inner, .. } => self.expr(inner),
+            ExprKind::StructLit { fields, .. } => {
+                for f in fields {
+                    self.expr(&mut f.value);
+                }
+            }
+            ExprKind::Binary { left, right, .. } | ExprKind::Len { array: left, dim: Some(right) } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::Array(elems) | ExprKind::NewArray { dims: elems } => {
+                for e in elems {
+                    self.expr(e);
+                }
+            }
+            ExprKind::Index { array, indices } => {
+                self.expr(array);
+                for i in indices {
+                    self.expr(i);
+                }
+            }
+            ExprKind::Call { callee, args } => {
+                for a in args.iter_mut() {
+                    self.expr(a);
+                }
+                if let ExprKind::Ident { name, sym } = &mut callee.kind {
+                    *sym = self.function(name, args.len(), callee.span);
+                }
+            }
+            ExprKind::If { cond, body, else_branch } => {
+                self.expr(cond);
+                let before = self.flow.clone();
+                self.stmt(body);
+                let after_then = std::mem::replace(&mut self.flow, before);
+                if let Some(else_stmt) = else_branch {
+                    self.stmt(else_stmt);
+                }
+                let after_else = std::mem::take(&mut self.flow);
+                self.flow = after_then.join(after_else);
+            }
+            ExprKind::While { cond, body } => {
+                // the body may run zero times, so nothing it assigns survives the loop
+                self.expr(cond);
+                let before = self.flow.clone();
+                self.stmt(body);
+                self.flow = before;
+            }
+            ExprKind::For { var, start, end, body } => {
+                // bounds are evaluated before the loop variable exists
+                self.expr(start);
+                self.expr(end);
+                let before = self.flow.clone();
+                self.scopes.push(HashMap::new());
+                if let ExprKind::Ident { name, sym } = &mut var.kind {
+                    *sym = Some(self.define(name, SymbolKind::LoopVar, var.span));
+                }
+                self.stmt(body);
+                self.scopes.pop();
+                self.flow = before;
+            }
+        }
+    }
+
+    // resolve a variable read
+    fn variable(&mut self, name: &str, span: Span) -> Option<SymbolId> {
+        let Some(id) = self.lookup(name) else {
+            let mut diag = Diagnostic::error(format!("cannot find variable `{name}` in this scope"))
+                .with_code(codes::UNDEFINED_VARIABLE)
+                .with_primary(span, "not found in this scope");
+            if self.functions.contains_key(name) {
+                diag = diag.with_note(format!("`{name}` is a function; call it with `{name}(...)`"));
+            }
+            self.errors.push(diag);
+            return None;
+        };
+        if !self.flow.diverged && !self.flow.assigned.contains(&id) {
+            self.errors.push(
+                Diagnostic::error(format!("variable `{name}` is used before it is assigned"))
+                    .with_code(codes::USED_BEFORE_ASSIGNED)
+                    .with_primary(span, "read here")
+                    .with_label(self.symbols.get(id).span, "assigned here")
+                    .with_note(format!("`{name}` must be assigned on every path that reaches this point")),
+            );
+        }
+        Some(id)
+    }
+
+    // resolve the callee of a call with `arg_count` arguments
+    fn function(&mut self, name: &str, arg_count: usize, span: Span) -> Option<SymbolId> {
+        let Some(&id) = self.functions.get(name) else {
+            self.errors.push(
+                Diagnostic::error(format!("cannot find function `{name}`"))
+                    .with_code(codes::UNKNOWN_FUNCTION)
+                    .with_primary(span, "not defined in this program"),
+            );
+            return None;
+        };
+        if let SymbolKind::Function { arity } = self.symbols.get(id).kind
+            && arity != arg_count
+        {
+            self.errors.push(
+                Diagnostic::error(format!("wrong number of arguments to `{name}`"))
+                    .with_code(codes::ARGUMENT_COUNT)
+                    .with_primary(span, format!("expected {arity} argument(s), found {arg_count}"))
+                    .with_label(self.symbols.get(id).span, "function defined here"),
+            );
+        }
+        Some(id)
+    }
+}
+
+// Whether `expr` names somewhere a value can be stored: a variable, an
+// element of any array, or a field of one of those. A parse error counts, so
+// it is not reported twice.
+fn is_place(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Ident { .. } | ExprKind::Index { .. } | ExprKind::Error => true,
+        ExprKind::Field { base, .. } => is_place(base),
+        _ => false,
+    }
+}
@@ -0,0 +1,1032 @@
+use std::collections::HashMap;
+use crate::frontend::ast::{BinaryOp, Expr, ExprKind, FieldDecl, Stmt, StmtKind, SymbolId, Type, TypeExpr, TypeExprKind, UnaryOp};
+use crate::frontend::span::Span;
+use crate::diagnostic::{codes, Diagnostic};
+use crate::sema::resolve::SymbolTable;
+
+// A type during inference: either known, or a variable that unification
+// will solve later. Arrays (element type and rank) are kept apart from
+// `Known` so their element type can be inferred too; `Known` never holds a
+// `Type::Array`.
+#[derive(Debug, Clone, PartialEq)]
+enum Ty {
+    Known(Type),
+    Var(u32),
+    Array(Box<Ty>, u32),
+}
+
+// What an unsolved type variable may still turn into.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Any,
+    // something arithmetic works on
+    Numeric,
+    // the type of an integer literal, or an operand of a bitwise operator
+    Integer,
+    // the type of a float literal
+    Float,
+}
+
+impl Kind {
+    fn admits(self, ty: &Type) -> bool {
+        match self {
+            Kind::Any => true,
+            Kind::Numeric => ty.is_int() || ty.is_float(),
+            Kind::Integer => ty.is_int(),
+            Kind::Float => ty.is_float(),
+        }
+    }
+
+    // the kind of a variable that must satisfy both `self` and `other`
+    fn meet(self, other: Kind) -> Option<Kind> {
+        match (self, other) {
+            (Kind::Any, k) | (k, Kind::Any) | (Kind::Numeric, k) | (k, Kind::Numeric) => Some(k),
+            (Kind::Integer, Kind::Integer) => Some(Kind::Integer),
+            (Kind::Float, Kind::Float) => Some(Kind::Float),
+            (Kind::Integer, Kind::Float) | (Kind::Float, Kind::Integer) => None,
+        }
+    }
+
+    // the type an unsolved variable of this kind ends up with
+    fn default(self) -> Type {
+        match self {
+            Kind::Any | Kind::Numeric | Kind::Integer => Type::I64,
+            Kind::Float => Type::F64,
+        }
+    }
+}
+
+struct TypeVar {
+    kind: Kind,
+    solution: Option<Ty>,
+}
+
+struct Signature {
+    params: Vec<Ty>,
+    ret: Ty,
+}
+
+// The fields of every struct, in declaration order.
+type Structs = HashMap<String, Vec<(String, Ty)>>;
+
+struct Checker<'a> {
+    symbols: &'a SymbolTable,
+    structs: Structs,
+    type_vars: Vec<TypeVar>,
+    // type of every variable and parameter
+    vars: HashMap<SymbolId, Ty>,
+    functions: HashMap<String, Signature>,
+    // what a `return` in the current function must produce
+    ret: Ty,
+    // set after a `return`: the rest of the current path never runs
+    diverges: bool,
+    // set for the statement about to be checked when its value is thrown
+    // away; an `if` there is not asked to give both branches one type
+    discarded: bool,
+    // the type of every expression, by its span; no two expressions the
+    // parser builds share one. Written into the AST by `fill_expr`
+    recorded: HashMap<Span, Ty>,
+    // integer literals, checked against their type's range once types are known
+    literals: Vec<(i128, Span, Ty)>,
+    errors: Vec<Diagnostic>,
+}
+
+/// Type-check a resolved program, filling in `Expr::ty`, `Param::ty` and the
+/// `ret_ty` of every function.
+///
+/// Annotations are optional. Unannotated variables, parameters and return
+/// types are inferred by unification over the whole program: a parameter's
+/// type may come from its uses in the body or from the arguments of a call.
+/// Whatever is still unknown at the end defaults to `f64` if a float literal
+/// flowed into it and to `i64` (also spelled `int`) otherwise. Functions are
+/// not generic: every call of a function sees the same types.
+///
+/// Arithmetic and ordering comparisons take two numbers of the same type;
+/// nothing converts implicitly, neither ints to floats nor between integer
+/// sizes. Bitwise operators and shifts take two integers of the same type.
+/// `x += v` and friends need `x` and `v` to be numbers of one type; `x++`
+/// and `x--` need `x` to be a number.
+/// An integer literal must fit the type it ends up with. `==` and `!=` take
+/// two operands of the same type. Conditions and the operands of `and`, `or`
+/// and `not` must be bools.
+///
+/// Array elements all have one type, inferred like a variable's. The rank of
+/// an array is part of its type: `m[i, j]` needs a two-dimensional array.
+/// Extents and indices are `i64`.
+///
+/// Struct fields must be annotated. A struct may hold other structs by value
+/// but not itself, directly or through other structs; an array of itself is
+/// fine. Reading a field needs the struct type to be known at that point.
+pub fn check_program(stmts: &mut [Stmt], symbols: &SymbolTable) -> Result<(), Vec<Diagnostic>> {
+    let mut c = Checker {
+        symbols,
+        structs: HashMap::new(),
+        type_vars: Vec::new(),
+        vars: HashMap::new(),
+        functions: HashMap::new(),
+        ret: Ty::Known(Type::I64),
+        diverges: false,
+        discarded: false,
+        recorded: HashMap::new(),
+        literals: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    // structs come first so any annotation can name them
+    c.declare_structs(stmts);
+
+    // signatures come first so calls can refer to later definitions
+    for s in stmts.iter() {
+        if let StmtKind::Fn { name, params, ret, .. } = &s.kind {
+            let mut param_tys = Vec::new();
+            for p in params {
+                let ty = c.annotation(p.ann.as_ref());
+                c.vars.insert(p.sym.expect("resolved"), ty.clone());
+                param_tys.push(ty);
+            }
+            let ret = c.annotation(ret.as_ref());
+            c.functions.insert(name.clone(), Signature { params: param_tys, ret });
+        }
+    }
+
+    for s in stmts.iter() {
+        if let StmtKind::Fn { name, body, .. } = &s.kind {
+            c.ret = c.functions[name].ret.clone();
+            c.diverges = false;
+            let ty = c.stmt(body);
+            if !c.diverges {
+                c.expect(c.ret.clone(), ty, body.span, "the function's result");
+            }
+        }
+    }
+
+    // the program's value is returned from `main`; any type is fine there,
+    // but an explicit `return` must produce an int
+    c.ret = Ty::Known(Type::I64);
+    c.diverges = false;
+    let top_level: Vec<&Stmt> = stmts.iter().filter(|s| !matches!(s.kind, StmtKind::Fn { .. } | StmtKind::Struct { .. })).collect();
+    for (i, s) in top_level.iter().enumerate() {
+        c.discarded = i + 1 < top_level.len();
+        c.stmt(s);
+    }
+
+    for s in stmts.iter() {
+        if let StmtKind::Fn { name, ret, .. } = &s.kind
+            && c.finish(c.functions[name].ret.clone()) == Type::Unit
+        {
+            let span = ret.as_ref().map_or(s.span, |r| r.span);
+            c.errors.push(
+                Diagnostic::error(format!("function `{name}` does not produce a value"))
+                    .with_code(codes::TYPE_MISMATCH)
+                    .with_primary(span, "the result of this function has type unit")
+                    .with_note("end the body with an expression, or `return` a value on every path"),
+            );
+        }
+    }
+
+    for (value, span, ty) in std::mem::take(&mut c.literals) {
+        let ty = c.finish(ty);
+        let (min, max) = ty.int_range().expect("integer literals have integer types");
+        if value < min || value > max {
+            c.errors.push(
+                Diagnostic::error(format!("literal out of range for {ty}"))
+                    .with_code(codes::TYPE_MISMATCH)
+                    .with_primary(span, format!("{ty} holds values from {min} to {max}")),
+            );
+        }
+    }
+
+    if !c.errors.is_empty() {
+        c.errors.sort_by_key(|d| d.primary.as_ref().map(|l| l.span.start));
+        return Err(c.errors);
+    }
+
+    let recorded = std::mem::take(&mut c.recorded);
+    let types: HashMap<Span, Type> = recorded.into_iter().map(|(span, ty)| (span, c.finish(ty))).collect();
+    for s in stmts.iter_mut() {
+        if let StmtKind::Fn { name, params, ret_ty, body, .. } = &mut s.kind {
+            let sig = &c.functions[name.as_str()];
+            let (param_tys, ret) = (sig.params.clone(), sig.ret.clone());
+            for (p, ty) in params.iter_mut().zip(param_tys) {
+                p.ty = Some(c.finish(ty));
+            }
+            *ret_ty = Some(c.finish(ret));
+            fill_stmt(body, &types, &c.structs);
+        }
+        if let StmtKind::Struct { name, fields } = &mut s.kind {
+            let field_tys: Vec<Ty> = c.structs[name.as_str()].iter().map(|(_, ty)| ty.clone()).collect();
+            for (f, ty) in fields.iter_mut().zip(field_tys) {
+                f.ty = Some(c.finish(ty));
+            }
+        }
+    }
+    for s in stmts.iter_mut() {
+        if !matches!(s.kind, StmtKind::Fn { .. } | StmtKind::Struct { .. }) {
+            fill_stmt(s, &types, &c.structs);
+        }
+    }
+    Ok(())
+}
+
+// The names an annotation can use.
+const TYPE_NAMES: &[(&str, Type)] = &[
+    ("int", Type::I64),
+    ("i8", Type::I8),
+    ("i16", Type::I16),
+    ("i32", Type::I32),
+    ("i64", Type::I64),
+    ("u8", Type::U8),
+    ("u16", Type::U16),
+    ("u32", Type::U32),
+    ("u64", Type::U64),
+    ("bool", Type::Bool),
+    ("f32", Type::F32),
+    ("f64", Type::F64),
+];
+
+fn named_type(name: &str) -> Option<Type> {
+    TYPE_NAMES.iter().find(|(n, _)| *n == name).map(|(_, ty)| ty.clone())
+}
+
+// Position of `field` among the fields of struct `name`.
+fn field_index(structs: &Structs, name: &str, field: &str) -> Option<u32> {
+    structs.get(name)?.iter().position(|(f, _)| f == field).map(|i| i as u32)
+}
+
+fn op_symbol(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Rem => "%",
+        BinaryOp::Greater => ">",
+        BinaryOp::Less => "<",
+        BinaryOp::Equal => "==",
+        BinaryOp::GreaterEq => ">=",
+        BinaryOp::LessEq => "<=",
+        BinaryOp::NotEqual => "!=",
+        BinaryOp::BitAnd => "&",
+        BinaryOp::BitOr => "|",
+        BinaryOp::BitXor => "^",
+        BinaryOp::Shl => "<<",
+        BinaryOp::Shr => ">>",
+        BinaryOp::And => "and",
+        BinaryOp::Or => "or",
+    }
+}
+
+// Store the inferred types into the AST, each expression taking the type
+// recorded for its span.
+fn fill_stmt(stmt: &mut Stmt, types: &HashMap<Span, Type>, structs: &Structs) {
+    match &mut stmt.kind {
+        StmtKind::Expr(e) | StmtKind::Return(e) => fill_expr(e, types, structs),
+        StmtKind::Assign { target, value, .. } => {
+            fill_expr(value, types, structs);
+            match target.kind {
+                ExprKind::Ident { .. } => target.ty = types.get(&target.span).cloned(),
+                _ => fill_expr(target, types, structs),
+            }
+        }
+        StmtKind::CompoundAssign { target, value, .. } => {
+            if let Some(value) = value {
+                fill_expr(value, types, structs);
+            }
+            fill_expr(target, types, structs);
+        }
+        StmtKind::Block(stmts) => {
+            for s in stmts {
+                fill_stmt(s, types, structs);
+            }
+        }
+        StmtKind::Fn { .. } | StmtKind::Struct { .. } => {}
+    }
+}
+
+fn fill_expr(expr: &mut Expr, types: &HashMap<Span, Type>, structs: &Structs) {
+    match &mut expr.kind {
+        ExprKind::Number(_) | ExprKind::Float(_) | ExprKind::Bool(_) | ExprKind::Ident { .. } | ExprKind::Error => {}
+        ExprKind::Unary { expr: inner, .. } | ExprKind::Len { array: inner, dim: None } => fill_expr(inner, types, structs),
+        ExprKind::Binary { left, right, .. } | ExprKind::Len { array: left, dim: Some(right) } => {
+            fill_expr(left, types, structs);
+            fill_expr(right, types, structs);
+        }
+        ExprKind::Array(elems) | ExprKind::NewArray { dims: elems } => {
+            for e in elems {
+                fill_expr(e, types, structs);
+            }
+        }
+        ExprKind::Index { array, indices } => {
+            fill_expr(array, types, structs);
+            for i in indices {
+                fill_expr(i, types, structs);
+            }
+        }
+        ExprKind::StructLit { name, fields } => {
+            for f in fields {
+                fill_expr(&mut f.value, types, structs);
+                f.index = field_index(structs, name, &f.name);
+            }
+        }
+        ExprKind::Field { base, name, index } => {
+            fill_expr(base, types, structs);
+            if let Some(Type::Struct(s)) = &base.ty {
+                *index = field_index(structs, s, name);
+            }
+        }
+        ExprKind::Call { args, .. } => {
+            for a in args {
+                fill_expr(a, types, structs);
+            }
+        }
+        ExprKind::If { cond, body, else_branch } => {
+            fill_expr(cond, types, structs);
+            fill_stmt(body, types, structs);
+            if let Some(else_stmt) = else_branch {
+                fill_stmt(else_stmt, types, structs);
+            }
+        }
+        ExprKind::While { cond, body } => {
+            fill_expr(cond, types, structs);
+            fill_stmt(body, types, structs);
+        }
+        ExprKind::For { var, start, end, body } => {
+            fill_expr(start, types, structs);
+            fill_expr(end, types, structs);
+            var.ty = types.get(&var.span).cloned();
+            fill_stmt(body, types, structs);
+        }
+    }
+    expr.ty = types.get(&expr.span).cloned();
+}
+
+impl Checker<'_> {
+    fn fresh(&mut self, kind: Kind) -> Ty {
+        self.type_vars.push(TypeVar { kind, solution: None });
+        Ty::Var(self.type_vars.len() as u32 - 1)
+    }
+
+    // follow solved variables until reaching a known type or an unsolved variable
+    fn resolve(&self, ty: Ty) -> Ty {
+        let mut ty = ty;
+        while let Ty::Var(v) = ty {
+            match &self.type_vars[v as usize].solution {
+                Some(next) => ty = next.clone(),
+                None => break,
+            }
+        }
+        ty
+    }
+
+    // make `a` and `b` the same type; false if they cannot be
+    fn unify(&mut self, a: Ty, b: Ty) -> bool {
+        match (self.resolve(a), self.resolve(b)) {
+            (Ty::Known(x), Ty::Known(y)) => x == y,
+            (Ty::Array(x, r), Ty::Array(y, s)) => r == s && self.unify(*x, *y),
+            (Ty::Var(v), Ty::Var(w)) => {
+                if v == w {
+                    return true;
+                }
+                let Some(kind) = self.type_vars[v as usize].kind.meet(self.type_vars[w as usize].kind) else {
+                    return false;
+                };
+                self.type_vars[w as usize].kind = kind;
+                self.type_vars[v as usize].solution = Some(Ty::Var(w));
+                true
+            }
+            (Ty::Var(v), t) | (t, Ty::Var(v)) => {
+                let fits = match &t {
+                    Ty::Known(known) => self.type_vars[v as usize].kind.admits(known),
+                    // only an unconstrained variable can be an array, and not one of itself
+                    _ => self.type_vars[v as usize].kind == Kind::Any && !self.occurs(v, t.clone()),
+                };
+                if fits {
+                    self.type_vars[v as usize].solution = Some(t);
+                }
+                fits
+            }
+            (Ty::Known(_), Ty::Array(..)) | (Ty::Array(..), Ty::Known(_)) => false,
+        }
+    }
+
+    // whether variable `v` appears inside `ty`
+    fn occurs(&self, v: u32, ty: Ty) -> bool {
+        match self.resolve(ty) {
+            Ty::Var(w) => v == w,
+            Ty::Array(elem, _) => self.occurs(v, *elem),
+            Ty::Known(_) => false,
+        }
+    }
+
+    // the final type, picking the default for anything still unsolved
+    fn finish(&mut self, ty: Ty) -> Type {
+        match self.resolve(ty) {
+            Ty::Known(t) => t,
+            Ty::Array(elem, rank) => Type::Array { elem: Box::new(self.finish(*elem)), rank },
+            Ty::Var(v) => {
+                let var = &mut self.type_vars[v as usize];
+                let t = var.kind.default();
+                var.solution = Some(Ty::Known(t.clone()));
+                t
+            }
+        }
+    }
+
+    fn show(&self, ty: Ty) -> String {
+        match self.resolve(ty) {
+            Ty::Known(t) => t.to_string(),
+            Ty::Array(elem, 1) => format!("[{}]", self.show(*elem)),
+            Ty::Array(elem, rank) => format!("[{}, {}]", self.show(*elem), rank),
+            Ty::Var(v) => match self.type_vars[v as usize].kind {
+                Kind::Any => "_".to_string(),
+                Kind::Numeric => "{number}".to_string(),
+                Kind::Integer => "{integer}".to_string(),
+                Kind::Float => "{float}".to_string(),
+            },
+        }
+    }
+
+    // the type an optional annotation asks for; a fresh variable without one
+    fn annotation(&mut self, ann: Option<&TypeExpr>) -> Ty {
+        let Some(ann) = ann else {
+            return self.fresh(Kind::Any);
+        };
+        match &ann.kind {
+            TypeExprKind::Array { elem, rank } => {
+                let elem = self.annotation(Some(elem));
+                match u32::try_from(*rank) {
+                    Ok(rank) if rank > 0 => Ty::Array(Box::new(elem), rank),
+                    _ => {
+                        self.errors.push(
+                            Diagnostic::error(format!("invalid array rank {rank}"))
+                                .with_code(codes::UNKNOWN_TYPE)
+                                .with_primary(ann.span, "an array needs at least one dimension"),
+                        );
+                        self.fresh(Kind::Any)
+                    }
+                }
+            }
+            TypeExprKind::Named(name) => match named_type(name) {
+                Some(t) => Ty::Known(t),
+                None if self.structs.contains_key(name) => Ty::Known(Type::Struct(name.clone())),
+                None => {
+                    let names: Vec<String> = TYPE_NAMES.iter().map(|(n, _)| format!("`{n}`")).collect();
+                    self.errors.push(
+                        Diagnostic::error(format!("unknown type `{name}`"))
+                            .with_code(codes::UNKNOWN_TYPE)
+                            .with_primary(ann.span, "not a type")
+                            .with_note(format!(
+                                "the types are {}, the declared structs, and `[T]` for an array of T",
+                                names.join(", ")
+                            )),
+                    );
+                    self.fresh(Kind::Any)
+                }
+            },
+        }
+    }
+
+    // report unless `found` can be `expected`
+    fn expect(&mut self, expected: Ty, found: Ty, span: Span, context: &str) {
+        if !self.unify(expected.clone(), found.clone()) {
+            let (expected, found) = (self.show(expected), self.show(found));
+            self.errors.push(
+                Diagnostic::error(format!("mismatched types: expected {expected}, found {found}"))
+                    .with_code(codes::TYPE_MISMATCH)
+                    .with_primary(span, format!("{context} must be {expected}")),
+            );
+        }
+    }
+
+    // type of the statement's value
+    fn stmt(&mut self, stmt: &Stmt) -> Ty {
+        let discarded = std::mem::take(&mut self.discarded);
+        match &stmt.kind {
+            StmtKind::Expr(e) => {
+                self.discarded = discarded;
+                self.expr(e)
+            }
+            StmtKind::Assign { target, ann, value } => {
+                let ty = self.expr(value);
+                let element = match target.kind {
+                    ExprKind::Index { .. } | ExprKind::Field { .. } => Some(self.expr(target)),
+                    _ => {
+                        self.recorded.insert(target.span, ty.clone());
+                        None
+                    }
+                };
+                if self.resolve(ty.clone()) == Ty::Known(Type::Unit) {
+                    self.errors.push(
+                        Diagnostic::error("cannot assign an expression that has no value")
+                            .with_code(codes::TYPE_MISMATCH)
+                            .with_primary(value.span, "this has type unit"),
+                    );
+                    return ty;
+                }
+                if let Some(element) = element {
+                    self.expect(element, ty.clone(), value.span, "the stored value");
+                    return ty;
+                }
+                let ExprKind::Ident { sym, .. } = &target.kind else {
+                    return ty;
+                };
+                let id = sym.expect("resolved");
+                let declared = match ann {
+                    Some(_) => Some(self.annotation(ann.as_ref())),
+                    None => self.vars.get(&id).cloned(),
+                };
+                let Some(declared) = declared else {
+                    self.vars.insert(id, ty.clone());
+                    return ty;
+                };
+                if let Some(previous) = self.vars.get(&id).cloned() {
+                    if ann.is_some() && !self.unify(previous.clone(), declared.clone()) {
+                        let symbol = self.symbols.get(id);
+                        let (previous, declared) = (self.show(previous), self.show(declared));
+                        self.errors.push(
+                            Diagnostic::error(format!("conflicting types for `{}`", symbol.name))
+                                .with_code(codes::TYPE_MISMATCH)
+                                .with_primary(target.span, format!("annotated as {declared} here"))
+                                .with_label(symbol.span, format!("`{}` is {previous} here", symbol.name)),
+                        );
+                        return ty;
+                    }
+                } else {
+                    self.vars.insert(id, declared.clone());
+                }
+                if !self.unify(declared.clone(), ty.clone()) {
+                    let symbol = self.symbols.get(id);
+                    let (declared, found) = (self.show(declared), self.show(ty.clone()));
+                    self.errors.push(
+                        Diagnostic::error(format!("mismatched types: `{}` is {declared}, found {found}", symbol.name))
+                            .with_code(codes::TYPE_MISMATCH)
+                            .with_primary(value.span, format!("expected {declared}"))
+                            .with_label(symbol.span, format!("`{}` gets its type here", symbol.name)),
+                    );
+                }
+                ty
+            }
+            StmtKind::CompoundAssign { target, op, value } => {
+                // `x += v` is `x = x + v` with `x` evaluated once, so both
+                // sides are numbers of one type
+                let value_ty = value.as_ref().map(|v| self.expr(v));
+                let ty = self.expr(target);
+                let symbol = match (value, op) {
+                    (Some(_), op) => format!("{}=", op_symbol(*op)),
+                    (None, BinaryOp::Add) => "++".to_string(),
+                    (None, _) => "--".to_string(),
+                };
+                let context = format!("the target of `{symbol}`");
+                let number = self.fresh(Kind::Numeric);
+                self.expect(number.clone(), ty, target.span, &context);
+                if let (Some(value), Some(value_ty)) = (value, value_ty) {
+                    self.expect(number.clone(), value_ty, value.span, &format!("the right side of `{symbol}`"));
+                }
+                number
+            }
+            StmtKind::Block(stmts) => {
+                // only the last statement gives the block its value
+                let mut ty = Ty::Known(Type::Unit);
+                for (i, s) in stmts.iter().enumerate() {
+                    self.discarded = discarded || i + 1 < stmts.len();
+                    ty = self.stmt(s);
+                }
+                ty
+            }
+            // rejected by the resolver
+            StmtKind::Fn { .. } | StmtKind::Struct { .. } => Ty::Known(Type::Unit),
+            StmtKind::Return(value) => {
+                let ty = self.expr(value);
+                self.expect(self.ret.clone(), ty, value.span, "a returned value");
+                self.diverges = true;
+                Ty::Known(Type::Unit)
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Ty {
+        let ty = self.expr_kind(expr);
+        self.recorded.insert(expr.span, ty.clone());
+        ty
+    }
+
+    fn expr_kind(&mut self, expr: &Expr) -> Ty {
+        // applies to this expression only, not to its operands
+        let discarded = std::mem::take(&mut self.discarded);
+        match &expr.kind {
+            ExprKind::Number(n) => {
+                let ty = self.fresh(Kind::Integer);
+                self.literals.push((*n as i128, expr.span, ty.clone()));
+                ty
+            }
+            ExprKind::Float(_) => self.fresh(Kind::Float),
+            ExprKind::Bool(_) => Ty::Known(Type::Bool),
+            // never reaches the type checker; any type will do
+            ExprKind::Error => self.fresh(Kind::Any),
+            ExprKind::Ident { sym, .. } => match self.vars.get(&sym.expect("resolved")) {
+                Some(ty) => ty.clone(),
+                // only missing after an earlier error; stay quiet about it
+                None => self.fresh(Kind::Any),
+            },
+            ExprKind::Unary { op: UnaryOp::Neg, expr: inner } => {
+                let ty = self.expr(inner);
+                // `-128` is in range for i8 even though `128` is not
+                if let ExprKind::Number(n) = inner.kind {
+                    let literal = self.literals.last_mut().expect("just recorded");
+                    literal.0 = -(n as i128);
+                    literal.1 = expr.span;
+                }
+                let number = self.fresh(Kind::Numeric);
+                self.expect(number.clone(), ty, inner.span, "the operand of `-`");
+                number
+            }
+            ExprKind::Unary { op: UnaryOp::Not, expr: inner } => {
+                let ty = self.expr(inner);
+                self.expect(Ty::Known(Type::Bool), ty, inner.span, "the operand of `not`");
+                Ty::Known(Type::Bool)
+            }
+            ExprKind::Unary { op: UnaryOp::BitNot, expr: inner } => {
+                let ty = self.expr(inner);
+                let integer = self.fresh(Kind::Integer);
+                self.expect(integer.clone(), ty, inner.span, "the operand of `~`");
+                integer
+            }
+            ExprKind::Binary { left, op, right } => {
+                let l = self.expr(left);
+                let r = self.expr(right);
+                match op {
+                    BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem
+                    | BinaryOp::Greater | BinaryOp::Less | BinaryOp::GreaterEq | BinaryOp::LessEq => {
+                        let context = format!("an operand of `{}`", op_symbol(*op));
+                        let number = self.fresh(Kind::Numeric);
+                        self.expect(number.clone(), l, left.span, &context);
+                        self.expect(number.clone(), r, right.span, &context);
+                        match op {
+                            BinaryOp::Greater | BinaryOp::Less | BinaryOp::GreaterEq | BinaryOp::LessEq => {
+                                Ty::Known(Type::Bool)
+                            }
+                            _ => number,
+                        }
+                    }
+                    // a shift amount has the type of the value shifted, like
+                    // every other operator's operands
+                    BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor | BinaryOp::Shl | BinaryOp::Shr => {
+                        let context = format!("an operand of `{}`", op_symbol(*op));
+                        let integer = self.fresh(Kind::Integer);
+                        self.expect(integer.clone(), l, left.span, &context);
+                        self.expect(integer.clone(), r, right.span, &context);
+                        integer
+                    }
+                    BinaryOp::Equal | BinaryOp::NotEqual => {
+                        let aggregate = match self.resolve(l.clone()) {
+                            Ty::Array(..) => Some(("arrays", "compare the elements in a loop instead")),
+                            Ty::Known(Type::Struct(_)) => Some(("structs", "compare the fields instead")),
+                            _ => None,
+                        };
+                        let symbol = op_symbol(*op);
+                        if let Some((what, note)) = aggregate {
+                            self.errors.push(
+                                Diagnostic::error(format!("cannot compare {what} with `{symbol}`"))
+                                    .with_code(codes::TYPE_MISMATCH)
+                                    .with_primary(expr.span, format!("{what} have no `{symbol}`"))
+                                    .with_note(note),
+                            );
+                        } else if !self.unify(l.clone(), r.clone()) || self.resolve(l.clone()) == Ty::Known(Type::Unit) {
+                            let (l, r) = (self.show(l), self.show(r));
+                            self.errors.push(
+                                Diagnostic::error(format!("cannot compare {l} with {r}"))
+                                    .with_code(codes::TYPE_MISMATCH)
+                                    .with_primary(expr.span, format!("both sides of `{symbol}` must have the same type"))
+                                    .with_label(left.span, format!("this is {l}"))
+                                    .with_label(right.span, format!("this is {r}")),
+                            );
+                        }
+                        Ty::Known(Type::Bool)
+                    }
+                    BinaryOp::And | BinaryOp::Or => {
+                        let context = format!("an operand of `{}`", op_symbol(*op));
+                        self.expect(Ty::Known(Type::Bool), l, left.span, &context);
+                        self.expect(Ty::Known(Type::Bool), r, right.span, &context);
+                        Ty::Known(Type::Bool)
+                    }
+                }
+            }
+            ExprKind::Call { callee, args } => {
+                let name = match &callee.kind {
+                    ExprKind::Ident { name, .. } => name.as_str(),
+                    _ => "",
+                };
+                let sig = self.functions.get(name).map(|s| (s.params.clone(), s.ret.clone()));
+                let mut arg_tys = Vec::new();
+                for a in args {
+                    arg_tys.push(self.expr(a));
+                }
+                let Some((params, ret)) = sig else {
+                    return self.fresh(Kind::Any);
+                };
+                for ((a, ty), param) in args.iter().zip(arg_tys).zip(params) {
+                    self.expect(param, ty, a.span, "an argument");
+                }
+                ret
+            }
+            ExprKind::If { cond, body, else_branch } => {
+                self.condition(cond);
+                let before = self.diverges;
+                self.discarded = discarded || else_branch.is_none();
+                let then_ty = self.stmt(body);
+                let then_diverges = std::mem::replace(&mut self.diverges, before);
+                let Some(else_stmt) = else_branch else {
+                    return Ty::Known(Type::Unit);
+                };
+                self.discarded = discarded;
+                let else_ty = self.stmt(else_stmt);
+                let else_diverges = self.diverges;
+                self.diverges = before || (then_diverges && else_diverges);
+
+                // an `if` used as a statement has no value, so its branches
+                // need not agree; lowering then gives the join no parameter
+                if discarded {
+                    return Ty::Known(Type::Unit);
+                }
+                // a branch that returns does not contribute a value
+                match (then_diverges, else_diverges) {
+                    (true, true) => Ty::Known(Type::Unit),
+                    (true, false) => else_ty,
+                    (false, true) => then_ty,
+                    (false, false) => {
+                        if !self.unify(then_ty.clone(), else_ty.clone()) {
+                            let (then_ty, else_ty) = (self.show(then_ty.clone()), self.show(else_ty));
+                            self.errors.push(
+                                Diagnostic::error("`if` and `else` have incompatible types")
+                                    .with_code(codes::TYPE_MISMATCH)
+                                    .with_primary(else_stmt.span, format!("expected {then_ty}, found {else_ty}"))
+                                    .with_label(body.span, format!("this is {then_ty}")),
+                            );
+                        }
+                        then_ty
+                    }
+                }
+            }
+            ExprKind::While { cond, body } => {
+                self.condition(cond);
+                // the body may not run at all, so a `return` inside does not end this path
+                let before = self.diverges;
+                self.discarded = true;
+                self.stmt(body);
+                // but there is no `break`, so only a `return` leaves `while true`
+                self.diverges = before || matches!(cond.kind, ExprKind::Bool(true));
+                Ty::Known(Type::Unit)
+            }
+            ExprKind::For { var, start, end, body } => {
+                let counter = self.fresh(Kind::Integer);
+                let ty = self.expr(start);
+                self.expect(counter.clone(), ty, start.span, "a range bound");
+                let ty = self.expr(end);
+                self.expect(counter.clone(), ty, end.span, "a range bound");
+                if let ExprKind::Ident { sym, .. } = &var.kind {
+                    self.vars.insert(sym.expect("resolved"), counter.clone());
+                }
+                self.recorded.insert(var.span, counter);
+                let before = self.diverges;
+                self.discarded = true;
+                self.stmt(body);
+                self.diverges = before;
+                Ty::Known(Type::Unit)
+            }
+            ExprKind::Array(elems) => {
+                let elem = self.fresh(Kind::Any);
+                for e in elems {
+                    let ty = self.expr(e);
+                    if self.resolve(ty.clone()) == Ty::Known(Type::Unit) {
+                        self.errors.push(
+                            Diagnostic::error("an array element must have a value")
+                                .with_code(codes::TYPE_MISMATCH)
+                                .with_primary(e.span, "this has type unit"),
+                        );
+                        continue;
+                    }
+                    self.expect(elem.clone(), ty, e.span, "an array element");
+                }
+                Ty::Array(Box::new(elem), 1)
+            }
+            ExprKind::NewArray { dims } => {
+                for d in dims {
+                    let ty = self.expr(d);
+                    self.expect(Ty::Known(Type::I64), ty, d.span, "an array extent");
+                }
+                Ty::Array(Box::new(self.fresh(Kind::Any)), dims.len() as u32)
+            }
+            ExprKind::Len { array, dim } => {
+                let ty = self.expr(array);
+                let d = match dim {
+                    Some(dim) => {
+                        let ty = self.expr(dim);
+                        self.expect(Ty::Known(Type::I64), ty, dim.span, "a dimension");
+                        match dim.kind {
+                            ExprKind::Number(n) => n,
+                            _ => {
+                                self.errors.push(
+                                    Diagnostic::error("the dimension in `len` must be an integer literal")
+                                        .with_code(codes::TYPE_MISMATCH)
+                                        .with_primary(dim.span, "not a literal"),
+                                );
+                                0
+                            }
+                        }
+                    }
+                    None => 0,
+                };
+                // an array whose rank is not known yet is taken to be one-dimensional
+                if !matches!(self.resolve(ty.clone()), Ty::Array(..)) {
+                    let any = Ty::Array(Box::new(self.fresh(Kind::Any)), 1);
+                    self.expect(any, ty.clone(), array.span, "the argument of `len`");
+                }
+                if let Ty::Array(_, rank) = self.resolve(ty.clone())
+                    && d >= rank as u64
+                {
+                    let shown = self.show(ty);
+                    let span = dim.as_ref().map_or(expr.span, |d| d.span);
+                    self.errors.push(
+                        Diagnostic::error(format!("{shown} has no dimension {d}"))
+                            .with_code(codes::TYPE_MISMATCH)
+                            .with_primary(span, format!("dimensions are numbered from 0 to {}", rank - 1))
+                            .with_note("an array is one-dimensional unless its uses say otherwise; annotate it as `[T, N]`"),
+                    );
+                }
+                Ty::Known(Type::I64)
+            }
+            ExprKind::Index { array, indices } => {
+                let ty = self.expr(array);
+                let elem = self.fresh(Kind::Any);
+                let indexed = Ty::Array(Box::new(elem.clone()), indices.len() as u32);
+                self.expect(indexed, ty, array.span, "an indexed value");
+                for i in indices {
+                    let ty = self.expr(i);
+                    self.expect(Ty::Known(Type::I64), ty, i.span, "an index");
+                }
+                elem
+            }
+            ExprKind::StructLit { name, fields } => {
+                let tys: Vec<Ty> = fields.iter().map(|f| self.expr(&f.value)).collect();
+                let Some(decl) = self.structs.get(name).cloned() else {
+                    self.errors.push(
+                        Diagnostic::error(format!("cannot find struct `{name}`"))
+                            .with_code(codes::UNKNOWN_TYPE)
+                            .with_primary(expr.span, "not a declared struct"),
+                    );
+                    return self.fresh(Kind::Any);
+                };
+                let mut seen: HashMap<&str, Span> = HashMap::new();
+                for (f, ty) in fields.iter().zip(tys) {
+                    if let Some(&first) = seen.get(f.name.as_str()) {
+                        self.errors.push(
+                            Diagnostic::error(format!("field `{}` is given more than once", f.name))
+                                .with_code(codes::DUPLICATE_FIELD)
+                                .with_primary(f.span, "given again here")
+                                .with_label(first, "first given here"),
+                        );
+                        continue;
+                    }
+                    seen.insert(&f.name, f.span);
+                    match decl.iter().find(|(d, _)| *d == f.name) {
+                        Some((_, field_ty)) => {
+                            self.expect(field_ty.clone(), ty, f.value.span, &format!("field `{}`", f.name))
+                        }
+                        None => self.errors.push(
+                            Diagnostic::error(format!("struct `{name}` has no field named `{}`", f.name))
+                                .with_code(codes::UNKNOWN_FIELD)
+                                .with_primary(f.span, "unknown field"),
+                        ),
+                    }
+                }
+                let missing: Vec<String> = decl
+                    .iter()
+                    .filter(|(d, _)| !seen.contains_key(d.as_str()))
+                    .map(|(d, _)| format!("`{d}`"))
+                    .collect();
+                if !missing.is_empty() {
+                    self.errors.push(
+                        Diagnostic::error(format!("missing fields {} in `{name}`", missing.join(", ")))
+                            .with_code(codes::MISSING_FIELD)
+                            .with_primary(expr.span, "every field must be given"),
+                    );
+                }
+                Ty::Known(Type::Struct(name.clone()))
+            }
+            ExprKind::Field { base, name, .. } => {
+                let ty = self.expr(base);
+                match self.resolve(ty.clone()) {
+                    Ty::Known(Type::Struct(s)) => {
+                        let field = self.structs[&s].iter().find(|(f, _)| f == name).map(|(_, ty)| ty.clone());
+                        match field {
+                            Some(field_ty) => return field_ty,
+                            None => self.errors.push(
+                                Diagnostic::error(format!("struct `{s}` has no field named `{name}`"))
+                                    .with_code(codes::UNKNOWN_FIELD)
+                                    .with_primary(expr.span, "unknown field"),
+                            ),
+                        }
+                    }
+                    Ty::Var(v) if self.type_vars[v as usize].kind == Kind::Any => self.errors.push(
+                        Diagnostic::error(format!("cannot read field `{name}` of a value of unknown type"))
+                            .with_code(codes::UNKNOWN_TYPE)
+                            .with_primary(base.span, "the type of this is not known here")
+                            .with_note("annotate the variable or parameter with its struct type"),
+                    ),
+                    _ => {
+                        let shown = self.show(ty);
+                        self.errors.push(
+                            Diagnostic::error(format!("{shown} has no fields"))
+                                .with_code(codes::UNKNOWN_FIELD)
+                                .with_primary(expr.span, format!("`.{name}` on a value of type {shown}")),
+                        );
+                    }
+                }
+                self.fresh(Kind::Any)
+            }
+        }
+    }
+
+    // Record the fields of every struct, then reject structs that contain
+    // themselves by value, which would make them infinitely large.
+    fn declare_structs(&mut self, stmts: &[Stmt]) {
+        let mut decls: Vec<(&str, Span, &[FieldDecl])> = Vec::new();
+        let mut spans: HashMap<&str, Span> = HashMap::new();
+        for s in stmts {
+            let StmtKind::Struct { name, fields } = &s.kind else { continue };
+            if named_type(name).is_some() {
+                self.errors.push(
+                    Diagnostic::error(format!("cannot define a struct named `{name}`"))
+                        .with_code(codes::DUPLICATE_STRUCT)
+                        .with_primary(s.span, format!("`{name}` is a built-in type")),
+                );
+            } else if let Some(&first) = spans.get(name.as_str()) {
+                self.errors.push(
+                    Diagnostic::error(format!("struct `{name}` is defined more than once"))
+                        .with_code(codes::DUPLICATE_STRUCT)
+                        .with_primary(s.span, "redefined here")
+                        .with_label(first, "first defined here"),
+                );
+            } else {
+                spans.insert(name, s.span);
+                self.structs.insert(name.clone(), Vec::new());
+                decls.push((name, s.span, fields));
+            }
+        }
+
+        // fields are resolved once every struct name is known
+        for &(name, _, fields) in &decls {
+            let mut seen: HashMap<&str, Span> = HashMap::new();
+            let mut field_tys = Vec::new();
+            for f in fields {
+                if let Some(&first) = seen.get(f.name.as_str()) {
+                    self.errors.push(
+                        Diagnostic::error(format!("field `{}` is declared more than once", f.name))
+                            .with_code(codes::DUPLICATE_FIELD)
+                            .with_primary(f.span, "declared again here")
+                            .with_label(first, "first declared here"),
+                    );
+                }
+                seen.insert(&f.name, f.span);
+                field_tys.push((f.name.clone(), self.annotation(Some(&f.ann))));
+            }
+            self.structs.insert(name.to_string(), field_tys);
+        }
+
+        for &(name, span, _) in &decls {
+            if self.contains_struct(name, name, &mut Vec::new()) {
+                self.errors.push(
+                    Diagnostic::error(format!("recursive struct `{name}` has infinite size"))
+                        .with_code(codes::RECURSIVE_STRUCT)
+                        .with_primary(span, format!("`{name}` contains itself"))
+                        .with_note("an array of the struct is fine, since arrays are stored by reference"),
+                );
+            }
+        }
+    }
+
+    // whether struct `outer` holds a `target` by value, directly or nested
+    fn contains_struct(&self, outer: &str, target: &str, visited: &mut Vec<String>) -> bool {
+        if visited.iter().any(|v| v == outer) {
+            return false;
+        }
+        visited.push(outer.to_string());
+        self.structs[outer].iter().any(|(_, ty)| match ty {
+            Ty::Known(Type::Struct(inner)) => inner == target || self.contains_struct(inner, target, visited),
+            _ => false,
+        })
+    }
+
+    fn condition(&mut self, cond: &Expr) {
+        let ty = self.expr(cond);
+        if !self.unify(Ty::Known(Type::Bool), ty.clone()) {
+            let ty = self.show(ty);
+            self.errors.push(
+                Diagnostic::error(format!("mismatched types: expected bool, found {ty}"))
+                    .with_code(codes::TYPE_MISMATCH)
+                    .with_primary(cond.span, "a condition must be bool")
+                    .with_note("use a comparison such as `x > 0` to test an int"),
+            );
+        }
+    }
+}